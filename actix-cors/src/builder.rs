@@ -15,7 +15,7 @@ use log::error;
 use once_cell::sync::Lazy;
 use smallvec::smallvec;
 
-use crate::{AllOrSome, CorsError, CorsMiddleware, Inner, OriginFn};
+use crate::{AllOrSome, CorsError, CorsMiddleware, Inner, OriginFn, PolicyMatcher};
 
 /// Convenience for getting mut refs to inner. Cleaner than `Rc::get_mut`.
 /// Additionally, always causes first error (if any) to be reported during initialization.
@@ -86,6 +86,7 @@ static ALL_METHODS_SET: Lazy<HashSet<Method>> = Lazy::new(|| {
 pub struct Cors {
     inner: Rc<Inner>,
     error: Option<Either<HttpError, CorsError>>,
+    policies: Vec<(PolicyMatcher, Cors)>,
 }
 
 impl Cors {
@@ -121,6 +122,7 @@ impl Cors {
         Cors {
             inner: Rc::new(inner),
             error: None,
+            policies: Vec::new(),
         }
     }
 
@@ -485,6 +487,101 @@ impl Cors {
 
         self
     }
+
+    /// Applies another CORS policy to the requests selected by `matcher`.
+    ///
+    /// Requests are handled by the policy of the first matcher that selects them, in the order
+    /// they were added, or by this policy otherwise. Policies are selected from the path of the
+    /// request before it is routed, so a policy cannot be selected by the configuration of a route
+    /// or resource, e.g. its app data; see [`PolicyMatcher`].
+    ///
+    /// # Initialization Errors
+    /// - If `policy` is invalid
+    /// - If `policy` has policies of its own
+    ///
+    /// # Example
+    /// ```
+    /// use actix_cors::{Cors, PolicyMatcher};
+    /// use actix_web::{web, App, HttpResponse};
+    ///
+    /// let cors = Cors::default()
+    ///     .allowed_origin("https://app.example.com")
+    ///     .allowed_methods(vec!["GET", "POST"])
+    ///     .supports_credentials()
+    ///     // the public API can be read from any origin
+    ///     .policy(
+    ///         PolicyMatcher::PathPrefix("/public".into()),
+    ///         Cors::default()
+    ///             .allow_any_origin()
+    ///             .allowed_methods(vec!["GET"])
+    ///             .send_wildcard(),
+    ///     );
+    ///
+    /// let app = App::new().wrap(cors).service(
+    ///     web::scope("/public").route("/status", web::get().to(HttpResponse::Ok)),
+    /// );
+    /// ```
+    pub fn policy(mut self, matcher: PolicyMatcher, policy: Cors) -> Cors {
+        self.policies.push((matcher, policy));
+        self
+    }
+
+    /// Validates the configuration and bakes header values.
+    fn finalize(&self) -> Result<Rc<Inner>, ()> {
+        if let Some(ref err) = self.error {
+            match err {
+                Either::Left(err) => error!("{}", err),
+                Either::Right(err) => error!("{}", err),
+            }
+
+            return Err(());
+        }
+
+        if self
+            .policies
+            .iter()
+            .any(|(_, policy)| !policy.policies.is_empty())
+        {
+            error!("CORS policies applied with `Cors::policy` can not have policies of their own.");
+            return Err(());
+        }
+
+        let mut inner = Rc::clone(&self.inner);
+
+        if inner.supports_credentials && inner.send_wildcard && inner.allowed_origins.is_all() {
+            error!(
+                "Illegal combination of CORS options: credentials can not be supported when all \
+                    origins are allowed and `send_wildcard` is enabled."
+            );
+            return Err(());
+        }
+
+        // bake allowed headers value if Some and not empty
+        match inner.allowed_headers.as_ref() {
+            Some(header_set) if !header_set.is_empty() => {
+                let allowed_headers_str = intersperse_header_values(header_set);
+                Rc::make_mut(&mut inner).allowed_headers_baked = Some(allowed_headers_str);
+            }
+            _ => {}
+        }
+
+        // bake allowed methods value if not empty
+        if !inner.allowed_methods.is_empty() {
+            let allowed_methods_str = intersperse_header_values(&inner.allowed_methods);
+            Rc::make_mut(&mut inner).allowed_methods_baked = Some(allowed_methods_str);
+        }
+
+        // bake exposed headers value if Some and not empty
+        match inner.expose_headers.as_ref() {
+            Some(header_set) if !header_set.is_empty() => {
+                let expose_headers_str = intersperse_header_values(header_set);
+                Rc::make_mut(&mut inner).expose_headers_baked = Some(expose_headers_str);
+            }
+            _ => {}
+        }
+
+        Ok(inner)
+    }
 }
 
 impl Default for Cors {
@@ -519,6 +616,7 @@ impl Default for Cors {
         Cors {
             inner: Rc::new(inner),
             error: None,
+            policies: Vec::new(),
         }
     }
 }
@@ -537,50 +635,26 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        if let Some(ref err) = self.error {
-            match err {
-                Either::Left(err) => error!("{}", err),
-                Either::Right(err) => error!("{}", err),
-            }
-
-            return future::err(());
-        }
-
-        let mut inner = Rc::clone(&self.inner);
-
-        if inner.supports_credentials && inner.send_wildcard && inner.allowed_origins.is_all() {
-            error!(
-                "Illegal combination of CORS options: credentials can not be supported when all \
-                    origins are allowed and `send_wildcard` is enabled."
-            );
-            return future::err(());
-        }
-
-        // bake allowed headers value if Some and not empty
-        match inner.allowed_headers.as_ref() {
-            Some(header_set) if !header_set.is_empty() => {
-                let allowed_headers_str = intersperse_header_values(header_set);
-                Rc::make_mut(&mut inner).allowed_headers_baked = Some(allowed_headers_str);
-            }
-            _ => {}
-        }
-
-        // bake allowed methods value if not empty
-        if !inner.allowed_methods.is_empty() {
-            let allowed_methods_str = intersperse_header_values(&inner.allowed_methods);
-            Rc::make_mut(&mut inner).allowed_methods_baked = Some(allowed_methods_str);
-        }
+        let inner = match self.finalize() {
+            Ok(inner) => inner,
+            Err(()) => return future::err(()),
+        };
 
-        // bake exposed headers value if Some and not empty
-        match inner.expose_headers.as_ref() {
-            Some(header_set) if !header_set.is_empty() => {
-                let expose_headers_str = intersperse_header_values(header_set);
-                Rc::make_mut(&mut inner).expose_headers_baked = Some(expose_headers_str);
-            }
-            _ => {}
-        }
+        let policies = match self
+            .policies
+            .iter()
+            .map(|(matcher, policy)| Ok((matcher.clone(), policy.finalize()?)))
+            .collect::<Result<_, ()>>()
+        {
+            Ok(policies) => policies,
+            Err(()) => return future::err(()),
+        };
 
-        future::ok(CorsMiddleware { service, inner })
+        future::ok(CorsMiddleware {
+            service,
+            inner,
+            policies,
+        })
     }
 }
 
@@ -610,7 +684,7 @@ where
 
 impl PartialEq for Cors {
     fn eq(&self, other: &Self) -> bool {
-        self.inner == other.inner
+        self.inner == other.inner && self.policies == other.policies
         // Because of the cors-function, checking if the content is equal implies that the errors are equal
         //
         // Proof by contradiction:
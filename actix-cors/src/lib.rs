@@ -6,6 +6,10 @@
 //!
 //! This CORS middleware automatically handles `OPTIONS` preflight requests.
 //!
+//! A single middleware can also apply different policies to requests selected by their path, e.g.
+//! a permissive policy to a public API and a strict one to an authenticated API; see
+//! [`Cors::policy`].
+//!
 //! # Crate Features
 //! - `draft-private-network-access`: ⚠️ Unstable. Adds opt-in support for the [Private Network
 //!   Access] spec extensions. This feature is unstable since it will follow breaking changes in the
@@ -61,9 +65,15 @@ mod builder;
 mod error;
 mod inner;
 mod middleware;
+mod policy;
 
 use crate::{
     all_or_some::AllOrSome,
     inner::{Inner, OriginFn},
 };
-pub use crate::{builder::Cors, error::CorsError, middleware::CorsMiddleware};
+pub use crate::{
+    builder::Cors,
+    error::CorsError,
+    middleware::CorsMiddleware,
+    policy::{CorsPolicy, PolicyMatcher},
+};
@@ -16,7 +16,7 @@ use log::debug;
 use crate::{
     builder::intersperse_header_values,
     inner::{add_vary_header, header_value_try_into_method},
-    AllOrSome, CorsError, Inner,
+    AllOrSome, CorsError, Inner, PolicyMatcher,
 };
 
 /// Service wrapper for Cross-Origin Resource Sharing support.
@@ -28,9 +28,18 @@ use crate::{
 pub struct CorsMiddleware<S> {
     pub(crate) service: S,
     pub(crate) inner: Rc<Inner>,
+    pub(crate) policies: Rc<[(PolicyMatcher, Rc<Inner>)]>,
 }
 
 impl<S> CorsMiddleware<S> {
+    /// Returns the policy applied to the request.
+    fn select(&self, req: &ServiceRequest) -> &Rc<Inner> {
+        self.policies
+            .iter()
+            .find(|(matcher, _)| matcher.matches(req))
+            .map_or(&self.inner, |(_, inner)| inner)
+    }
+
     /// Returns true if request is `OPTIONS` and contains an `Access-Control-Request-Method` header.
     fn is_request_preflight(req: &ServiceRequest) -> bool {
         // check request method is OPTIONS
@@ -57,9 +66,7 @@ impl<S> CorsMiddleware<S> {
     /// - `Origin` header is acceptable;
     /// - `Access-Control-Request-Method` header is acceptable;
     /// - `Access-Control-Request-Headers` header is acceptable.
-    fn handle_preflight(&self, req: ServiceRequest) -> ServiceResponse {
-        let inner = Rc::clone(&self.inner);
-
+    fn handle_preflight(inner: &Inner, req: ServiceRequest) -> ServiceResponse {
         match inner.validate_origin(req.head()) {
             Ok(true) => {}
             Ok(false) => return req.error_response(CorsError::OriginNotAllowed),
@@ -208,23 +215,24 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
+        let inner = Rc::clone(self.select(&req));
         let origin = req.headers().get(header::ORIGIN);
 
         // handle preflight requests
-        if self.inner.preflight && Self::is_request_preflight(&req) {
-            let res = self.handle_preflight(req);
+        if inner.preflight && Self::is_request_preflight(&req) {
+            let res = Self::handle_preflight(&inner, req);
             return ok(res.map_into_right_body()).boxed_local();
         }
 
         // only check actual requests with a origin header
-        let origin_allowed = match (origin, self.inner.validate_origin(req.head())) {
+        let origin_allowed = match (origin, inner.validate_origin(req.head())) {
             (None, _) => false,
             (_, Ok(origin_allowed)) => origin_allowed,
             (_, Err(err)) => {
                 debug!("origin validation failed; inner service is not called");
                 let mut res = req.error_response(err);
 
-                if self.inner.vary_header {
+                if inner.vary_header {
                     add_vary_header(res.headers_mut());
                 }
 
@@ -232,7 +240,6 @@ where
             }
         };
 
-        let inner = Rc::clone(&self.inner);
         let fut = self.service.call(req);
 
         Box::pin(async move {
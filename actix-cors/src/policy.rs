@@ -0,0 +1,99 @@
+use std::borrow::Cow;
+
+use actix_web::{dev::ServiceRequest, HttpMessage as _};
+
+/// Selects the requests a CORS policy applies to; see [`Cors::policy`](crate::Cors::policy).
+///
+/// Path patterns and resource names are looked up in the routing table before the request is
+/// routed, so preflight requests are matched against the right policy even though no `OPTIONS`
+/// route is registered for the resource.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PolicyMatcher {
+    /// Requests whose path is in the given prefix, e.g. `/api` matches `/api` and `/api/users` but
+    /// not `/apis`.
+    ///
+    /// Use this to apply a policy to a scope.
+    PathPrefix(Cow<'static, str>),
+
+    /// Requests matching the resource with the given pattern, e.g. `/users/{id}`.
+    ///
+    /// The pattern includes the prefixes of the scopes the resource is registered in.
+    Pattern(Cow<'static, str>),
+
+    /// Requests matching the resource with the given name; see `Resource::name()`.
+    ResourceName(Cow<'static, str>),
+
+    /// Requests with a [`CorsPolicy`] extension of the given name.
+    Extension(Cow<'static, str>),
+}
+
+impl PolicyMatcher {
+    /// Returns true if the policy applies to `req`.
+    pub(crate) fn matches(&self, req: &ServiceRequest) -> bool {
+        match self {
+            Self::PathPrefix(prefix) => {
+                let prefix = prefix.trim_end_matches('/');
+                req.path()
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            }
+            Self::Pattern(pattern) => req.match_pattern().as_deref() == Some(pattern),
+            Self::ResourceName(name) => req.match_name() == Some(name),
+            Self::Extension(name) => req
+                .extensions()
+                .get::<CorsPolicy>()
+                .is_some_and(|policy| policy.0 == *name),
+        }
+    }
+}
+
+/// Request extension selecting a CORS policy by name; see [`PolicyMatcher::Extension`].
+///
+/// Insert it in the extensions of requests from a middleware that runs before [`Cors`], i.e. that
+/// is registered after it.
+///
+/// [`Cors`]: crate::Cors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsPolicy(Cow<'static, str>);
+
+impl CorsPolicy {
+    /// Constructs an extension selecting the policy with the given `name`.
+    pub fn new(name: impl Into<Cow<'static, str>>) -> Self {
+        Self(name.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn path_prefix() {
+        let matcher = PolicyMatcher::PathPrefix("/api/".into());
+
+        for (path, matches) in [
+            ("/api", true),
+            ("/api/", true),
+            ("/api/users", true),
+            ("/apis", false),
+            ("/", false),
+        ] {
+            let req = TestRequest::with_uri(path).to_srv_request();
+            assert_eq!(matcher.matches(&req), matches, "{path}");
+        }
+    }
+
+    #[test]
+    fn extension() {
+        let matcher = PolicyMatcher::Extension("public".into());
+
+        let req = TestRequest::default().to_srv_request();
+        assert!(!matcher.matches(&req));
+
+        req.extensions_mut().insert(CorsPolicy::new("public"));
+        assert!(matcher.matches(&req));
+    }
+}
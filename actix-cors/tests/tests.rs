@@ -1,13 +1,13 @@
-use actix_cors::Cors;
+use actix_cors::{Cors, CorsPolicy, PolicyMatcher};
 use actix_utils::future::ok;
 use actix_web::{
-    dev::{fn_service, ServiceRequest, Transform},
+    dev::{fn_service, Service as _, ServiceRequest, Transform},
     http::{
         header::{self, HeaderValue},
         Method, StatusCode,
     },
     test::{self, TestRequest},
-    HttpResponse,
+    web, App, HttpMessage as _, HttpResponse,
 };
 use regex::bytes::Regex;
 
@@ -642,6 +642,138 @@ async fn expose_all_request_header_values() {
     assert!(cd_hdr.contains("access-control-allow-origin"));
 }
 
+#[actix_web::test]
+async fn policies() {
+    let app = test::init_service(
+        App::new()
+            .wrap(
+                Cors::default()
+                    .allowed_origin("https://app.example.com")
+                    .allowed_methods(vec![Method::GET, Method::POST])
+                    .supports_credentials()
+                    .policy(
+                        PolicyMatcher::PathPrefix("/public".into()),
+                        Cors::default()
+                            .allow_any_origin()
+                            .allowed_methods(vec![Method::GET])
+                            .send_wildcard(),
+                    )
+                    .policy(
+                        PolicyMatcher::ResourceName("webhook".into()),
+                        Cors::default()
+                            .allowed_origin("https://partner.example.com")
+                            .allowed_methods(vec![Method::POST]),
+                    )
+                    .policy(PolicyMatcher::Extension("embed".into()), Cors::permissive()),
+            )
+            .wrap_fn(|req, srv| {
+                if req.headers().contains_key("x-embed") {
+                    req.extensions_mut().insert(CorsPolicy::new("embed"));
+                }
+                srv.call(req)
+            })
+            .service(web::scope("/public").route("/status", web::get().to(HttpResponse::Ok)))
+            .service(
+                web::resource("/hooks/{id}")
+                    .name("webhook")
+                    .route(web::post().to(HttpResponse::Ok)),
+            )
+            .route("/account", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let preflight = |path: &str, origin: &str, method: Method| {
+        TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri(path)
+            .insert_header((header::ORIGIN, origin))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method.as_str()))
+    };
+    let allow_origin = |headers: &header::HeaderMap| {
+        headers
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|origin| origin.to_str().unwrap().to_owned())
+    };
+
+    // no OPTIONS route is registered: preflight requests are still matched to their policy
+    let req = preflight("/public/status", "https://any.example.com", Method::GET);
+    let res = test::call_service(&app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(allow_origin(res.headers()).as_deref(), Some("*"));
+
+    let req = preflight("/account", "https://any.example.com", Method::GET);
+    let res = test::call_service(&app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let req = preflight("/account", "https://app.example.com", Method::POST);
+    let res = test::call_service(&app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        allow_origin(res.headers()).as_deref(),
+        Some("https://app.example.com")
+    );
+    assert_eq!(
+        res.headers()
+            .get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS)
+            .unwrap(),
+        "true"
+    );
+
+    let req = preflight("/hooks/1", "https://partner.example.com", Method::POST);
+    let res = test::call_service(&app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()
+            .get(header::ACCESS_CONTROL_ALLOW_METHODS)
+            .unwrap(),
+        "POST"
+    );
+
+    let req = preflight("/hooks/1", "https://app.example.com", Method::POST);
+    let res = test::call_service(&app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // actual requests
+    let req = TestRequest::get()
+        .uri("/public/status")
+        .insert_header((header::ORIGIN, "https://any.example.com"));
+    let res = test::call_service(&app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(allow_origin(res.headers()).as_deref(), Some("*"));
+
+    let req = TestRequest::get()
+        .uri("/account")
+        .insert_header((header::ORIGIN, "https://any.example.com"));
+    let res = test::call_service(&app, req.to_request()).await;
+    assert_eq!(allow_origin(res.headers()), None);
+
+    let req = TestRequest::get()
+        .uri("/account")
+        .insert_header((header::ORIGIN, "https://any.example.com"))
+        .insert_header(("x-embed", "1"));
+    let res = test::call_service(&app, req.to_request()).await;
+    assert_eq!(
+        allow_origin(res.headers()).as_deref(),
+        Some("https://any.example.com")
+    );
+}
+
+#[actix_web::test]
+#[should_panic]
+async fn nested_policies() {
+    Cors::default()
+        .policy(
+            PolicyMatcher::PathPrefix("/public".into()),
+            Cors::permissive().policy(
+                PolicyMatcher::PathPrefix("/public/embed".into()),
+                Cors::permissive(),
+            ),
+        )
+        .new_transform(test::ok_service())
+        .await
+        .unwrap();
+}
+
 #[cfg(feature = "draft-private-network-access")]
 #[actix_web::test]
 async fn private_network_access() {
@@ -0,0 +1,494 @@
+//! Guards and middleware to restrict access to authenticated users.
+//!
+//! [`RequireIdentity`] only lets requests with a valid [`Identity`] through, while [`RequireRole`]
+//! additionally checks the roles granted to the user via [`Identity::set_roles`]. [`RecentAuth`]
+//! protects sensitive operations by requiring the user to have logged in or re-authenticated (see
+//! [`Identity::reauthenticated`]) recently.
+//!
+//! Both types can be used as a route [`Guard`], in which case a failed check simply skips the
+//! route, or as middleware. When used as middleware, a failed check is rejected:
+//! - requests without a valid identity that accept `text/html` (including via `text/*` or `*/*`)
+//!   are redirected to the login URL, if one has been configured, with the original path and query
+//!   preserved in a `next` query parameter;
+//! - all other requests receive a `401 Unauthorized` (no identity) or `403 Forbidden` (missing
+//!   role) response.
+//!
+//! The rejection response can be fully customized by registering an error handler, e.g.
+//! [`RequireIdentity::error_handler`].
+//!
+//! ```no_run
+//! use actix_web::{web, App, HttpResponse};
+//! use actix_identity::{
+//!     authorization::{RequireIdentity, RequireRole},
+//!     IdentityMiddleware,
+//! };
+//! # use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+//! # let secret_key = actix_web::cookie::Key::generate();
+//!
+//! App::new()
+//!     .service(
+//!         web::scope("/admin")
+//!             .wrap(RequireRole::new("admin").login_url("/login"))
+//!             .default_service(web::to(HttpResponse::Ok)),
+//!     )
+//!     .service(
+//!         web::scope("/api")
+//!             .wrap(RequireIdentity::new())
+//!             .default_service(web::to(HttpResponse::Ok)),
+//!     )
+//!     .wrap(IdentityMiddleware::default())
+//!     .wrap(SessionMiddleware::new(CookieSessionStore::default(), secret_key));
+//! ```
+
+use std::{borrow::Cow, fmt, rc::Rc, time::Duration};
+
+use actix_utils::future::{ready, Ready};
+use actix_web::{
+    body::EitherBody,
+    cookie::time::OffsetDateTime,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    guard::{Guard, GuardContext},
+    http::{
+        header::{self, Header as _},
+        StatusCode,
+    },
+    mime, Error, HttpRequest, HttpResponse,
+};
+use futures_core::future::LocalBoxFuture;
+
+use crate::{error::GetIdentityError, Identity, IdentityExt as _};
+
+/// Only lets requests with a valid [`Identity`] through.
+///
+/// See the [module-level documentation](self) for the behavior of failed checks.
+///
+/// # Examples
+/// ```
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_identity::authorization::RequireIdentity;
+///
+/// App::new()
+///     // as a guard: anonymous users fall through to the next matching route
+///     .route("/", web::get().guard(RequireIdentity::new()).to(HttpResponse::Ok))
+///     // as middleware: anonymous users are redirected to `/login?next=%2Faccount`
+///     .service(
+///         web::resource("/account")
+///             .wrap(RequireIdentity::new().login_url("/login"))
+///             .to(HttpResponse::Ok),
+///     );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RequireIdentity {
+    rejection: Rejection,
+}
+
+impl RequireIdentity {
+    /// Constructs a new `RequireIdentity` check that responds with `401 Unauthorized` on failure.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Redirects browsers to `url` when the check fails.
+    ///
+    /// Only requests that fail with `401 Unauthorized` and whose `Accept` header admits `text/html`
+    /// are redirected.
+    pub fn login_url(mut self, url: impl Into<String>) -> Self {
+        self.rejection.login_url = Some(url.into());
+        self
+    }
+
+    /// Sets the name of the query parameter that carries the originally requested URL when
+    /// redirecting to the login URL.
+    ///
+    /// Defaults to `next`.
+    pub fn next_param(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.rejection.next_param = name.into();
+        self
+    }
+
+    /// Sets a function that builds the response sent when the check fails.
+    ///
+    /// The function receives the rejected request and the status code that would otherwise be
+    /// used. It takes precedence over the login URL redirect.
+    pub fn error_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, StatusCode) -> HttpResponse + 'static,
+    {
+        self.rejection.error_handler = Some(Rc::new(handler));
+        self
+    }
+}
+
+impl Guard for RequireIdentity {
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        Requirement::Identity.check(ctx.get_identity()).is_ok()
+    }
+}
+
+/// Only lets requests through if their [`Identity`] has been granted at least one of the given
+/// roles.
+///
+/// Roles are attached to an identity using [`Identity::set_roles`]. See the
+/// [module-level documentation](self) for the behavior of failed checks.
+///
+/// # Examples
+/// ```
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_identity::authorization::RequireRole;
+///
+/// App::new().service(
+///     web::scope("/support")
+///         .wrap(RequireRole::any_of(["admin", "support"]))
+///         .default_service(web::to(HttpResponse::Ok)),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct RequireRole {
+    roles: Rc<[String]>,
+    rejection: Rejection,
+}
+
+impl RequireRole {
+    /// Constructs a new `RequireRole` check for a single role.
+    pub fn new(role: impl Into<String>) -> Self {
+        Self::any_of([role.into()])
+    }
+
+    /// Constructs a new `RequireRole` check that passes if the user has any of the given roles.
+    pub fn any_of<I, R>(roles: I) -> Self
+    where
+        I: IntoIterator<Item = R>,
+        R: Into<String>,
+    {
+        Self {
+            roles: roles.into_iter().map(Into::into).collect(),
+            rejection: Rejection::default(),
+        }
+    }
+
+    /// Redirects browsers to `url` when the check fails.
+    ///
+    /// Only requests that fail with `401 Unauthorized` and whose `Accept` header admits `text/html`
+    /// are redirected.
+    pub fn login_url(mut self, url: impl Into<String>) -> Self {
+        self.rejection.login_url = Some(url.into());
+        self
+    }
+
+    /// Sets the name of the query parameter that carries the originally requested URL when
+    /// redirecting to the login URL.
+    ///
+    /// Defaults to `next`.
+    pub fn next_param(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.rejection.next_param = name.into();
+        self
+    }
+
+    /// Sets a function that builds the response sent when the check fails.
+    ///
+    /// The function receives the rejected request and the status code that would otherwise be
+    /// used. It takes precedence over the login URL redirect.
+    pub fn error_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, StatusCode) -> HttpResponse + 'static,
+    {
+        self.rejection.error_handler = Some(Rc::new(handler));
+        self
+    }
+}
+
+impl Guard for RequireRole {
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        Requirement::Roles(Rc::clone(&self.roles))
+            .check(ctx.get_identity())
+            .is_ok()
+    }
+}
+
+/// Only lets requests through if their [`Identity`] was authenticated recently.
+///
+/// The user counts as recently authenticated if they logged in, or called
+/// [`Identity::reauthenticated`], less than `max_age` ago. Stale requests are rejected with
+/// `401 Unauthorized` or, for browsers, redirected to the re-authentication URL if one has been
+/// configured. See the [module-level documentation](self) for more details.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+///
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_identity::authorization::RecentAuth;
+///
+/// App::new().service(
+///     web::resource("/account/delete")
+///         // require the password to have been entered in the last 5 minutes
+///         .wrap(RecentAuth::new(Duration::from_secs(5 * 60)).reauthentication_url("/confirm"))
+///         .post(HttpResponse::Ok),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct RecentAuth {
+    max_age: Duration,
+    rejection: Rejection,
+}
+
+impl RecentAuth {
+    /// Constructs a new `RecentAuth` check that accepts authentications younger than `max_age`.
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            rejection: Rejection::default(),
+        }
+    }
+
+    /// Redirects browsers to `url` when the check fails.
+    ///
+    /// Only requests that fail with `401 Unauthorized` and whose `Accept` header admits `text/html`
+    /// are redirected.
+    pub fn reauthentication_url(mut self, url: impl Into<String>) -> Self {
+        self.rejection.login_url = Some(url.into());
+        self
+    }
+
+    /// Sets the name of the query parameter that carries the originally requested URL when
+    /// redirecting to the re-authentication URL.
+    ///
+    /// Defaults to `next`.
+    pub fn next_param(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.rejection.next_param = name.into();
+        self
+    }
+
+    /// Sets a function that builds the response sent when the check fails.
+    ///
+    /// The function receives the rejected request and the status code that would otherwise be
+    /// used. It takes precedence over the login URL redirect.
+    pub fn error_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, StatusCode) -> HttpResponse + 'static,
+    {
+        self.rejection.error_handler = Some(Rc::new(handler));
+        self
+    }
+}
+
+impl Guard for RecentAuth {
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        Requirement::RecentAuth(self.max_age)
+            .check(ctx.get_identity())
+            .is_ok()
+    }
+}
+
+/// What a request must satisfy to be let through.
+#[derive(Debug, Clone)]
+enum Requirement {
+    Identity,
+    Roles(Rc<[String]>),
+    RecentAuth(Duration),
+}
+
+impl Requirement {
+    /// Returns the status code to reject the request with if the requirement is not met.
+    fn check(&self, identity: Result<Identity, GetIdentityError>) -> Result<(), StatusCode> {
+        let identity = identity.map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+        match self {
+            Requirement::Identity => Ok(()),
+            Requirement::Roles(required) => {
+                let roles = identity.roles().map_err(|err| {
+                    tracing::debug!(
+                        error.display = %err,
+                        error.debug = ?err,
+                        "Failed to retrieve the roles attached to an `Identity`."
+                    );
+                    StatusCode::FORBIDDEN
+                })?;
+
+                if roles.iter().any(|role| required.contains(role)) {
+                    Ok(())
+                } else {
+                    Err(StatusCode::FORBIDDEN)
+                }
+            }
+            Requirement::RecentAuth(max_age) => match identity.authenticated_at() {
+                Ok(Some(authenticated_at))
+                    if OffsetDateTime::now_utc() - authenticated_at <= *max_age =>
+                {
+                    Ok(())
+                }
+                Ok(_) => Err(StatusCode::UNAUTHORIZED),
+                Err(err) => {
+                    tracing::debug!(
+                        error.display = %err,
+                        error.debug = ?err,
+                        "Failed to retrieve the authentication timestamp of an `Identity`."
+                    );
+                    Err(StatusCode::UNAUTHORIZED)
+                }
+            },
+        }
+    }
+}
+
+type ErrorHandler = dyn Fn(&HttpRequest, StatusCode) -> HttpResponse;
+
+/// How requests that fail a check are answered.
+#[derive(Clone)]
+struct Rejection {
+    login_url: Option<String>,
+    next_param: Cow<'static, str>,
+    error_handler: Option<Rc<ErrorHandler>>,
+}
+
+impl Default for Rejection {
+    fn default() -> Self {
+        Self {
+            login_url: None,
+            next_param: Cow::Borrowed("next"),
+            error_handler: None,
+        }
+    }
+}
+
+impl fmt::Debug for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rejection")
+            .field("login_url", &self.login_url)
+            .field("next_param", &self.next_param)
+            .field("error_handler", &self.error_handler.is_some())
+            .finish()
+    }
+}
+
+impl Rejection {
+    fn respond(&self, req: &HttpRequest, status: StatusCode) -> HttpResponse {
+        if let Some(ref error_handler) = self.error_handler {
+            return error_handler(req, status);
+        }
+
+        match self.login_url {
+            Some(ref login_url) if status == StatusCode::UNAUTHORIZED && accepts_html(req) => {
+                let next = req
+                    .uri()
+                    .path_and_query()
+                    .map_or_else(|| req.path(), |pq| pq.as_str());
+                let query = serde_urlencoded::to_string([(self.next_param.as_ref(), next)])
+                    .unwrap_or_default();
+                let separator = if login_url.contains('?') { '&' } else { '?' };
+
+                HttpResponse::SeeOther()
+                    .insert_header((header::LOCATION, format!("{login_url}{separator}{query}")))
+                    .finish()
+            }
+            _ => HttpResponse::new(status),
+        }
+    }
+}
+
+/// Returns true if the `Accept` header of `req` admits `text/html`, including via `text/*` or
+/// `*/*`, with a non-zero quality.
+fn accepts_html(req: &HttpRequest) -> bool {
+    header::Accept::parse(req).is_ok_and(|accept| {
+        accept.iter().any(|item| {
+            let (type_, subtype) = (item.item.type_(), item.item.subtype());
+
+            item.quality > header::Quality::ZERO
+                && (type_ == mime::STAR || type_ == mime::TEXT)
+                && (subtype == mime::STAR || (type_ == mime::TEXT && subtype == mime::HTML))
+        })
+    })
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireIdentity
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthorizationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthorizationMiddleware {
+            service: Rc::new(service),
+            requirement: Requirement::Identity,
+            rejection: Rc::new(self.rejection.clone()),
+        }))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthorizationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthorizationMiddleware {
+            service: Rc::new(service),
+            requirement: Requirement::Roles(Rc::clone(&self.roles)),
+            rejection: Rc::new(self.rejection.clone()),
+        }))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RecentAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthorizationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthorizationMiddleware {
+            service: Rc::new(service),
+            requirement: Requirement::RecentAuth(self.max_age),
+            rejection: Rc::new(self.rejection.clone()),
+        }))
+    }
+}
+
+#[doc(hidden)]
+pub struct AuthorizationMiddleware<S> {
+    service: Rc<S>,
+    requirement: Requirement,
+    rejection: Rc<Rejection>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthorizationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Err(status) = self.requirement.check(req.get_identity()) {
+            let res = self.rejection.respond(req.request(), status);
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+
+        let srv = Rc::clone(&self.service);
+        Box::pin(async move { Ok(srv.call(req).await?.map_into_left_body()) })
+    }
+}
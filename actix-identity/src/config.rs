@@ -1,28 +1,49 @@
 //! Configuration options to tune the behavior of [`IdentityMiddleware`].
 
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
-use crate::IdentityMiddleware;
+use crate::{
+    events::IdentityEvents,
+    policy::IdentityPolicy,
+    storage::{IdentityStorage, SessionStorage},
+    IdentityMiddleware,
+};
 
 #[derive(Debug, Clone)]
 pub(crate) struct Configuration {
-    pub(crate) on_logout: LogoutBehavior,
+    pub(crate) on_logout: Option<LogoutBehavior>,
     pub(crate) login_deadline: Option<Duration>,
     pub(crate) visit_deadline: Option<Duration>,
+    pub(crate) policies: Vec<Arc<dyn IdentityPolicy + Send + Sync>>,
+    pub(crate) storage: Arc<dyn IdentityStorage + Send + Sync>,
+    pub(crate) events: Option<Arc<dyn IdentityEvents + Send + Sync>>,
+    pub(crate) renew_session_key: bool,
     pub(crate) id_key: &'static str,
     pub(crate) last_visit_unix_timestamp_key: &'static str,
     pub(crate) login_unix_timestamp_key: &'static str,
+    pub(crate) roles_key: &'static str,
+    pub(crate) reauthentication_unix_timestamp_key: &'static str,
+    pub(crate) authentication_method_key: &'static str,
+    pub(crate) impersonators_key: &'static str,
 }
 
 impl Default for Configuration {
     fn default() -> Self {
         Self {
-            on_logout: LogoutBehavior::PurgeSession,
+            on_logout: None,
             login_deadline: None,
             visit_deadline: None,
+            policies: Vec::new(),
+            storage: Arc::new(SessionStorage),
+            events: None,
+            renew_session_key: true,
             id_key: "actix_identity.user_id",
             last_visit_unix_timestamp_key: "actix_identity.last_visited_at",
             login_unix_timestamp_key: "actix_identity.logged_in_at",
+            roles_key: "actix_identity.roles",
+            reauthentication_unix_timestamp_key: "actix_identity.reauthenticated_at",
+            authentication_method_key: "actix_identity.authentication_method",
+            impersonators_key: "actix_identity.impersonators",
         }
     }
 }
@@ -81,11 +102,64 @@ impl IdentityMiddlewareBuilder {
         self
     }
 
+    /// Set a custom key to store the roles granted to the user.
+    ///
+    /// See [`Identity::set_roles`](crate::Identity::set_roles).
+    pub fn roles_key(mut self, key: &'static str) -> Self {
+        self.configuration.roles_key = key;
+        self
+    }
+
+    /// Set a custom key to store the unix timestamp of the last re-authentication.
+    ///
+    /// See [`Identity::reauthenticated`](crate::Identity::reauthenticated).
+    pub fn reauthentication_unix_timestamp_key(mut self, key: &'static str) -> Self {
+        self.configuration.reauthentication_unix_timestamp_key = key;
+        self
+    }
+
+    /// Set a custom key to store the method used for the last re-authentication.
+    ///
+    /// See [`Identity::reauthenticated`](crate::Identity::reauthenticated).
+    pub fn authentication_method_key(mut self, key: &'static str) -> Self {
+        self.configuration.authentication_method_key = key;
+        self
+    }
+
+    /// Set a custom key to store the identities set aside while impersonating another user.
+    ///
+    /// See [`Identity::impersonate`](crate::Identity::impersonate).
+    pub fn impersonators_key(mut self, key: &'static str) -> Self {
+        self.configuration.impersonators_key = key;
+        self
+    }
+
     /// Determines how [`Identity::logout`](crate::Identity::logout) affects the current session.
     ///
     /// By default, the current session is purged ([`LogoutBehavior::PurgeSession`]).
     pub fn logout_behavior(mut self, logout_behavior: LogoutBehavior) -> Self {
-        self.configuration.on_logout = logout_behavior;
+        self.configuration.on_logout = Some(logout_behavior);
+        self
+    }
+
+    /// Determines whether the key of the current session is renewed when the privileges attached to
+    /// it change.
+    ///
+    /// If enabled, the session key is renewed by [`Identity::login`], [`Identity::set_roles`],
+    /// [`Identity::reauthenticated`], [`Identity::impersonate`] and
+    /// [`Identity::stop_impersonating`]. This protects against session fixation: a session key
+    /// obtained by an attacker before the user logged in cannot be used to act on their behalf
+    /// afterwards.
+    ///
+    /// Only disable this if you renew the session key yourself. By default, it is enabled.
+    ///
+    /// [`Identity::login`]: crate::Identity::login
+    /// [`Identity::set_roles`]: crate::Identity::set_roles
+    /// [`Identity::reauthenticated`]: crate::Identity::reauthenticated
+    /// [`Identity::impersonate`]: crate::Identity::impersonate
+    /// [`Identity::stop_impersonating`]: crate::Identity::stop_impersonating
+    pub fn renew_session_key(mut self, enabled: bool) -> Self {
+        self.configuration.renew_session_key = enabled;
         self
     }
 
@@ -117,6 +191,33 @@ impl IdentityMiddlewareBuilder {
         self
     }
 
+    /// Sets the backend used to store identities.
+    ///
+    /// By default, identities are stored in the current session ([`SessionStorage`]). See the
+    /// [`storage`](crate::storage) module for alternatives.
+    pub fn storage(mut self, storage: impl IdentityStorage + Send + Sync) -> Self {
+        self.configuration.storage = Arc::new(storage);
+        self
+    }
+
+    /// Adds a custom policy that is evaluated on every request carrying an identity.
+    ///
+    /// Policies are evaluated in the order they are added, after the built-in login and visit
+    /// deadlines. See the [`policy`](crate::policy) module for more details.
+    pub fn policy(mut self, policy: impl IdentityPolicy + Send + Sync) -> Self {
+        self.configuration.policies.push(Arc::new(policy));
+        self
+    }
+
+    /// Sets a handler that is notified of identity lifecycle events, such as logins, logouts and
+    /// expirations.
+    ///
+    /// See the [`events`](crate::events) module for more details.
+    pub fn events(mut self, events: impl IdentityEvents + Send + Sync) -> Self {
+        self.configuration.events = Some(Arc::new(events));
+        self
+    }
+
     /// Finalises the builder and returns an [`IdentityMiddleware`] instance.
     pub fn build(self) -> IdentityMiddleware {
         IdentityMiddleware::new(self.configuration)
@@ -1,13 +1,45 @@
 //! Failure modes of identity operations.
 
-use actix_session::{SessionGetError, SessionInsertError};
+use std::{error::Error as StdError, fmt};
+
 use actix_web::{cookie::time::error::ComponentRange, http::StatusCode, ResponseError};
 use derive_more::derive::{Display, Error, From};
 
+/// Error that can occur while reading or writing the state backing an identity.
+///
+/// See the [`storage`](crate::storage) module.
+#[derive(Debug)]
+pub struct StorageError(Box<dyn StdError + Send + Sync + 'static>);
+
+impl StorageError {
+    /// Wraps an error raised by an identity storage backend.
+    pub fn new(err: impl Into<Box<dyn StdError + Send + Sync + 'static>>) -> Self {
+        Self(err.into())
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to access the identity storage: {}", self.0)
+    }
+}
+
+impl StdError for StorageError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
+
+impl ResponseError for StorageError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
 /// Error that can occur during login attempts.
 #[derive(Debug, Display, Error, From)]
 #[display("{_0}")]
-pub struct LoginError(SessionInsertError);
+pub struct LoginError(StorageError);
 
 impl ResponseError for LoginError {
     fn status_code(&self) -> StatusCode {
@@ -15,6 +47,17 @@ impl ResponseError for LoginError {
     }
 }
 
+/// Error that can occur while updating the state attached to an identity.
+#[derive(Debug, Display, Error, From)]
+#[display("{_0}")]
+pub struct UpdateIdentityError(StorageError);
+
+impl ResponseError for UpdateIdentityError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
 /// Error encountered when working with a session that has expired.
 #[derive(Debug, Display, Error)]
 #[display("The given session has expired and is no longer valid")]
@@ -49,9 +92,9 @@ pub enum GetIdentityError {
     #[display("{_0}")]
     MissingIdentityError(MissingIdentityError),
 
-    /// Failed to accessing the session store.
+    /// Failed to access the identity storage.
     #[display("{_0}")]
-    SessionGetError(SessionGetError),
+    StorageError(StorageError),
 
     /// Identity info was lost after being validated.
     ///
@@ -60,6 +103,39 @@ pub enum GetIdentityError {
     LostIdentityError(LostIdentityError),
 }
 
+/// The current identity is not impersonating another user.
+#[derive(Debug, Display, Error)]
+#[display("The current identity is not impersonating another user")]
+#[non_exhaustive]
+pub struct NotImpersonatingError;
+
+/// Errors that can occur while starting or stopping an impersonation.
+#[derive(Debug, Display, Error, From)]
+#[non_exhaustive]
+pub enum ImpersonationError {
+    /// Failed to retrieve the current identity.
+    #[display("{_0}")]
+    GetIdentityError(GetIdentityError),
+
+    /// The current identity is not impersonating another user.
+    #[display("{_0}")]
+    NotImpersonatingError(NotImpersonatingError),
+
+    /// Failed to update the identity storage.
+    #[display("{_0}")]
+    StorageError(StorageError),
+}
+
+impl ResponseError for ImpersonationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::GetIdentityError(err) => err.status_code(),
+            Self::NotImpersonatingError(_) => StatusCode::BAD_REQUEST,
+            Self::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 impl ResponseError for GetIdentityError {
     fn status_code(&self) -> StatusCode {
         match self {
@@ -68,3 +144,19 @@ impl ResponseError for GetIdentityError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // errors can be converted into `anyhow::Error` or `Box<dyn Error + Send + Sync>`
+    static_assertions::assert_impl_all!(StorageError: Send, Sync);
+    static_assertions::assert_impl_all!(LoginError: Send, Sync);
+    static_assertions::assert_impl_all!(UpdateIdentityError: Send, Sync);
+    static_assertions::assert_impl_all!(SessionExpiryError: Send, Sync);
+    static_assertions::assert_impl_all!(LostIdentityError: Send, Sync);
+    static_assertions::assert_impl_all!(MissingIdentityError: Send, Sync);
+    static_assertions::assert_impl_all!(GetIdentityError: Send, Sync);
+    static_assertions::assert_impl_all!(NotImpersonatingError: Send, Sync);
+    static_assertions::assert_impl_all!(ImpersonationError: Send, Sync);
+}
@@ -0,0 +1,135 @@
+//! Hooks to observe the lifecycle of identities, e.g. for audit logs or security alerts.
+//!
+//! Register an [`IdentityEvents`] handler with [`IdentityMiddlewareBuilder::events`] to be notified
+//! every time a user logs in or out, starts or stops impersonating another user, is logged out by a
+//! [policy](crate::policy), or presents an identity that cannot be retrieved.
+//!
+//! # Examples
+//! ```
+//! use actix_identity::{events::IdentityEvent, IdentityMiddleware};
+//! use actix_web::HttpRequest;
+//!
+//! let middleware = IdentityMiddleware::builder()
+//!     .events(|event: &IdentityEvent, req: &HttpRequest| {
+//!         let peer = req.connection_info().realip_remote_addr().map(ToOwned::to_owned);
+//!
+//!         match event {
+//!             IdentityEvent::LoggedIn { user_id } => {
+//!                 tracing::info!(user.id = %user_id, ?peer, "user logged in");
+//!             }
+//!             IdentityEvent::Lost { error } => {
+//!                 tracing::warn!(%error, ?peer, "failed to retrieve identity");
+//!             }
+//!             _ => {}
+//!         }
+//!     })
+//!     .build();
+//! ```
+//!
+//! [`IdentityMiddlewareBuilder::events`]: crate::config::IdentityMiddlewareBuilder::events
+
+use std::fmt;
+
+use actix_web::HttpRequest;
+
+use crate::error::GetIdentityError;
+
+/// Something that happened to the identity attached to a request.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum IdentityEvent {
+    /// A user logged in, via [`Identity::login`](crate::Identity::login).
+    LoggedIn {
+        /// The id of the user that logged in.
+        user_id: String,
+    },
+
+    /// A user logged out, via [`Identity::logout`](crate::Identity::logout).
+    LoggedOut {
+        /// The id of the user that logged out.
+        user_id: String,
+    },
+
+    /// A user was logged out because their login deadline elapsed.
+    ///
+    /// See [`IdentityMiddlewareBuilder::login_deadline`].
+    ///
+    /// [`IdentityMiddlewareBuilder::login_deadline`]: crate::config::IdentityMiddlewareBuilder::login_deadline
+    ExpiredLogin {
+        /// The id of the user that was logged out.
+        user_id: String,
+    },
+
+    /// A user was logged out because their visit deadline elapsed.
+    ///
+    /// See [`IdentityMiddlewareBuilder::visit_deadline`].
+    ///
+    /// [`IdentityMiddlewareBuilder::visit_deadline`]: crate::config::IdentityMiddlewareBuilder::visit_deadline
+    ExpiredVisit {
+        /// The id of the user that was logged out.
+        user_id: String,
+    },
+
+    /// A user was logged out by a custom [policy](crate::policy).
+    Revoked {
+        /// The id of the user that was logged out.
+        user_id: String,
+    },
+
+    /// A user started impersonating another user, via
+    /// [`Identity::impersonate`](crate::Identity::impersonate).
+    ImpersonationStarted {
+        /// The id of the user acting as `user_id`.
+        impersonator_id: String,
+        /// The id of the impersonated user.
+        user_id: String,
+    },
+
+    /// A user stopped impersonating another user, via
+    /// [`Identity::stop_impersonating`](crate::Identity::stop_impersonating).
+    ImpersonationStopped {
+        /// The id of the user that was acting as `user_id`, and is now acting as themselves again.
+        impersonator_id: String,
+        /// The id of the user that was impersonated.
+        user_id: String,
+    },
+
+    /// The identity attached to a request could not be retrieved, e.g. because the underlying
+    /// state is corrupted.
+    ///
+    /// Requests that simply do not carry an identity do not trigger this event.
+    Lost {
+        /// Why the identity could not be retrieved.
+        error: GetIdentityError,
+    },
+}
+
+/// A handler for [`IdentityEvent`]s.
+///
+/// `IdentityEvents` is implemented for closures with the same signature as
+/// [`on_event`](Self::on_event). See the [module-level documentation](self) for an example.
+///
+/// Events are dispatched once the response has been produced. They are not dispatched for requests
+/// that a middleware wrapped by [`IdentityMiddleware`] fails with an error instead of a response,
+/// since the request is no longer available; errors returned by handlers are not affected.
+///
+/// [`IdentityMiddleware`]: crate::IdentityMiddleware
+pub trait IdentityEvents: 'static {
+    /// Called when `event` happens while processing `req`.
+    fn on_event(&self, event: &IdentityEvent, req: &HttpRequest);
+}
+
+impl<F> IdentityEvents for F
+where
+    F: Fn(&IdentityEvent, &HttpRequest) + 'static,
+{
+    fn on_event(&self, event: &IdentityEvent, req: &HttpRequest) {
+        (self)(event, req)
+    }
+}
+
+impl fmt::Debug for dyn IdentityEvents + Send + Sync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IdentityEvents")
+    }
+}
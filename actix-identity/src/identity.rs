@@ -1,4 +1,9 @@
-use actix_session::Session;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::Arc,
+};
+
 use actix_utils::future::{ready, Ready};
 use actix_web::{
     cookie::time::OffsetDateTime,
@@ -6,19 +11,23 @@ use actix_web::{
     http::StatusCode,
     Error, FromRequest, HttpMessage, HttpRequest, HttpResponse,
 };
+use serde::{Deserialize, Serialize};
 
 use crate::{
     config::LogoutBehavior,
     error::{
-        GetIdentityError, LoginError, LostIdentityError, MissingIdentityError, SessionExpiryError,
+        GetIdentityError, ImpersonationError, LoginError, LostIdentityError, MissingIdentityError,
+        NotImpersonatingError, SessionExpiryError, UpdateIdentityError,
     },
+    events::{IdentityEvent, IdentityEvents},
+    storage::State,
 };
 
 /// A verified user identity. It can be used as a request extractor.
 ///
-/// The lifecycle of a user identity is tied to the lifecycle of the underlying session. If the
-/// session is destroyed (e.g. the session expired), the user identity will be forgotten, de-facto
-/// forcing a user log out.
+/// The lifecycle of a user identity is tied to the lifecycle of the underlying session (or token,
+/// see the [`storage`](crate::storage) module). If the session is destroyed (e.g. the session
+/// expired), the user identity will be forgotten, de-facto forcing a user log out.
 ///
 /// # Examples
 /// ```
@@ -78,13 +87,22 @@ pub struct Identity(IdentityInner);
 
 #[derive(Clone)]
 pub(crate) struct IdentityInner {
-    pub(crate) session: Session,
+    pub(crate) state: State,
     pub(crate) logout_behavior: LogoutBehavior,
-    pub(crate) is_login_deadline_enabled: bool,
-    pub(crate) is_visit_deadline_enabled: bool,
+    pub(crate) renew_session_key: bool,
     pub(crate) id_key: &'static str,
     pub(crate) last_visit_unix_timestamp_key: &'static str,
     pub(crate) login_unix_timestamp_key: &'static str,
+    pub(crate) roles_key: &'static str,
+    pub(crate) reauthentication_unix_timestamp_key: &'static str,
+    pub(crate) authentication_method_key: &'static str,
+    pub(crate) impersonators_key: &'static str,
+    /// Whether the last visit timestamp must be refreshed once the response has been produced.
+    pub(crate) refresh_last_visit: Rc<Cell<bool>>,
+    /// The event handler of the outermost middleware, which handles the events of all layers.
+    pub(crate) events: Option<Arc<dyn IdentityEvents + Send + Sync>>,
+    /// Events waiting to be dispatched by the middleware; `None` if no handler is registered.
+    pub(crate) pending_events: Option<Rc<RefCell<Vec<IdentityEvent>>>>,
 }
 
 impl IdentityInner {
@@ -101,9 +119,22 @@ impl IdentityInner {
             .to_owned()
     }
 
+    /// Renew the session key, unless disabled, after the privileges attached to it changed.
+    fn privileges_changed(&self) {
+        if self.renew_session_key {
+            self.state.renew();
+        }
+    }
+
+    fn record(&self, event: IdentityEvent) {
+        if let Some(ref pending_events) = self.pending_events {
+            pending_events.borrow_mut().push(event);
+        }
+    }
+
     /// Retrieve the user id attached to the current session.
     fn get_identity(&self) -> Result<String, GetIdentityError> {
-        self.session
+        self.state
             .get::<String>(self.id_key)?
             .ok_or_else(|| MissingIdentityError.into())
     }
@@ -128,7 +159,7 @@ impl Identity {
     /// ```
     pub fn id(&self) -> Result<String, GetIdentityError> {
         self.0
-            .session
+            .state
             .get(self.0.id_key)?
             .ok_or_else(|| LostIdentityError.into())
     }
@@ -139,6 +170,13 @@ impl Identity {
     /// `login` has been called, the user will be able to access all routes that require a valid
     /// [`Identity`].
     ///
+    /// The key of the current session is renewed to prevent session fixation, unless disabled with
+    /// [`IdentityMiddlewareBuilder::renew_session_key`].
+    ///
+    /// The login and last visit timestamps are always recorded, whichever deadlines are enabled, so
+    /// that [`RecentAuth`] and custom [policies](crate::policy) can rely on [`logged_at`] and
+    /// [`last_visited_at`]. Earlier versions only recorded them when the matching deadline was set.
+    ///
     /// # Examples
     /// ```
     /// use actix_web::{post, Responder, HttpRequest, HttpMessage, HttpResponse};
@@ -150,19 +188,27 @@ impl Identity {
     ///     HttpResponse::Ok()
     /// }
     /// ```
+    ///
+    /// [`IdentityMiddlewareBuilder::renew_session_key`]: crate::config::IdentityMiddlewareBuilder::renew_session_key
+    /// [`RecentAuth`]: crate::authorization::RecentAuth
+    /// [`logged_at`]: Self::logged_at
+    /// [`last_visited_at`]: Self::last_visited_at
     pub fn login(ext: &Extensions, id: String) -> Result<Self, LoginError> {
         let inner = IdentityInner::extract(ext);
-        inner.session.insert(inner.id_key, id)?;
+        inner.state.insert(inner.id_key, id.clone())?;
         let now = OffsetDateTime::now_utc().unix_timestamp();
-        if inner.is_login_deadline_enabled {
-            inner.session.insert(inner.login_unix_timestamp_key, now)?;
-        }
-        if inner.is_visit_deadline_enabled {
-            inner
-                .session
-                .insert(inner.last_visit_unix_timestamp_key, now)?;
-        }
-        inner.session.renew();
+        inner.state.insert(inner.login_unix_timestamp_key, now)?;
+        inner
+            .state
+            .insert(inner.last_visit_unix_timestamp_key, now)?;
+        inner.state.remove(inner.roles_key);
+        inner
+            .state
+            .remove(inner.reauthentication_unix_timestamp_key);
+        inner.state.remove(inner.authentication_method_key);
+        inner.state.remove(inner.impersonators_key);
+        inner.privileges_changed();
+        inner.record(IdentityEvent::LoggedIn { user_id: id });
         Ok(Self(inner))
     }
 
@@ -187,57 +233,342 @@ impl Identity {
     ///
     /// [`IdentityMiddlewareBuilder::logout_behavior`]: crate::config::IdentityMiddlewareBuilder::logout_behavior
     pub fn logout(self) {
+        if let Ok(user_id) = self.id() {
+            self.0.record(IdentityEvent::LoggedOut { user_id });
+        }
+
+        self.forget();
+    }
+
+    /// Remove the user identity from the current session without recording a logout event.
+    pub(crate) fn forget(self) {
         match self.0.logout_behavior {
             LogoutBehavior::PurgeSession => {
-                self.0.session.purge();
+                self.0.state.purge();
             }
             LogoutBehavior::DeleteIdentityKeys => {
-                self.0.session.remove(self.0.id_key);
-                if self.0.is_login_deadline_enabled {
-                    self.0.session.remove(self.0.login_unix_timestamp_key);
-                }
-                if self.0.is_visit_deadline_enabled {
-                    self.0.session.remove(self.0.last_visit_unix_timestamp_key);
-                }
+                self.0.state.remove(self.0.id_key);
+                self.0.state.remove(self.0.roles_key);
+                self.0.state.remove(self.0.login_unix_timestamp_key);
+                self.0.state.remove(self.0.last_visit_unix_timestamp_key);
+                self.0
+                    .state
+                    .remove(self.0.reauthentication_unix_timestamp_key);
+                self.0.state.remove(self.0.authentication_method_key);
+                self.0.state.remove(self.0.impersonators_key);
             }
         }
     }
 
+    /// Return the roles granted to the user associated to the current session.
+    ///
+    /// An identity without any roles (e.g. [`set_roles`](Self::set_roles) was never called)
+    /// returns an empty list.
+    ///
+    /// # Examples
+    /// ```
+    /// use actix_web::{get, HttpResponse, Responder};
+    /// use actix_identity::Identity;
+    ///
+    /// #[get("/admin")]
+    /// async fn admin(user: Identity) -> impl Responder {
+    ///     if user.roles().unwrap().iter().any(|role| role == "admin") {
+    ///         HttpResponse::Ok()
+    ///     } else {
+    ///         HttpResponse::Forbidden()
+    ///     }
+    /// }
+    /// ```
+    pub fn roles(&self) -> Result<Vec<String>, GetIdentityError> {
+        Ok(self
+            .0
+            .state
+            .get::<Vec<String>>(self.0.roles_key)?
+            .unwrap_or_default())
+    }
+
+    /// Replace the roles granted to the user associated to the current session.
+    ///
+    /// Roles are checked by [`RequireRole`](crate::authorization::RequireRole).
+    ///
+    /// # Examples
+    /// ```
+    /// use actix_web::{post, Responder, HttpRequest, HttpMessage, HttpResponse};
+    /// use actix_identity::Identity;
+    ///
+    /// #[post("/login")]
+    /// async fn login(request: HttpRequest) -> impl Responder {
+    ///     let user = Identity::login(&request.extensions(), "User1".into()).unwrap();
+    ///     user.set_roles(["admin"]).unwrap();
+    ///     HttpResponse::Ok()
+    /// }
+    /// ```
+    pub fn set_roles<I, R>(&self, roles: I) -> Result<(), UpdateIdentityError>
+    where
+        I: IntoIterator<Item = R>,
+        R: Into<String>,
+    {
+        let roles = roles.into_iter().map(Into::into).collect::<Vec<String>>();
+        self.0.state.insert(self.0.roles_key, roles)?;
+        self.0.privileges_changed();
+        Ok(())
+    }
+
+    /// Record that the user has just proven their identity again, e.g. by re-entering their
+    /// password or completing an MFA challenge, using the given authentication `method`.
+    ///
+    /// Routes protected by [`RecentAuth`](crate::authorization::RecentAuth) can then be accessed
+    /// until the re-authentication becomes stale.
+    ///
+    /// # Examples
+    /// ```
+    /// use actix_web::{post, Responder, HttpResponse};
+    /// use actix_identity::Identity;
+    ///
+    /// #[post("/confirm-password")]
+    /// async fn confirm_password(user: Identity) -> impl Responder {
+    ///     // Some kind of authentication should happen here
+    ///     // [...]
+    ///
+    ///     user.reauthenticated("password").unwrap();
+    ///     HttpResponse::Ok()
+    /// }
+    /// ```
+    pub fn reauthenticated(&self, method: impl Into<String>) -> Result<(), UpdateIdentityError> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        self.0
+            .state
+            .insert(self.0.reauthentication_unix_timestamp_key, now)?;
+        self.0
+            .state
+            .insert(self.0.authentication_method_key, method.into())?;
+        self.0.privileges_changed();
+        Ok(())
+    }
+
+    /// Return the time at which the user last re-authenticated.
+    ///
+    /// Returns `None` if [`reauthenticated`](Self::reauthenticated) has not been called since the
+    /// user logged in.
+    pub fn reauthenticated_at(&self) -> Result<Option<OffsetDateTime>, GetIdentityError> {
+        Ok(self
+            .0
+            .state
+            .get(self.0.reauthentication_unix_timestamp_key)?
+            .map(OffsetDateTime::from_unix_timestamp)
+            .transpose()
+            .map_err(SessionExpiryError)?)
+    }
+
+    /// Return the method the user last re-authenticated with, as passed to
+    /// [`reauthenticated`](Self::reauthenticated).
+    pub fn authentication_method(&self) -> Result<Option<String>, GetIdentityError> {
+        Ok(self.0.state.get(self.0.authentication_method_key)?)
+    }
+
+    /// Return the time at which the user last proved their identity, either by logging in or by
+    /// re-authenticating.
+    pub fn authenticated_at(&self) -> Result<Option<OffsetDateTime>, GetIdentityError> {
+        Ok(self.logged_at()?.max(self.reauthenticated_at()?))
+    }
+
+    /// Act as the user identified by `target_id` while preserving the current identity.
+    ///
+    /// After `impersonate` has been called, [`id`](Self::id) returns `target_id` and
+    /// [`impersonator`](Self::impersonator) returns the id of the user that started the
+    /// impersonation. The roles and re-authentication state of the impersonating user are set
+    /// aside until [`stop_impersonating`](Self::stop_impersonating) is called; the impersonated
+    /// identity starts without roles.
+    ///
+    /// Impersonations can be nested. The login deadline keeps applying to the original login and
+    /// [`logout`](Self::logout) ends all impersonations together with the original identity.
+    ///
+    /// # Examples
+    /// ```
+    /// use actix_web::{post, web, Responder, HttpRequest, HttpMessage, HttpResponse};
+    /// use actix_identity::Identity;
+    ///
+    /// #[post("/support/impersonate/{customer_id}")]
+    /// async fn impersonate(request: HttpRequest, customer_id: web::Path<String>) -> impl Responder {
+    ///     // Check that the current user is allowed to impersonate customers
+    ///     // [...]
+    ///
+    ///     Identity::impersonate(&request.extensions(), customer_id.into_inner()).unwrap();
+    ///     HttpResponse::Ok()
+    /// }
+    /// ```
+    pub fn impersonate(ext: &Extensions, target_id: String) -> Result<Self, ImpersonationError> {
+        let identity = Self::extract(ext)?;
+
+        let impersonator_id = identity.id()?;
+        let mut impersonators = identity.impersonators()?;
+        impersonators.push(Impersonator {
+            id: impersonator_id.clone(),
+            roles: identity.0.state.get(identity.0.roles_key)?,
+            reauthenticated_at: identity
+                .0
+                .state
+                .get(identity.0.reauthentication_unix_timestamp_key)?,
+            authentication_method: identity.authentication_method()?,
+        });
+
+        let state = &identity.0.state;
+        state.insert(identity.0.impersonators_key, impersonators)?;
+        state.insert(identity.0.id_key, &target_id)?;
+        state.remove(identity.0.roles_key);
+        state.remove(identity.0.reauthentication_unix_timestamp_key);
+        state.remove(identity.0.authentication_method_key);
+        identity.0.privileges_changed();
+        identity.0.record(IdentityEvent::ImpersonationStarted {
+            impersonator_id,
+            user_id: target_id,
+        });
+
+        Ok(identity)
+    }
+
+    /// Return the id of the user impersonating the current identity, if any.
+    ///
+    /// See [`impersonate`](Self::impersonate).
+    pub fn impersonator(&self) -> Result<Option<String>, GetIdentityError> {
+        Ok(self
+            .impersonators()?
+            .pop()
+            .map(|impersonator| impersonator.id))
+    }
+
+    /// Stop impersonating the current identity and return to the identity of the impersonator.
+    ///
+    /// The roles and re-authentication state of the impersonator are restored. Returns an error if
+    /// the current identity is not being impersonated.
+    ///
+    /// # Examples
+    /// ```
+    /// use actix_web::{post, Responder, HttpResponse};
+    /// use actix_identity::Identity;
+    ///
+    /// #[post("/support/stop-impersonating")]
+    /// async fn stop_impersonating(user: Identity) -> impl Responder {
+    ///     match user.stop_impersonating() {
+    ///         Ok(_) => HttpResponse::Ok(),
+    ///         Err(_) => HttpResponse::BadRequest(),
+    ///     }
+    /// }
+    /// ```
+    pub fn stop_impersonating(self) -> Result<Self, ImpersonationError> {
+        let mut impersonators = self.impersonators()?;
+        let impersonator = impersonators.pop().ok_or(NotImpersonatingError)?;
+
+        let user_id = self.id()?;
+        let state = &self.0.state;
+        if impersonators.is_empty() {
+            state.remove(self.0.impersonators_key);
+        } else {
+            state.insert(self.0.impersonators_key, impersonators)?;
+        }
+
+        state.insert(self.0.id_key, &impersonator.id)?;
+        if let Some(roles) = impersonator.roles {
+            state.insert(self.0.roles_key, roles)?;
+        } else {
+            state.remove(self.0.roles_key);
+        }
+        if let Some(timestamp) = impersonator.reauthenticated_at {
+            state.insert(self.0.reauthentication_unix_timestamp_key, timestamp)?;
+        } else {
+            state.remove(self.0.reauthentication_unix_timestamp_key);
+        }
+        if let Some(method) = impersonator.authentication_method {
+            state.insert(self.0.authentication_method_key, method)?;
+        } else {
+            state.remove(self.0.authentication_method_key);
+        }
+        self.0.privileges_changed();
+        self.0.record(IdentityEvent::ImpersonationStopped {
+            impersonator_id: impersonator.id,
+            user_id,
+        });
+
+        Ok(self)
+    }
+
+    fn impersonators(&self) -> Result<Vec<Impersonator>, GetIdentityError> {
+        Ok(self
+            .0
+            .state
+            .get(self.0.impersonators_key)?
+            .unwrap_or_default())
+    }
+
     pub(crate) fn extract(ext: &Extensions) -> Result<Self, GetIdentityError> {
-        let inner = IdentityInner::extract(ext);
+        Self::from_inner(IdentityInner::extract(ext))
+    }
+
+    pub(crate) fn from_inner(inner: IdentityInner) -> Result<Self, GetIdentityError> {
         inner.get_identity()?;
         Ok(Self(inner))
     }
 
-    pub(crate) fn logged_at(&self) -> Result<Option<OffsetDateTime>, GetIdentityError> {
+    /// Return the time at which the user logged in.
+    ///
+    /// Returns `None` if no login timestamp is attached to the current session, e.g. because it was
+    /// created by an older version of `actix-identity`.
+    pub fn logged_at(&self) -> Result<Option<OffsetDateTime>, GetIdentityError> {
         Ok(self
             .0
-            .session
+            .state
             .get(self.0.login_unix_timestamp_key)?
             .map(OffsetDateTime::from_unix_timestamp)
             .transpose()
             .map_err(SessionExpiryError)?)
     }
 
-    pub(crate) fn last_visited_at(&self) -> Result<Option<OffsetDateTime>, GetIdentityError> {
+    /// Return the time of the user's last visit.
+    ///
+    /// The last visit timestamp is always set on login, but only refreshed on every request when
+    /// [`IdentityMiddlewareBuilder::visit_deadline`] is enabled.
+    ///
+    /// [`IdentityMiddlewareBuilder::visit_deadline`]: crate::config::IdentityMiddlewareBuilder::visit_deadline
+    pub fn last_visited_at(&self) -> Result<Option<OffsetDateTime>, GetIdentityError> {
         Ok(self
             .0
-            .session
+            .state
             .get(self.0.last_visit_unix_timestamp_key)?
             .map(OffsetDateTime::from_unix_timestamp)
             .transpose()
             .map_err(SessionExpiryError)?)
     }
 
+    pub(crate) fn renew(&self) {
+        self.0.state.renew();
+    }
+
+    /// Refresh the last visit timestamp once the response has been produced.
+    ///
+    /// Deferring the update ensures that all the (possibly nested) `IdentityMiddleware` layers
+    /// evaluate their visit deadline against the same timestamp.
+    pub(crate) fn refresh_last_visit(&self) {
+        self.0.refresh_last_visit.set(true);
+    }
+
     pub(crate) fn set_last_visited_at(&self) -> Result<(), LoginError> {
         let now = OffsetDateTime::now_utc().unix_timestamp();
         self.0
-            .session
+            .state
             .insert(self.0.last_visit_unix_timestamp_key, now)?;
         Ok(())
     }
 }
 
+/// Identity state set aside while its owner impersonates another user.
+#[derive(Debug, Serialize, Deserialize)]
+struct Impersonator {
+    id: String,
+    roles: Option<Vec<String>>,
+    reauthenticated_at: Option<i64>,
+    authentication_method: Option<String>,
+}
+
 /// Extractor implementation for [`Identity`].
 ///
 /// # Examples
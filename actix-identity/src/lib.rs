@@ -1,7 +1,7 @@
 //! Identity management for Actix Web.
 //!
 //! `actix-identity` can be used to track identity of a user across multiple requests. It is built
-//! on top of HTTP sessions, via [`actix-session`](https://docs.rs/actix-session).
+//! on top of HTTP sessions, via [`actix-session`](https://docs.rs/actix-session), by default.
 //!
 //! # Getting started
 //! To start using identity management in your Actix Web application you must register
@@ -89,8 +89,26 @@
 //! - have been inactive for a while (see [`IdentityMiddlewareBuilder::visit_deadline`]);
 //! - logged in too long ago (see [`IdentityMiddlewareBuilder::login_deadline`]).
 //!
+//! Additional rules can be plugged in as custom policies; see the [`policy`] module. To audit
+//! logins, logouts and expirations, register a handler with
+//! [`IdentityMiddlewareBuilder::events`]; see the [`events`] module.
+//!
+//! # Access control
+//! The [`authorization`] module provides guards and middleware, such as
+//! [`RequireIdentity`](authorization::RequireIdentity) and
+//! [`RequireRole`](authorization::RequireRole), to restrict routes to authenticated users without
+//! repeating the same checks in every request handler.
+//!
+//! # Stateless identities
+//! Identities do not have to be stored in a session. [`TokenStorage`](storage::TokenStorage)
+//! keeps them in a self-contained signed or encrypted token, carried by a cookie or a header,
+//! which removes the need for `SessionMiddleware` and a session store. Register it with
+//! [`IdentityMiddlewareBuilder::storage`]; see the [`storage`] module for more details.
+//!
 //! [`IdentityMiddlewareBuilder::visit_deadline`]: config::IdentityMiddlewareBuilder::visit_deadline
 //! [`IdentityMiddlewareBuilder::login_deadline`]: config::IdentityMiddlewareBuilder::login_deadline
+//! [`IdentityMiddlewareBuilder::storage`]: config::IdentityMiddlewareBuilder::storage
+//! [`IdentityMiddlewareBuilder::events`]: config::IdentityMiddlewareBuilder::events
 
 #![forbid(unsafe_code)]
 #![deny(missing_docs)]
@@ -98,10 +116,14 @@
 #![doc(html_favicon_url = "https://actix.rs/favicon.ico")]
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+pub mod authorization;
 pub mod config;
 pub mod error;
+pub mod events;
 mod identity;
 mod identity_ext;
 mod middleware;
+pub mod policy;
+pub mod storage;
 
 pub use self::{identity::Identity, identity_ext::IdentityExt, middleware::IdentityMiddleware};
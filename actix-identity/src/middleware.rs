@@ -1,18 +1,25 @@
-use std::rc::Rc;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::Arc,
+};
 
-use actix_session::SessionExt;
 use actix_utils::future::{ready, Ready};
 use actix_web::{
     body::MessageBody,
-    cookie::time::{format_description::well_known::Rfc3339, OffsetDateTime},
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    error::InternalError,
     Error, HttpMessage as _, Result,
 };
 use futures_core::future::LocalBoxFuture;
 
 use crate::{
-    config::{Configuration, IdentityMiddlewareBuilder},
+    config::{Configuration, IdentityMiddlewareBuilder, LogoutBehavior},
+    error::GetIdentityError,
+    events::{IdentityEvent, IdentityEvents},
     identity::IdentityInner,
+    policy::{IdentityPolicy, LoginDeadline, PolicyDecision, VisitDeadline},
+    storage::State,
     Identity,
 };
 
@@ -40,6 +47,44 @@ use crate::{
 /// # ;
 /// }
 /// ```
+///
+/// # Per-scope configuration
+/// `IdentityMiddleware` can be registered again on a scope or resource to override the
+/// configuration of the application-level middleware for the routes it wraps:
+///
+/// - its login and visit deadlines and its custom policies are evaluated _in addition to_ those of
+///   the outer middleware. Overrides can therefore tighten deadlines, but not loosen them;
+/// - its [logout behavior](IdentityMiddlewareBuilder::logout_behavior), if set, replaces the one
+///   of the outer middleware;
+/// - all other settings, such as the storage backend, the keys and the
+///   [event handler](IdentityMiddlewareBuilder::events), are taken from the outermost middleware,
+///   which remains in charge of loading and persisting the identity state. Logouts forced by the
+///   policies of a nested middleware are reported to the handler of the outermost one.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use actix_identity::{config::LogoutBehavior, IdentityMiddleware};
+/// use actix_web::{web, App, HttpResponse};
+///
+/// let app = App::new()
+///     .wrap(
+///         IdentityMiddleware::builder()
+///             .visit_deadline(Some(Duration::from_secs(30 * 24 * 60 * 60)))
+///             .logout_behavior(LogoutBehavior::DeleteIdentityKeys)
+///             .build(),
+///     )
+///     .service(
+///         web::scope("/admin")
+///             .wrap(
+///                 IdentityMiddleware::builder()
+///                     .visit_deadline(Some(Duration::from_secs(15 * 60)))
+///                     .logout_behavior(LogoutBehavior::PurgeSession)
+///                     .build(),
+///             )
+///             .default_service(web::to(HttpResponse::Ok)),
+///     );
+/// ```
 #[derive(Default, Clone)]
 pub struct IdentityMiddleware {
     configuration: Rc<Configuration>,
@@ -109,30 +154,138 @@ where
         let srv = Rc::clone(&self.service);
         let configuration = Rc::clone(&self.configuration);
         Box::pin(async move {
+            let outer_events = match req.extensions_mut().get_mut::<IdentityInner>() {
+                Some(identity_inner) => {
+                    if let Some(ref logout_behavior) = configuration.on_logout {
+                        identity_inner.logout_behavior = logout_behavior.clone();
+                    }
+                    Some(identity_inner.events.clone())
+                }
+                None => None,
+            };
+
+            if let Some(events) = outer_events {
+                // An outer `IdentityMiddleware` has already loaded the identity state and is in
+                // charge of persisting it: only apply the overrides of this layer.
+                enforce_policies(&req, &configuration, events.as_ref(), true);
+                return srv.call(req).await;
+            }
+
+            let state = configuration.storage.load(&req);
+            let refresh_last_visit = Rc::new(Cell::new(false));
+            let pending_events = configuration
+                .events
+                .as_ref()
+                .map(|_| Rc::new(RefCell::new(Vec::new())));
             let identity_inner = IdentityInner {
-                session: req.get_session(),
-                logout_behavior: configuration.on_logout.clone(),
-                is_login_deadline_enabled: configuration.login_deadline.is_some(),
-                is_visit_deadline_enabled: configuration.visit_deadline.is_some(),
+                state: State::new(Rc::clone(&state)),
+                logout_behavior: configuration
+                    .on_logout
+                    .clone()
+                    .unwrap_or(LogoutBehavior::PurgeSession),
+                renew_session_key: configuration.renew_session_key,
                 id_key: configuration.id_key,
                 last_visit_unix_timestamp_key: configuration.last_visit_unix_timestamp_key,
                 login_unix_timestamp_key: configuration.login_unix_timestamp_key,
+                roles_key: configuration.roles_key,
+                reauthentication_unix_timestamp_key: configuration
+                    .reauthentication_unix_timestamp_key,
+                authentication_method_key: configuration.authentication_method_key,
+                impersonators_key: configuration.impersonators_key,
+                refresh_last_visit: Rc::clone(&refresh_last_visit),
+                events: configuration.events.clone(),
+                pending_events: pending_events.clone(),
+            };
+            req.extensions_mut().insert(identity_inner.clone());
+            enforce_policies(&req, &configuration, configuration.events.as_ref(), false);
+
+            let res = srv.call(req).await;
+
+            if refresh_last_visit.get() {
+                // the user may have logged out while the request was being processed
+                if let Ok(identity) = Identity::from_inner(identity_inner) {
+                    if let Err(err) = identity.set_last_visited_at() {
+                        tracing::warn!(
+                            error.display = %err,
+                            error.debug = ?err,
+                            "Failed to set the last visited timestamp on `Identity` for an \
+                            incoming request."
+                        );
+                    }
+                }
+            }
+
+            let mut res = match res {
+                Ok(res) => res,
+                Err(err) => {
+                    // The request is gone along with the failed service: the identity can still be
+                    // persisted to the response the error is turned into, but events can't be
+                    // dispatched.
+                    if let Some(pending_events) = pending_events {
+                        tracing::debug!(
+                            events = ?pending_events.take(),
+                            "Dropped identity events of a request that failed with an error."
+                        );
+                    }
+
+                    let mut response = err.error_response();
+                    state.persist(response.head_mut())?;
+                    return Err(InternalError::from_response(err, response).into());
+                }
             };
-            req.extensions_mut().insert(identity_inner);
-            enforce_policies(&req, &configuration);
-            srv.call(req).await
+
+            if let (Some(events), Some(pending_events)) = (&configuration.events, pending_events) {
+                for event in pending_events.take() {
+                    events.on_event(&event, res.request());
+                }
+            }
+
+            state.persist(res.response_mut().head_mut())?;
+            Ok(res)
         })
     }
 }
 
-// easier to scan with returns where they are
-// especially if the function body were to evolve in the future
-#[allow(clippy::needless_return)]
-fn enforce_policies(req: &ServiceRequest, configuration: &Configuration) {
-    let must_extract_identity =
-        configuration.login_deadline.is_some() || configuration.visit_deadline.is_some();
+/// The event dispatched when a policy logs a user out.
+type LogoutEvent = fn(String) -> IdentityEvent;
 
-    if !must_extract_identity {
+/// Evaluates the policies of `configuration`, reporting the logouts they force to `events`, the
+/// handler of the outermost middleware.
+fn enforce_policies(
+    req: &ServiceRequest,
+    configuration: &Configuration,
+    events: Option<&Arc<dyn IdentityEvents + Send + Sync>>,
+    is_nested: bool,
+) {
+    let login_deadline = configuration.login_deadline.map(LoginDeadline::new);
+    let visit_deadline = configuration.visit_deadline.map(VisitDeadline::new);
+
+    let builtin_policies: [Option<(&dyn IdentityPolicy, LogoutEvent)>; 2] = [
+        login_deadline.as_ref().map(|policy| {
+            (
+                policy as &dyn IdentityPolicy,
+                (|user_id| IdentityEvent::ExpiredLogin { user_id }) as LogoutEvent,
+            )
+        }),
+        visit_deadline.as_ref().map(|policy| {
+            (
+                policy as &dyn IdentityPolicy,
+                (|user_id| IdentityEvent::ExpiredVisit { user_id }) as LogoutEvent,
+            )
+        }),
+    ];
+    let mut policies = builtin_policies
+        .into_iter()
+        .flatten()
+        .chain(configuration.policies.iter().map(|policy| {
+            (
+                &**policy as &dyn IdentityPolicy,
+                (|user_id| IdentityEvent::Revoked { user_id }) as LogoutEvent,
+            )
+        }))
+        .peekable();
+
+    if policies.peek().is_none() && events.is_none() {
         return;
     }
 
@@ -144,116 +297,44 @@ fn enforce_policies(req: &ServiceRequest, configuration: &Configuration) {
                 error.debug = ?err,
                 "Failed to extract an `Identity` from the incoming request."
             );
+
+            // lost identities are reported by the outermost layer only
+            if !is_nested && !matches!(err, GetIdentityError::MissingIdentityError(_)) {
+                emit(events, IdentityEvent::Lost { error: err }, req);
+            }
+
             return;
         }
     };
 
-    if let Some(login_deadline) = configuration.login_deadline {
-        if matches!(
-            enforce_login_deadline(&identity, login_deadline),
-            PolicyDecision::LogOut
-        ) {
-            identity.logout();
-            return;
-        }
-    }
+    let mut renew = false;
 
-    if let Some(visit_deadline) = configuration.visit_deadline {
-        if matches!(
-            enforce_visit_deadline(&identity, visit_deadline),
-            PolicyDecision::LogOut
-        ) {
-            identity.logout();
-            return;
-        } else if let Err(err) = identity.set_last_visited_at() {
-            tracing::warn!(
-                error.display = %err,
-                error.debug = ?err,
-                "Failed to set the last visited timestamp on `Identity` for an incoming request."
-            );
-        }
-    }
-}
+    for (policy, logout_event) in policies {
+        match policy.evaluate(&identity, req) {
+            PolicyDecision::StayLoggedIn => {}
+            PolicyDecision::Renew => renew = true,
+            PolicyDecision::LogOut => {
+                if let Ok(user_id) = identity.id() {
+                    emit(events, logout_event(user_id), req);
+                }
 
-fn enforce_login_deadline(
-    identity: &Identity,
-    login_deadline: std::time::Duration,
-) -> PolicyDecision {
-    match identity.logged_at() {
-        Ok(None) => {
-            tracing::info!(
-                "Login deadline is enabled, but there is no login timestamp in the session \
-                state attached to the incoming request. Logging the user out."
-            );
-            PolicyDecision::LogOut
-        }
-        Err(err) => {
-            tracing::info!(
-                error.display = %err,
-                error.debug = ?err,
-                "Login deadline is enabled but we failed to extract the login timestamp from the \
-                session state attached to the incoming request. Logging the user out."
-            );
-            PolicyDecision::LogOut
-        }
-        Ok(Some(logged_in_at)) => {
-            let elapsed = OffsetDateTime::now_utc() - logged_in_at;
-            if elapsed > login_deadline {
-                tracing::info!(
-                    user.logged_in_at = %logged_in_at.format(&Rfc3339).unwrap_or_default(),
-                    identity.login_deadline_seconds = login_deadline.as_secs(),
-                    identity.elapsed_since_login_seconds = elapsed.whole_seconds(),
-                    "Login deadline is enabled and too much time has passed since the user logged \
-                    in. Logging the user out."
-                );
-                PolicyDecision::LogOut
-            } else {
-                PolicyDecision::StayLoggedIn
+                identity.forget();
+                return;
             }
         }
     }
-}
 
-fn enforce_visit_deadline(
-    identity: &Identity,
-    visit_deadline: std::time::Duration,
-) -> PolicyDecision {
-    match identity.last_visited_at() {
-        Ok(None) => {
-            tracing::info!(
-                "Last visit deadline is enabled, but there is no last visit timestamp in the \
-                session state attached to the incoming request. Logging the user out."
-            );
-            PolicyDecision::LogOut
-        }
-        Err(err) => {
-            tracing::info!(
-                error.display = %err,
-                error.debug = ?err,
-                "Last visit deadline is enabled but we failed to extract the last visit timestamp \
-                from the session state attached to the incoming request. Logging the user out."
-            );
-            PolicyDecision::LogOut
-        }
-        Ok(Some(last_visited_at)) => {
-            let elapsed = OffsetDateTime::now_utc() - last_visited_at;
-            if elapsed > visit_deadline {
-                tracing::info!(
-                    user.last_visited_at = %last_visited_at.format(&Rfc3339).unwrap_or_default(),
-                    identity.visit_deadline_seconds = visit_deadline.as_secs(),
-                    identity.elapsed_since_last_visit_seconds = elapsed.whole_seconds(),
-                    "Last visit deadline is enabled and too much time has passed since the last \
-                    time the user visited. Logging the user out."
-                );
-                PolicyDecision::LogOut
-            } else {
-                PolicyDecision::StayLoggedIn
-            }
-        }
+    if renew {
+        identity.renew();
     }
 }
 
-enum PolicyDecision {
-    StayLoggedIn,
-    LogOut,
+fn emit(
+    events: Option<&Arc<dyn IdentityEvents + Send + Sync>>,
+    event: IdentityEvent,
+    req: &ServiceRequest,
+) {
+    if let Some(events) = events {
+        events.on_event(&event, req.request());
+    }
 }
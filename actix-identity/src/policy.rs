@@ -0,0 +1,213 @@
+//! Policies that decide whether an [`Identity`] is still valid for an incoming request.
+//!
+//! Policies are registered on [`IdentityMiddlewareBuilder`] and evaluated, in order, by
+//! [`IdentityMiddleware`] for every request that carries an identity. The first policy that asks
+//! for the user to be logged out wins; the remaining policies are not evaluated.
+//!
+//! The login and visit deadlines ([`IdentityMiddlewareBuilder::login_deadline`] and
+//! [`IdentityMiddlewareBuilder::visit_deadline`]) are implemented as the built-in [`LoginDeadline`]
+//! and [`VisitDeadline`] policies. When enabled, they are evaluated before any custom policy.
+//!
+//! # Examples
+//! ```
+//! use std::time::Duration;
+//!
+//! use actix_identity::{policy::PolicyDecision, Identity, IdentityMiddleware};
+//! use actix_web::{cookie::time::OffsetDateTime, dev::ServiceRequest};
+//!
+//! /// Sessions that started before this instant must log in again.
+//! fn sessions_invalidated_before(_user_id: &str) -> Option<OffsetDateTime> {
+//!     // look up a per-user revocation timestamp, e.g. in a local cache
+//!     None
+//! }
+//!
+//! let middleware = IdentityMiddleware::builder()
+//!     .visit_deadline(Some(Duration::from_secs(30 * 24 * 60 * 60)))
+//!     .policy(|identity: &Identity, _req: &ServiceRequest| {
+//!         let (Ok(user_id), Ok(Some(logged_in_at))) = (identity.id(), identity.logged_at()) else {
+//!             return PolicyDecision::LogOut;
+//!         };
+//!
+//!         match sessions_invalidated_before(&user_id) {
+//!             Some(invalidated_at) if logged_in_at < invalidated_at => PolicyDecision::LogOut,
+//!             _ => PolicyDecision::StayLoggedIn,
+//!         }
+//!     })
+//!     .build();
+//! ```
+//!
+//! [`IdentityMiddleware`]: crate::IdentityMiddleware
+//! [`IdentityMiddlewareBuilder`]: crate::config::IdentityMiddlewareBuilder
+//! [`IdentityMiddlewareBuilder::login_deadline`]: crate::config::IdentityMiddlewareBuilder::login_deadline
+//! [`IdentityMiddlewareBuilder::visit_deadline`]: crate::config::IdentityMiddlewareBuilder::visit_deadline
+
+use std::{fmt, time::Duration};
+
+use actix_web::{
+    cookie::time::{format_description::well_known::Rfc3339, OffsetDateTime},
+    dev::ServiceRequest,
+};
+
+use crate::Identity;
+
+/// The outcome of evaluating an [`IdentityPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PolicyDecision {
+    /// The identity is still valid.
+    StayLoggedIn,
+
+    /// The identity is still valid, but the key of the underlying session must be renewed.
+    ///
+    /// Evaluation continues with the next policy.
+    Renew,
+
+    /// The identity is no longer valid: the user is logged out and the remaining policies are not
+    /// evaluated.
+    LogOut,
+}
+
+/// A rule that is checked against the [`Identity`] attached to every incoming request.
+///
+/// `IdentityPolicy` is implemented for closures with the same signature as
+/// [`evaluate`](Self::evaluate). See the [module-level documentation](self) for an example.
+pub trait IdentityPolicy: 'static {
+    /// Decides whether `identity` is still valid for `req`.
+    fn evaluate(&self, identity: &Identity, req: &ServiceRequest) -> PolicyDecision;
+}
+
+impl<F> IdentityPolicy for F
+where
+    F: Fn(&Identity, &ServiceRequest) -> PolicyDecision + 'static,
+{
+    fn evaluate(&self, identity: &Identity, req: &ServiceRequest) -> PolicyDecision {
+        (self)(identity, req)
+    }
+}
+
+impl fmt::Debug for dyn IdentityPolicy + Send + Sync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IdentityPolicy")
+    }
+}
+
+/// Logs out users after a certain amount of time has passed since they logged in, regardless of
+/// their activity pattern.
+///
+/// See [`IdentityMiddlewareBuilder::login_deadline`].
+///
+/// [`IdentityMiddlewareBuilder::login_deadline`]: crate::config::IdentityMiddlewareBuilder::login_deadline
+#[derive(Debug, Clone, Copy)]
+pub struct LoginDeadline(Duration);
+
+impl LoginDeadline {
+    /// Constructs a new login deadline policy.
+    pub fn new(deadline: Duration) -> Self {
+        Self(deadline)
+    }
+}
+
+impl IdentityPolicy for LoginDeadline {
+    fn evaluate(&self, identity: &Identity, _req: &ServiceRequest) -> PolicyDecision {
+        let login_deadline = self.0;
+
+        match identity.logged_at() {
+            Ok(None) => {
+                tracing::info!(
+                    "Login deadline is enabled, but there is no login timestamp in the session \
+                    state attached to the incoming request. Logging the user out."
+                );
+                PolicyDecision::LogOut
+            }
+            Err(err) => {
+                tracing::info!(
+                    error.display = %err,
+                    error.debug = ?err,
+                    "Login deadline is enabled but we failed to extract the login timestamp from \
+                    the session state attached to the incoming request. Logging the user out."
+                );
+                PolicyDecision::LogOut
+            }
+            Ok(Some(logged_in_at)) => {
+                let elapsed = OffsetDateTime::now_utc() - logged_in_at;
+                if elapsed > login_deadline {
+                    tracing::info!(
+                        user.logged_in_at = %logged_in_at.format(&Rfc3339).unwrap_or_default(),
+                        identity.login_deadline_seconds = login_deadline.as_secs(),
+                        identity.elapsed_since_login_seconds = elapsed.whole_seconds(),
+                        "Login deadline is enabled and too much time has passed since the user \
+                        logged in. Logging the user out."
+                    );
+                    PolicyDecision::LogOut
+                } else {
+                    PolicyDecision::StayLoggedIn
+                }
+            }
+        }
+    }
+}
+
+/// Logs out users after a certain amount of time has passed since their last visit.
+///
+/// Users that stay logged in have their last visit timestamp refreshed once the response has been
+/// produced.
+///
+/// See [`IdentityMiddlewareBuilder::visit_deadline`].
+///
+/// [`IdentityMiddlewareBuilder::visit_deadline`]: crate::config::IdentityMiddlewareBuilder::visit_deadline
+#[derive(Debug, Clone, Copy)]
+pub struct VisitDeadline(Duration);
+
+impl VisitDeadline {
+    /// Constructs a new visit deadline policy.
+    pub fn new(deadline: Duration) -> Self {
+        Self(deadline)
+    }
+}
+
+impl IdentityPolicy for VisitDeadline {
+    fn evaluate(&self, identity: &Identity, _req: &ServiceRequest) -> PolicyDecision {
+        let visit_deadline = self.0;
+
+        let decision = match identity.last_visited_at() {
+            Ok(None) => {
+                tracing::info!(
+                    "Last visit deadline is enabled, but there is no last visit timestamp in the \
+                    session state attached to the incoming request. Logging the user out."
+                );
+                PolicyDecision::LogOut
+            }
+            Err(err) => {
+                tracing::info!(
+                    error.display = %err,
+                    error.debug = ?err,
+                    "Last visit deadline is enabled but we failed to extract the last visit \
+                    timestamp from the session state attached to the incoming request. Logging \
+                    the user out."
+                );
+                PolicyDecision::LogOut
+            }
+            Ok(Some(last_visited_at)) => {
+                let elapsed = OffsetDateTime::now_utc() - last_visited_at;
+                if elapsed > visit_deadline {
+                    tracing::info!(
+                        user.last_visited_at = %last_visited_at.format(&Rfc3339).unwrap_or_default(),
+                        identity.visit_deadline_seconds = visit_deadline.as_secs(),
+                        identity.elapsed_since_last_visit_seconds = elapsed.whole_seconds(),
+                        "Last visit deadline is enabled and too much time has passed since the \
+                        last time the user visited. Logging the user out."
+                    );
+                    PolicyDecision::LogOut
+                } else {
+                    PolicyDecision::StayLoggedIn
+                }
+            }
+        };
+
+        if decision == PolicyDecision::StayLoggedIn {
+            identity.refresh_last_visit();
+        }
+
+        decision
+    }
+}
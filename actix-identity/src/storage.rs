@@ -0,0 +1,499 @@
+//! Pluggable backends to store the state attached to an [`Identity`].
+//!
+//! By default, identities are stored in the current session ([`SessionStorage`]), which requires
+//! `actix-session`'s `SessionMiddleware` to be registered. [`TokenStorage`] is a stateless
+//! alternative: the identity state is kept in a self-contained signed or encrypted token that is
+//! exchanged with the client through a cookie or a header.
+//!
+//! Custom backends can be plugged in by implementing [`IdentityStorage`] and [`IdentityState`] and
+//! registering them with [`IdentityMiddlewareBuilder::storage`].
+//!
+//! [`Identity`]: crate::Identity
+//! [`IdentityMiddlewareBuilder::storage`]: crate::config::IdentityMiddlewareBuilder::storage
+
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc, sync::Arc};
+
+use actix_session::{config::CookieContentSecurity, Session, SessionExt as _};
+use actix_web::{
+    cookie::{time::Duration, Cookie, CookieJar, Key, SameSite},
+    dev::{ResponseHead, ServiceRequest},
+    http::header::{HeaderName, HeaderValue, SET_COOKIE},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::error::StorageError;
+
+/// A backend that stores the state attached to identities.
+///
+/// The backend is asked to [`load`](Self::load) the state attached to each incoming request. The
+/// returned [`IdentityState`] is then used by [`Identity`](crate::Identity) for the lifetime of the
+/// request and [persisted](IdentityState::persist) once a response has been produced.
+pub trait IdentityStorage: 'static {
+    /// Loads the identity state attached to `req`.
+    ///
+    /// Requests that do not carry any valid state must be given an empty state.
+    fn load(&self, req: &ServiceRequest) -> Rc<dyn IdentityState>;
+}
+
+impl fmt::Debug for dyn IdentityStorage + Send + Sync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IdentityStorage")
+    }
+}
+
+/// The identity state attached to a single request.
+///
+/// Values are stored as JSON under string keys.
+pub trait IdentityState {
+    /// Returns the value stored under `key`, if any.
+    fn get(&self, key: &str) -> Result<Option<Value>, StorageError>;
+
+    /// Stores `value` under `key`, replacing any previous value.
+    fn insert(&self, key: &str, value: Value) -> Result<(), StorageError>;
+
+    /// Removes the value stored under `key`, if any.
+    fn remove(&self, key: &str);
+
+    /// Removes all values, including those not managed by `actix-identity`.
+    fn purge(&self);
+
+    /// Renews the identifier of the state, if the backend has one, while keeping its values.
+    fn renew(&self);
+
+    /// Writes the state to the outgoing response, if the backend needs to.
+    ///
+    /// The default implementation does nothing.
+    fn persist(&self, res: &mut ResponseHead) -> Result<(), StorageError> {
+        let _ = res;
+        Ok(())
+    }
+}
+
+/// Typed access to an [`IdentityState`].
+#[derive(Clone)]
+pub(crate) struct State(Rc<dyn IdentityState>);
+
+impl State {
+    pub(crate) fn new(state: Rc<dyn IdentityState>) -> Self {
+        Self(state)
+    }
+
+    pub(crate) fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, StorageError> {
+        self.0
+            .get(key)?
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(StorageError::new)
+    }
+
+    pub(crate) fn insert<T: Serialize>(&self, key: &str, value: T) -> Result<(), StorageError> {
+        let value = serde_json::to_value(value).map_err(StorageError::new)?;
+        self.0.insert(key, value)
+    }
+
+    pub(crate) fn remove(&self, key: &str) {
+        self.0.remove(key);
+    }
+
+    pub(crate) fn purge(&self) {
+        self.0.purge();
+    }
+
+    pub(crate) fn renew(&self) {
+        self.0.renew();
+    }
+}
+
+/// Stores identities in the current session.
+///
+/// This is the default storage backend. It requires `actix-session`'s `SessionMiddleware` to be
+/// registered as an application middleware.
+#[derive(Debug, Clone, Copy, Default)]
+#[non_exhaustive]
+pub struct SessionStorage;
+
+impl IdentityStorage for SessionStorage {
+    fn load(&self, req: &ServiceRequest) -> Rc<dyn IdentityState> {
+        Rc::new(req.get_session())
+    }
+}
+
+impl IdentityState for Session {
+    fn get(&self, key: &str) -> Result<Option<Value>, StorageError> {
+        Session::get(self, key).map_err(StorageError::new)
+    }
+
+    fn insert(&self, key: &str, value: Value) -> Result<(), StorageError> {
+        Session::insert(self, key, value).map_err(StorageError::new)
+    }
+
+    fn remove(&self, key: &str) {
+        Session::remove(self, key);
+    }
+
+    fn purge(&self) {
+        Session::purge(self);
+    }
+
+    fn renew(&self) {
+        Session::renew(self);
+    }
+}
+
+/// Stores identities in a self-contained token exchanged with the client.
+///
+/// The token contains the whole identity state (user id, login and visit timestamps, etc.) and is
+/// either encrypted (the default) or signed with the given key. No server-side storage is needed,
+/// but a token cannot be revoked before it expires: use a short login or visit deadline, or a
+/// custom [policy](crate::policy), if that is a concern.
+///
+/// The token is carried either by a cookie ([`TokenStorage::cookie`]) or, for API clients, by a
+/// header ([`TokenStorage::header`]). In the latter case, clients must send back the last token
+/// they received in a response: an empty header value signals that the identity has been removed.
+///
+/// # Examples
+/// ```
+/// use actix_web::{cookie::Key, App};
+/// use actix_identity::{storage::TokenStorage, IdentityMiddleware};
+///
+/// // Read the key from configuration in production deployments.
+/// let secret_key = Key::generate();
+///
+/// App::new().wrap(
+///     IdentityMiddleware::builder()
+///         .storage(TokenStorage::cookie(secret_key).cookie_name("auth"))
+///         .build(),
+/// );
+/// ```
+#[derive(Clone)]
+pub struct TokenStorage {
+    config: Arc<TokenConfiguration>,
+}
+
+#[derive(Clone)]
+struct TokenConfiguration {
+    key: Key,
+    content_security: CookieContentSecurity,
+    transport: Transport,
+}
+
+#[derive(Clone)]
+enum Transport {
+    Cookie(CookieConfiguration),
+    Header(HeaderName),
+}
+
+#[derive(Clone)]
+struct CookieConfiguration {
+    name: String,
+    secure: bool,
+    http_only: bool,
+    same_site: SameSite,
+    path: String,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+}
+
+impl TokenStorage {
+    /// Constructs a token storage that exchanges tokens through a cookie named `identity`.
+    pub fn cookie(key: Key) -> Self {
+        Self::with_transport(
+            key,
+            Transport::Cookie(CookieConfiguration {
+                name: "identity".to_owned(),
+                secure: true,
+                http_only: true,
+                same_site: SameSite::Lax,
+                path: "/".to_owned(),
+                domain: None,
+                max_age: None,
+            }),
+        )
+    }
+
+    /// Constructs a token storage that exchanges tokens through the `header` request and response
+    /// header.
+    pub fn header(key: Key, header: HeaderName) -> Self {
+        Self::with_transport(key, Transport::Header(header))
+    }
+
+    fn with_transport(key: Key, transport: Transport) -> Self {
+        Self {
+            config: Arc::new(TokenConfiguration {
+                key,
+                content_security: CookieContentSecurity::Private,
+                transport,
+            }),
+        }
+    }
+
+    /// Determines how to secure the content of the token.
+    ///
+    /// By default, tokens are encrypted ([`CookieContentSecurity::Private`]).
+    pub fn content_security(mut self, content_security: CookieContentSecurity) -> Self {
+        Arc::make_mut(&mut self.config).content_security = content_security;
+        self
+    }
+
+    /// Sets the name of the cookie carrying the token.
+    ///
+    /// Defaults to `identity`. Has no effect on header-based token storages.
+    pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+        if let Some(cookie) = self.cookie_config() {
+            cookie.name = name.into();
+        }
+        self
+    }
+
+    /// Sets the `Secure` attribute of the cookie carrying the token.
+    ///
+    /// Defaults to `true`. Has no effect on header-based token storages.
+    pub fn cookie_secure(mut self, secure: bool) -> Self {
+        if let Some(cookie) = self.cookie_config() {
+            cookie.secure = secure;
+        }
+        self
+    }
+
+    /// Sets the `HttpOnly` attribute of the cookie carrying the token.
+    ///
+    /// Defaults to `true`. Has no effect on header-based token storages.
+    pub fn cookie_http_only(mut self, http_only: bool) -> Self {
+        if let Some(cookie) = self.cookie_config() {
+            cookie.http_only = http_only;
+        }
+        self
+    }
+
+    /// Sets the `SameSite` attribute of the cookie carrying the token.
+    ///
+    /// Defaults to `Lax`. Has no effect on header-based token storages.
+    pub fn cookie_same_site(mut self, same_site: SameSite) -> Self {
+        if let Some(cookie) = self.cookie_config() {
+            cookie.same_site = same_site;
+        }
+        self
+    }
+
+    /// Sets the `Path` attribute of the cookie carrying the token.
+    ///
+    /// Defaults to `/`. Has no effect on header-based token storages.
+    pub fn cookie_path(mut self, path: impl Into<String>) -> Self {
+        if let Some(cookie) = self.cookie_config() {
+            cookie.path = path.into();
+        }
+        self
+    }
+
+    /// Sets the `Domain` attribute of the cookie carrying the token.
+    ///
+    /// By default, the attribute is left unset. Has no effect on header-based token storages.
+    pub fn cookie_domain(mut self, domain: Option<String>) -> Self {
+        if let Some(cookie) = self.cookie_config() {
+            cookie.domain = domain;
+        }
+        self
+    }
+
+    /// Sets the `Max-Age` attribute of the cookie carrying the token.
+    ///
+    /// By default, the attribute is left unset and the cookie expires when the browser is closed.
+    /// Has no effect on header-based token storages.
+    pub fn cookie_max_age(mut self, max_age: Option<Duration>) -> Self {
+        if let Some(cookie) = self.cookie_config() {
+            cookie.max_age = max_age;
+        }
+        self
+    }
+
+    fn cookie_config(&mut self) -> Option<&mut CookieConfiguration> {
+        match Arc::make_mut(&mut self.config).transport {
+            Transport::Cookie(ref mut cookie) => Some(cookie),
+            Transport::Header(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for TokenStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenStorage").finish_non_exhaustive()
+    }
+}
+
+impl IdentityStorage for TokenStorage {
+    fn load(&self, req: &ServiceRequest) -> Rc<dyn IdentityState> {
+        let token = match self.config.transport {
+            Transport::Cookie(ref cookie) => req.cookie(&cookie.name).map(|c| c.value().to_owned()),
+            Transport::Header(ref header) => req
+                .headers()
+                .get(header)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+                .map(ToOwned::to_owned),
+        };
+
+        let entries = match token {
+            Some(token) => self.config.decode(token).unwrap_or_else(|| {
+                tracing::debug!("Invalid identity token, starting from an empty identity state.");
+                HashMap::new()
+            }),
+            None => HashMap::new(),
+        };
+
+        Rc::new(TokenState {
+            config: Arc::clone(&self.config),
+            inner: RefCell::new(TokenStateInner {
+                entries,
+                status: TokenStatus::Unchanged,
+            }),
+        })
+    }
+}
+
+impl TokenConfiguration {
+    /// The name used to sign or encrypt tokens; it is bound to the token content.
+    fn token_name(&self) -> &str {
+        match self.transport {
+            Transport::Cookie(ref cookie) => &cookie.name,
+            Transport::Header(ref header) => header.as_str(),
+        }
+    }
+
+    fn decode(&self, token: String) -> Option<HashMap<String, Value>> {
+        let name = self.token_name().to_owned();
+
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(name.clone(), token));
+
+        let cookie = match self.content_security {
+            CookieContentSecurity::Signed => jar.signed(&self.key).get(&name),
+            CookieContentSecurity::Private => jar.private(&self.key).get(&name),
+        }?;
+
+        serde_json::from_str(cookie.value()).ok()
+    }
+
+    fn encode(&self, entries: &HashMap<String, Value>) -> Result<Cookie<'static>, StorageError> {
+        let value = serde_json::to_string(entries).map_err(StorageError::new)?;
+        let mut cookie = Cookie::new(self.token_name().to_owned(), value);
+
+        if let Transport::Cookie(ref config) = self.transport {
+            cookie.set_secure(config.secure);
+            cookie.set_http_only(config.http_only);
+            cookie.set_same_site(config.same_site);
+            cookie.set_path(config.path.clone());
+
+            if let Some(max_age) = config.max_age {
+                cookie.set_max_age(max_age);
+            }
+
+            if let Some(ref domain) = config.domain {
+                cookie.set_domain(domain.clone());
+            }
+        }
+
+        let mut jar = CookieJar::new();
+        match self.content_security {
+            CookieContentSecurity::Signed => jar.signed_mut(&self.key).add(cookie),
+            CookieContentSecurity::Private => jar.private_mut(&self.key).add(cookie),
+        }
+
+        Ok(jar.delta().next().cloned().expect("cookie was just added"))
+    }
+}
+
+struct TokenState {
+    config: Arc<TokenConfiguration>,
+    inner: RefCell<TokenStateInner>,
+}
+
+struct TokenStateInner {
+    entries: HashMap<String, Value>,
+    status: TokenStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenStatus {
+    Unchanged,
+    Changed,
+    Purged,
+}
+
+impl TokenState {
+    fn mark_changed(inner: &mut TokenStateInner) {
+        if inner.status != TokenStatus::Purged || !inner.entries.is_empty() {
+            inner.status = TokenStatus::Changed;
+        }
+    }
+}
+
+impl IdentityState for TokenState {
+    fn get(&self, key: &str) -> Result<Option<Value>, StorageError> {
+        Ok(self.inner.borrow().entries.get(key).cloned())
+    }
+
+    fn insert(&self, key: &str, value: Value) -> Result<(), StorageError> {
+        let mut inner = self.inner.borrow_mut();
+        inner.entries.insert(key.to_owned(), value);
+        inner.status = TokenStatus::Changed;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.entries.remove(key).is_some() {
+            Self::mark_changed(&mut inner);
+        }
+    }
+
+    fn purge(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.entries.clear();
+        inner.status = TokenStatus::Purged;
+    }
+
+    fn renew(&self) {
+        // tokens have no identifier: issuing a new token is enough
+        let mut inner = self.inner.borrow_mut();
+        Self::mark_changed(&mut inner);
+    }
+
+    fn persist(&self, res: &mut ResponseHead) -> Result<(), StorageError> {
+        let inner = self.inner.borrow();
+
+        let cookie = match inner.status {
+            TokenStatus::Unchanged => return Ok(()),
+            TokenStatus::Purged if inner.entries.is_empty() => None,
+            TokenStatus::Changed | TokenStatus::Purged => Some(self.config.encode(&inner.entries)?),
+        };
+
+        let (name, value) = match (&self.config.transport, cookie) {
+            (Transport::Cookie(_), Some(cookie)) => (SET_COOKIE, cookie.encoded().to_string()),
+            (Transport::Cookie(config), None) => {
+                let mut removal_cookie = Cookie::build(config.name.clone(), "")
+                    .path(config.path.clone())
+                    .secure(config.secure)
+                    .http_only(config.http_only)
+                    .same_site(config.same_site)
+                    .finish();
+
+                if let Some(ref domain) = config.domain {
+                    removal_cookie.set_domain(domain.clone());
+                }
+
+                removal_cookie.make_removal();
+                (SET_COOKIE, removal_cookie.to_string())
+            }
+            (Transport::Header(header), Some(cookie)) => {
+                (header.clone(), cookie.value().to_owned())
+            }
+            (Transport::Header(header), None) => (header.clone(), String::new()),
+        };
+
+        let value = HeaderValue::from_str(&value).map_err(StorageError::new)?;
+        res.headers_mut().append(name, value);
+
+        Ok(())
+    }
+}
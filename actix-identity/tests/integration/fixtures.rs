@@ -1,5 +1,14 @@
-use actix_session::{storage::CookieSessionStore, SessionMiddleware};
-use actix_web::cookie::Key;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use actix_session::{
+    storage::{CookieSessionStore, LoadError, SaveError, SessionKey, SessionStore, UpdateError},
+    SessionMiddleware,
+};
+use actix_web::cookie::{time::Duration, Key};
+use serde_json::{Map, Value};
 use uuid::Uuid;
 
 pub fn store() -> CookieSessionStore {
@@ -15,3 +24,70 @@ pub fn session_middleware() -> SessionMiddleware<CookieSessionStore> {
         .cookie_domain(Some("localhost".into()))
         .build()
 }
+
+pub fn memory_session_middleware(
+    store: MemorySessionStore,
+    key: Key,
+) -> SessionMiddleware<MemorySessionStore> {
+    SessionMiddleware::builder(store, key)
+        .cookie_domain(Some("localhost".into()))
+        .build()
+}
+
+/// A server-side session store, keeping session states in memory.
+///
+/// Unlike `CookieSessionStore`, session keys can be invalidated, which is required to test
+/// session fixation.
+#[derive(Clone, Default)]
+pub struct MemorySessionStore {
+    states: Arc<Mutex<HashMap<String, Map<String, Value>>>>,
+}
+
+impl SessionStore for MemorySessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<Map<String, Value>>, LoadError> {
+        Ok(self
+            .states
+            .lock()
+            .unwrap()
+            .get(session_key.as_ref())
+            .cloned())
+    }
+
+    async fn save(
+        &self,
+        session_state: Map<String, Value>,
+        _ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let session_key = Uuid::new_v4().to_string();
+        self.states
+            .lock()
+            .unwrap()
+            .insert(session_key.clone(), session_state);
+        Ok(session_key.try_into().unwrap())
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: Map<String, Value>,
+        _ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        self.states
+            .lock()
+            .unwrap()
+            .insert(session_key.as_ref().to_owned(), session_state);
+        Ok(session_key)
+    }
+
+    async fn update_ttl(&self, _session_key: &SessionKey, _ttl: &Duration) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        self.states.lock().unwrap().remove(session_key.as_ref());
+        Ok(())
+    }
+}
@@ -1,9 +1,19 @@
-use std::time::Duration;
-
-use actix_identity::{config::LogoutBehavior, IdentityMiddleware};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_identity::{
+    config::LogoutBehavior, events::IdentityEvent, policy::PolicyDecision, storage::TokenStorage,
+    Identity, IdentityMiddleware,
+};
+use actix_web::{cookie::Key, dev::ServiceRequest, http::header::HeaderName, HttpRequest};
 use reqwest::StatusCode;
 
-use crate::{fixtures::user_id, test_app::TestApp};
+use crate::{
+    fixtures::user_id,
+    test_app::{TestApp, RECENT_AUTH_MAX_AGE, STRICT_VISIT_DEADLINE},
+};
 
 #[actix_web::test]
 async fn opaque_401_is_returned_for_unauthenticated_users() {
@@ -91,6 +101,87 @@ async fn session_key_is_renewed_on_login() {
     assert_eq!(body.session_status, "renewed");
 }
 
+#[actix_web::test]
+async fn fixated_session_key_is_invalid_after_login() {
+    let app = TestApp::spawn_with_server_side_sessions(IdentityMiddleware::builder());
+    let user_id = user_id();
+
+    // Create an anonymous session, whose key is known to an attacker
+    let response = app
+        .request(reqwest::Method::POST, "/increment")
+        .send()
+        .await
+        .unwrap();
+    let fixated_key = response
+        .cookies()
+        .find(|cookie| cookie.name() == "id")
+        .unwrap()
+        .value()
+        .to_owned();
+    let body = app.get_current_with_session_cookie(&fixated_key).await;
+    assert_eq!(body.counter, 1);
+
+    // Log-in
+    let body = app.post_login(user_id.clone()).await;
+    assert_eq!(body.user_id, Some(user_id.clone()));
+    assert_eq!(body.session_status, "renewed");
+
+    // The fixated key no longer grants access to the session
+    let body = app.get_current_with_session_cookie(&fixated_key).await;
+    assert_eq!(body.user_id, None);
+    assert_eq!(body.counter, 0);
+
+    // The user's own session is unaffected
+    let body = app.get_current().await;
+    assert_eq!(body.user_id, Some(user_id));
+    assert_eq!(body.counter, 1);
+}
+
+#[actix_web::test]
+async fn session_key_renewal_can_be_disabled() {
+    let app = TestApp::spawn_with_server_side_sessions(
+        IdentityMiddleware::builder().renew_session_key(false),
+    );
+    let user_id = user_id();
+
+    // Create an anonymous session
+    let response = app
+        .request(reqwest::Method::POST, "/increment")
+        .send()
+        .await
+        .unwrap();
+    let session_key = response
+        .cookies()
+        .find(|cookie| cookie.name() == "id")
+        .unwrap()
+        .value()
+        .to_owned();
+
+    // Log-in
+    let body = app.post_login(user_id.clone()).await;
+    assert_eq!(body.session_status, "changed");
+
+    // The session key is still valid
+    let body = app.get_current_with_session_cookie(&session_key).await;
+    assert_eq!(body.user_id, Some(user_id));
+}
+
+#[actix_web::test]
+async fn session_key_is_renewed_on_privilege_changes() {
+    let app = TestApp::spawn();
+    let user_id = user_id();
+
+    // Log-in
+    app.post_login(user_id.clone()).await;
+
+    let body = app.post_impersonate(crate::fixtures::user_id()).await;
+    assert_eq!(body.session_status, "renewed");
+
+    let body = app.post_stop_impersonating().await;
+    assert_eq!(body.user_id, Some(user_id));
+    assert_eq!(body.session_status, "renewed");
+}
+
 #[actix_web::test]
 async fn logout_works() {
     let app = TestApp::spawn();
@@ -210,3 +301,562 @@ async fn user_is_logged_out_when_visit_deadline_is_elapsed() {
     // We have been logged out!
     assert_eq!(body.user_id, None);
 }
+
+#[actix_web::test]
+async fn nested_middleware_deadlines_only_apply_to_their_scope() {
+    let app = TestApp::spawn();
+    let user_id = user_id();
+
+    // Log-in
+    let body = app.post_login(user_id.clone()).await;
+    assert_eq!(body.user_id, Some(user_id.clone()));
+
+    // Wait for the deadline of the nested middleware to pass
+    actix_web::rt::time::sleep(STRICT_VISIT_DEADLINE * 2).await;
+
+    // The outer middleware has no deadline
+    let body = app.get_current().await;
+    assert_eq!(body.user_id, Some(user_id.clone()));
+
+    // The nested middleware logs us out...
+    let body = app.get_json("/strict/current").await;
+    assert_eq!(body.user_id, None);
+
+    // ...everywhere
+    let body = app.get_current().await;
+    assert_eq!(body.user_id, None);
+}
+
+#[actix_web::test]
+async fn nested_middleware_deadlines_are_not_reset_by_the_outer_middleware() {
+    let app = TestApp::spawn_with_config(
+        IdentityMiddleware::builder().visit_deadline(Some(Duration::from_secs(60 * 60))),
+    );
+    let user_id = user_id();
+
+    // Log-in
+    let body = app.post_login(user_id.clone()).await;
+    assert_eq!(body.user_id, Some(user_id.clone()));
+
+    // Visit the nested scope before its deadline passes
+    let body = app.get_json("/strict/current").await;
+    assert_eq!(body.user_id, Some(user_id.clone()));
+
+    actix_web::rt::time::sleep(STRICT_VISIT_DEADLINE * 2).await;
+
+    // The outer middleware must not refresh the last visit timestamp before the nested one
+    // evaluates its own deadline
+    let body = app.get_json("/strict/current").await;
+    assert_eq!(body.user_id, None);
+}
+
+#[actix_web::test]
+async fn nested_middleware_overrides_logout_behavior() {
+    let app = TestApp::spawn();
+    let user_id = user_id();
+
+    // Create a session, then log-in
+    let body = app.post_increment().await;
+    assert_eq!(body.counter, 1);
+    app.post_login(user_id.clone()).await;
+
+    // Log out within the nested scope: only the identity keys are deleted
+    let response = app
+        .request(reqwest::Method::POST, "/strict/logout")
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    let body = app.get_current().await;
+    assert_eq!(body.user_id, None);
+    assert_eq!(body.counter, 1);
+}
+
+#[actix_web::test]
+async fn require_identity_rejects_anonymous_api_requests() {
+    let app = TestApp::spawn();
+
+    let response = app
+        .get_with_accept("/require_identity", "application/json")
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn require_identity_redirects_anonymous_browsers_to_login() {
+    let app = TestApp::spawn();
+
+    let response = app
+        .get_with_accept("/require_identity?tab=1", "text/html,*/*;q=0.8")
+        .await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(
+        response.headers()[reqwest::header::LOCATION],
+        "/login?next=%2Frequire_identity%3Ftab%3D1"
+    );
+}
+
+#[actix_web::test]
+async fn require_identity_redirects_wildcard_accept_to_login() {
+    let app = TestApp::spawn();
+
+    for accept in ["*/*", "text/*", "application/json, */*;q=0.1"] {
+        let response = app.get_with_accept("/require_identity", accept).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER, "{accept}");
+    }
+
+    let response = app
+        .get_with_accept("/require_identity", "application/json, text/html;q=0")
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn require_identity_lets_authenticated_users_through() {
+    let app = TestApp::spawn();
+    app.post_login(user_id()).await;
+
+    let response = app
+        .get_with_accept("/require_identity", "application/json")
+        .await;
+    assert!(response.status().is_success());
+}
+
+#[actix_web::test]
+async fn require_role_rejects_users_without_the_role() {
+    let app = TestApp::spawn();
+
+    let response = app
+        .get_with_accept("/require_role", "application/json")
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    app.post_login_with_roles(user_id(), vec!["support".to_owned()])
+        .await;
+    // authenticated users are not sent to the login page
+    let response = app.get_with_accept("/require_role", "*/*").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn require_role_lets_users_with_the_role_through() {
+    let app = TestApp::spawn();
+
+    app.post_login_with_roles(user_id(), vec!["admin".to_owned()])
+        .await;
+    let response = app.get_with_accept("/require_role", "*/*").await;
+    assert!(response.status().is_success());
+}
+
+#[actix_web::test]
+async fn custom_policy_can_log_users_out() {
+    let app = TestApp::spawn_with_config(IdentityMiddleware::builder().policy(
+        |_: &Identity, req: &ServiceRequest| {
+            if req.path() == "/current" {
+                PolicyDecision::LogOut
+            } else {
+                PolicyDecision::StayLoggedIn
+            }
+        },
+    ));
+    let user_id = user_id();
+
+    // Log-in
+    let body = app.post_login(user_id.clone()).await;
+    assert_eq!(body.user_id, Some(user_id.clone()));
+
+    // Custom policy does not apply
+    let body = app.post_increment().await;
+    assert_eq!(body.user_id, Some(user_id));
+
+    // Custom policy logs us out
+    let body = app.get_current().await;
+    assert_eq!(body.user_id, None);
+}
+
+#[actix_web::test]
+async fn custom_policy_can_renew_the_session_key() {
+    let app = TestApp::spawn_with_config(
+        IdentityMiddleware::builder()
+            .policy(|_: &Identity, _: &ServiceRequest| PolicyDecision::Renew),
+    );
+    let user_id = user_id();
+
+    // Log-in
+    let body = app.post_login(user_id.clone()).await;
+    assert_eq!(body.user_id, Some(user_id.clone()));
+
+    let body = app.get_current().await;
+    assert_eq!(body.user_id, Some(user_id));
+    assert_eq!(body.session_status, "renewed");
+}
+
+#[actix_web::test]
+async fn policies_are_not_evaluated_after_a_log_out() {
+    let app = TestApp::spawn_with_config(
+        IdentityMiddleware::builder()
+            .policy(|_: &Identity, _: &ServiceRequest| PolicyDecision::LogOut)
+            .policy(|_: &Identity, _: &ServiceRequest| -> PolicyDecision {
+                panic!("policy should not be evaluated after a log out")
+            }),
+    );
+
+    // Log-in
+    let user_id = user_id();
+    let body = app.post_login(user_id.clone()).await;
+    assert_eq!(body.user_id, Some(user_id));
+
+    let body = app.get_current().await;
+    assert_eq!(body.user_id, None);
+}
+
+#[actix_web::test]
+async fn recent_auth_requires_a_fresh_authentication() {
+    let app = TestApp::spawn();
+
+    let response = app.get_with_accept("/recent_auth", "*/*").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Logging in counts as a fresh authentication
+    app.post_login(user_id()).await;
+    let response = app.get_with_accept("/recent_auth", "*/*").await;
+    assert!(response.status().is_success());
+
+    // Wait for the authentication to become stale
+    actix_web::rt::time::sleep(RECENT_AUTH_MAX_AGE + Duration::from_secs(1)).await;
+    let response = app.get_with_accept("/recent_auth", "*/*").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Re-authenticating makes it fresh again
+    let response = app.post_reauthenticate().await;
+    assert!(response.status().is_success());
+    let response = app.get_with_accept("/recent_auth", "*/*").await;
+    assert!(response.status().is_success());
+}
+
+#[actix_web::test]
+async fn impersonation_preserves_the_original_identity() {
+    let app = TestApp::spawn();
+    let admin_id = user_id();
+    let customer_id = user_id();
+
+    app.post_login_with_roles(admin_id.clone(), vec!["admin".to_owned()])
+        .await;
+
+    // Act as the customer
+    let body = app.post_impersonate(customer_id.clone()).await;
+    assert_eq!(body.user_id, Some(customer_id.clone()));
+    assert_eq!(body.impersonator, Some(admin_id.clone()));
+
+    let body = app.get_current().await;
+    assert_eq!(body.user_id, Some(customer_id));
+    assert_eq!(body.impersonator, Some(admin_id.clone()));
+
+    // The customer does not have the roles of the impersonator
+    let response = app.get_with_accept("/require_role", "*/*").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Go back to the original identity
+    let body = app.post_stop_impersonating().await;
+    assert_eq!(body.user_id, Some(admin_id));
+    assert_eq!(body.impersonator, None);
+
+    let response = app.get_with_accept("/require_role", "*/*").await;
+    assert!(response.status().is_success());
+}
+
+#[actix_web::test]
+async fn logging_out_while_impersonating_logs_out_both_identities() {
+    let app = TestApp::spawn_with_config(
+        IdentityMiddleware::builder().logout_behavior(LogoutBehavior::DeleteIdentityKeys),
+    );
+
+    app.post_login(user_id()).await;
+    app.post_impersonate(user_id()).await;
+
+    let response = app.post_logout().await;
+    assert!(response.status().is_success());
+
+    let body = app.get_current().await;
+    assert_eq!(body.user_id, None);
+    assert_eq!(body.impersonator, None);
+}
+
+#[actix_web::test]
+async fn token_cookie_storage_works_without_sessions() {
+    let app = TestApp::spawn_without_session(
+        IdentityMiddleware::builder()
+            .storage(TokenStorage::cookie(Key::generate()).cookie_secure(false)),
+    );
+    let user_id = user_id();
+
+    // Log-in
+    let body = app.post_login(user_id.clone()).await;
+    assert_eq!(body.user_id, Some(user_id.clone()));
+
+    // Access identity-restricted route successfully
+    let response = app.get_identity_required().await;
+    assert!(response.status().is_success());
+
+    // Log-out
+    let response = app.post_logout().await;
+    assert!(response.status().is_success());
+
+    let response = app.get_identity_required().await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn token_storage_enforces_visit_deadline() {
+    let visit_deadline = Duration::from_millis(10);
+    let app = TestApp::spawn_without_session(
+        IdentityMiddleware::builder()
+            .visit_deadline(Some(visit_deadline))
+            .storage(TokenStorage::cookie(Key::generate()).cookie_secure(false)),
+    );
+    let user_id = user_id();
+
+    // Log-in
+    let body = app.post_login(user_id.clone()).await;
+    assert_eq!(body.user_id, Some(user_id));
+
+    // Wait for deadline to pass
+    actix_web::rt::time::sleep(visit_deadline * 2).await;
+
+    let body = app.get_current().await;
+    // We have been logged out!
+    assert_eq!(body.user_id, None);
+}
+
+#[actix_web::test]
+async fn token_storage_persists_identity_when_an_inner_service_fails() {
+    let header = HeaderName::from_static("x-identity-token");
+    let app = TestApp::spawn_without_session(
+        IdentityMiddleware::builder()
+            .storage(TokenStorage::header(Key::generate(), header.clone())),
+    );
+
+    let response = app
+        .request(reqwest::Method::POST, "/failed_login")
+        .json(&serde_json::json!({ "user_id": user_id() }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let token = response.headers()[header.as_str()].clone();
+
+    let response = app
+        .request(reqwest::Method::GET, "/identity_required")
+        .header(header.as_str(), token)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+}
+
+#[actix_web::test]
+async fn token_header_storage_rejects_tampered_tokens() {
+    let header = HeaderName::from_static("x-identity-token");
+    let app = TestApp::spawn_without_session(
+        IdentityMiddleware::builder()
+            .storage(TokenStorage::header(Key::generate(), header.clone())),
+    );
+
+    // Log-in
+    let response = app
+        .request(reqwest::Method::POST, "/login")
+        .json(&serde_json::json!({ "user_id": user_id() }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let token = response.headers()[header.as_str()]
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    // Access identity-restricted route with the token
+    let response = app
+        .request(reqwest::Method::GET, "/identity_required")
+        .header(header.as_str(), &token)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    // Access identity-restricted route without the token
+    let response = app
+        .request(reqwest::Method::GET, "/identity_required")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Access identity-restricted route with a tampered token
+    let mut tampered = token.into_bytes();
+    let last = tampered.len() - 2;
+    tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+    let response = app
+        .request(reqwest::Method::GET, "/identity_required")
+        .header(header.as_str(), String::from_utf8(tampered).unwrap())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+type EventLog = Arc<Mutex<Vec<String>>>;
+
+/// Collects a short description of every event emitted by `IdentityMiddleware`.
+fn event_recorder() -> (
+    EventLog,
+    impl Fn(&IdentityEvent, &HttpRequest) + Send + Sync,
+) {
+    let events = EventLog::default();
+    let recorder = {
+        let events = Arc::clone(&events);
+        move |event: &IdentityEvent, req: &HttpRequest| {
+            let description = match event {
+                IdentityEvent::LoggedIn { user_id } => format!("logged_in {user_id}"),
+                IdentityEvent::LoggedOut { user_id } => format!("logged_out {user_id}"),
+                IdentityEvent::ExpiredLogin { user_id } => format!("expired_login {user_id}"),
+                IdentityEvent::ExpiredVisit { user_id } => format!("expired_visit {user_id}"),
+                IdentityEvent::Revoked { user_id } => format!("revoked {user_id}"),
+                IdentityEvent::ImpersonationStarted {
+                    impersonator_id,
+                    user_id,
+                } => format!("impersonation_started {impersonator_id} as {user_id}"),
+                IdentityEvent::ImpersonationStopped {
+                    impersonator_id,
+                    user_id,
+                } => format!("impersonation_stopped {impersonator_id} as {user_id}"),
+                IdentityEvent::Lost { .. } => "lost".to_owned(),
+                _ => "unknown".to_owned(),
+            };
+            events
+                .lock()
+                .unwrap()
+                .push(format!("{description} {}", req.path()));
+        }
+    };
+
+    (events, recorder)
+}
+
+#[actix_web::test]
+async fn events_are_emitted_on_login_and_logout() {
+    let (events, recorder) = event_recorder();
+    let app = TestApp::spawn_with_config(IdentityMiddleware::builder().events(recorder));
+    let user_id = user_id();
+
+    app.post_login(user_id.clone()).await;
+    app.post_increment().await;
+    app.post_logout().await;
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            format!("logged_in {user_id} /login"),
+            format!("logged_out {user_id} /logout"),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn events_are_emitted_on_impersonation() {
+    let (events, recorder) = event_recorder();
+    let app = TestApp::spawn_with_config(IdentityMiddleware::builder().events(recorder));
+    let (support_id, customer_id) = (user_id(), user_id());
+
+    app.post_login(support_id.clone()).await;
+    app.post_impersonate(customer_id.clone()).await;
+    app.post_stop_impersonating().await;
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            format!("logged_in {support_id} /login"),
+            format!("impersonation_started {support_id} as {customer_id} /impersonate"),
+            format!("impersonation_stopped {support_id} as {customer_id} /stop_impersonating"),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn events_are_emitted_when_the_visit_deadline_is_elapsed() {
+    let visit_deadline = Duration::from_millis(10);
+    let (events, recorder) = event_recorder();
+    let app = TestApp::spawn_with_config(
+        IdentityMiddleware::builder()
+            .visit_deadline(Some(visit_deadline))
+            .events(recorder),
+    );
+    let user_id = user_id();
+
+    app.post_login(user_id.clone()).await;
+    actix_web::rt::time::sleep(visit_deadline * 2).await;
+    let body = app.get_current().await;
+    assert_eq!(body.user_id, None);
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            format!("logged_in {user_id} /login"),
+            format!("expired_visit {user_id} /current"),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn events_of_nested_middleware_are_emitted_by_the_outer_handler() {
+    // the nested middleware of the `/strict` scope has no event handler of its own
+    let (events, recorder) = event_recorder();
+    let app = TestApp::spawn_with_config(IdentityMiddleware::builder().events(recorder));
+    let user_id = user_id();
+
+    app.post_login(user_id.clone()).await;
+    actix_web::rt::time::sleep(STRICT_VISIT_DEADLINE * 2).await;
+    let body = app.get_json("/strict/current").await;
+    assert_eq!(body.user_id, None);
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            format!("logged_in {user_id} /login"),
+            format!("expired_visit {user_id} /strict/current"),
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn events_are_emitted_when_custom_policies_log_users_out() {
+    let (events, recorder) = event_recorder();
+    let app = TestApp::spawn_with_config(
+        IdentityMiddleware::builder()
+            .policy(|_: &Identity, req: &ServiceRequest| {
+                if req.path() == "/current" {
+                    PolicyDecision::LogOut
+                } else {
+                    PolicyDecision::StayLoggedIn
+                }
+            })
+            .events(recorder),
+    );
+    let user_id = user_id();
+
+    app.post_login(user_id.clone()).await;
+    let body = app.get_current().await;
+    assert_eq!(body.user_id, None);
+
+    // Anonymous requests do not trigger any event
+    app.get_current().await;
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            format!("logged_in {user_id} /login"),
+            format!("revoked {user_id} /current"),
+        ]
+    );
+}
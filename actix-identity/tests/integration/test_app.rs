@@ -1,11 +1,23 @@
-use std::net::TcpListener;
+use std::{net::TcpListener, time::Duration};
 
-use actix_identity::{config::IdentityMiddlewareBuilder, Identity, IdentityMiddleware};
-use actix_session::{Session, SessionStatus};
-use actix_web::{web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer};
+use actix_identity::{
+    authorization::{RecentAuth, RequireIdentity, RequireRole},
+    config::{IdentityMiddlewareBuilder, LogoutBehavior},
+    Identity, IdentityMiddleware,
+};
+use actix_session::{storage::SessionStore, Session, SessionMiddleware, SessionStatus};
+use actix_web::{
+    cookie::Key,
+    dev::{Service as _, ServiceResponse},
+    middleware::Condition,
+    web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer,
+};
 use serde::{Deserialize, Serialize};
 
-use crate::fixtures::session_middleware;
+use crate::fixtures::{memory_session_middleware, session_middleware, MemorySessionStore};
+
+pub const RECENT_AUTH_MAX_AGE: Duration = Duration::from_secs(2);
+pub const STRICT_VISIT_DEADLINE: Duration = Duration::from_secs(2);
 
 pub struct TestApp {
     port: u16,
@@ -15,18 +27,91 @@ pub struct TestApp {
 impl TestApp {
     /// Spawn a test application using a custom configuration for `IdentityMiddleware`.
     pub fn spawn_with_config(builder: IdentityMiddlewareBuilder) -> Self {
+        Self::spawn_inner(builder, session_middleware, true)
+    }
+
+    /// Spawn a test application using a custom configuration for `IdentityMiddleware`, without
+    /// registering `SessionMiddleware`.
+    pub fn spawn_without_session(builder: IdentityMiddlewareBuilder) -> Self {
+        Self::spawn_inner(builder, session_middleware, false)
+    }
+
+    /// Spawn a test application using a custom configuration for `IdentityMiddleware`, storing
+    /// session states server-side.
+    pub fn spawn_with_server_side_sessions(builder: IdentityMiddlewareBuilder) -> Self {
+        let store = MemorySessionStore::default();
+        let key = Key::generate();
+        Self::spawn_inner(
+            builder,
+            move || memory_session_middleware(store.clone(), key.clone()),
+            true,
+        )
+    }
+
+    fn spawn_inner<St, F>(
+        builder: IdentityMiddlewareBuilder,
+        session_middleware: F,
+        with_session: bool,
+    ) -> Self
+    where
+        St: SessionStore + 'static,
+        F: Fn() -> SessionMiddleware<St> + Clone + Send + 'static,
+    {
         // Random OS port
         let listener = TcpListener::bind("localhost:0").unwrap();
         let port = listener.local_addr().unwrap().port();
         let server = HttpServer::new(move || {
             App::new()
                 .wrap(builder.clone().build())
-                .wrap(session_middleware())
+                .wrap(Condition::new(with_session, session_middleware()))
                 .route("/increment", web::post().to(increment))
                 .route("/current", web::get().to(show))
                 .route("/login", web::post().to(login))
+                .service(
+                    // logs the user in, then fails in a middleware
+                    web::resource("/failed_login")
+                        .wrap_fn(|req, srv| {
+                            let res = srv.call(req);
+                            async {
+                                res.await?;
+                                Err::<ServiceResponse, _>(
+                                    actix_web::error::ErrorInternalServerError("failed"),
+                                )
+                            }
+                        })
+                        .route(web::post().to(login)),
+                )
                 .route("/logout", web::post().to(logout))
                 .route("/identity_required", web::get().to(identity_required))
+                .service(
+                    web::resource("/require_identity")
+                        .wrap(RequireIdentity::new().login_url("/login"))
+                        .to(HttpResponse::Ok),
+                )
+                .route("/reauthenticate", web::post().to(reauthenticate))
+                .route("/impersonate", web::post().to(impersonate))
+                .route("/stop_impersonating", web::post().to(stop_impersonating))
+                .service(
+                    web::resource("/recent_auth")
+                        .wrap(RecentAuth::new(RECENT_AUTH_MAX_AGE))
+                        .to(HttpResponse::Ok),
+                )
+                .service(
+                    web::scope("/strict")
+                        .wrap(
+                            IdentityMiddleware::builder()
+                                .visit_deadline(Some(STRICT_VISIT_DEADLINE))
+                                .logout_behavior(LogoutBehavior::DeleteIdentityKeys)
+                                .build(),
+                        )
+                        .route("/current", web::get().to(show))
+                        .route("/logout", web::post().to(logout)),
+                )
+                .service(
+                    web::resource("/require_role")
+                        .wrap(RequireRole::new("admin").login_url("/login"))
+                        .to(HttpResponse::Ok),
+                )
         })
         .workers(1)
         .listen(listener)
@@ -37,6 +122,7 @@ impl TestApp {
 
         let client = reqwest::Client::builder()
             .cookie_store(true)
+            .redirect(reqwest::redirect::Policy::none())
             .build()
             .unwrap();
 
@@ -55,6 +141,11 @@ impl TestApp {
         format!("http://localhost:{}", self.port)
     }
 
+    pub fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.api_client
+            .request(method, format!("{}{path}", &self.url()))
+    }
+
     pub async fn get_identity_required(&self) -> reqwest::Response {
         self.api_client
             .get(format!("{}/identity_required", &self.url()))
@@ -64,8 +155,12 @@ impl TestApp {
     }
 
     pub async fn get_current(&self) -> EndpointResponse {
+        self.get_json("/current").await
+    }
+
+    pub async fn get_json(&self, path: &str) -> EndpointResponse {
         self.api_client
-            .get(format!("{}/current", &self.url()))
+            .get(format!("{}{path}", &self.url()))
             .send()
             .await
             .unwrap()
@@ -84,17 +179,82 @@ impl TestApp {
         response.json().await.unwrap()
     }
 
+    /// Send a request to `/current` with the given session cookie, ignoring the cookies stored by
+    /// the API client.
+    pub async fn get_current_with_session_cookie(&self, cookie: &str) -> EndpointResponse {
+        reqwest::Client::new()
+            .get(format!("{}/current", &self.url()))
+            .header(reqwest::header::COOKIE, format!("id={cookie}"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_with_accept(&self, path: &str, accept: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}{path}", &self.url()))
+            .header(reqwest::header::ACCEPT, accept)
+            .send()
+            .await
+            .unwrap()
+    }
+
     pub async fn post_login(&self, user_id: String) -> EndpointResponse {
+        self.post_login_with_roles(user_id, Vec::new()).await
+    }
+
+    pub async fn post_login_with_roles(
+        &self,
+        user_id: String,
+        roles: Vec<String>,
+    ) -> EndpointResponse {
         let response = self
             .api_client
             .post(format!("{}/login", &self.url()))
-            .json(&LoginRequest { user_id })
+            .json(&LoginRequest { user_id, roles })
             .send()
             .await
             .unwrap();
         response.json().await.unwrap()
     }
 
+    pub async fn post_reauthenticate(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/reauthenticate", &self.url()))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_impersonate(&self, user_id: String) -> EndpointResponse {
+        self.api_client
+            .post(format!("{}/impersonate", &self.url()))
+            .json(&LoginRequest {
+                user_id,
+                roles: Vec::new(),
+            })
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_stop_impersonating(&self) -> EndpointResponse {
+        self.api_client
+            .post(format!("{}/stop_impersonating", &self.url()))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
     pub async fn post_logout(&self) -> reqwest::Response {
         self.api_client
             .post(format!("{}/logout", &self.url()))
@@ -107,6 +267,8 @@ impl TestApp {
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct EndpointResponse {
     pub user_id: Option<String>,
+    #[serde(default)]
+    pub impersonator: Option<String>,
     pub counter: i32,
     pub session_status: String,
 }
@@ -114,9 +276,12 @@ pub struct EndpointResponse {
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 struct LoginRequest {
     user_id: String,
+    #[serde(default)]
+    roles: Vec<String>,
 }
 
 async fn show(user: Option<Identity>, session: Session) -> HttpResponse {
+    let impersonator = user.as_ref().and_then(|u| u.impersonator().unwrap());
     let user_id = user.map(|u| u.id().unwrap());
     let counter: i32 = session
         .get::<i32>("counter")
@@ -125,6 +290,7 @@ async fn show(user: Option<Identity>, session: Session) -> HttpResponse {
 
     HttpResponse::Ok().json(&EndpointResponse {
         user_id,
+        impersonator,
         counter,
         session_status: session_status(session),
     })
@@ -140,6 +306,7 @@ async fn increment(session: Session, user: Option<Identity>) -> HttpResponse {
 
     HttpResponse::Ok().json(&EndpointResponse {
         user_id,
+        impersonator: None,
         counter,
         session_status: session_status(session),
     })
@@ -150,8 +317,11 @@ async fn login(
     request: HttpRequest,
     session: Session,
 ) -> HttpResponse {
-    let id = user_id.into_inner().user_id;
-    let user = Identity::login(&request.extensions(), id).unwrap();
+    let LoginRequest { user_id, roles } = user_id.into_inner();
+    let user = Identity::login(&request.extensions(), user_id).unwrap();
+    if !roles.is_empty() {
+        user.set_roles(roles).unwrap();
+    }
 
     let counter: i32 = session
         .get::<i32>("counter")
@@ -160,6 +330,7 @@ async fn login(
 
     HttpResponse::Ok().json(&EndpointResponse {
         user_id: Some(user.id().unwrap()),
+        impersonator: None,
         counter,
         session_status: session_status(session),
     })
@@ -172,6 +343,25 @@ async fn logout(user: Option<Identity>) -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
+async fn reauthenticate(user: Identity) -> HttpResponse {
+    user.reauthenticated("password").unwrap();
+    HttpResponse::Ok().finish()
+}
+
+async fn impersonate(
+    target: web::Json<LoginRequest>,
+    request: HttpRequest,
+    session: Session,
+) -> HttpResponse {
+    let user = Identity::impersonate(&request.extensions(), target.into_inner().user_id).unwrap();
+    show(Some(user), session).await
+}
+
+async fn stop_impersonating(user: Identity, session: Session) -> HttpResponse {
+    let user = user.stop_impersonating().unwrap();
+    show(Some(user), session).await
+}
+
 async fn identity_required(_identity: Identity) -> HttpResponse {
     HttpResponse::Ok().finish()
 }
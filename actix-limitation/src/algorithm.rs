@@ -0,0 +1,466 @@
+//! Rate limiting algorithms.
+//!
+//! The functions in this module implement each algorithm on top of an explicit state and clock, so
+//! that they can be shared by in-process stores. The Redis store implements the same logic in Lua
+//! scripts, which must be kept in sync.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{storage::Decision, Quota};
+
+/// The algorithm used to decide whether a request is within its quota.
+///
+/// See [`Builder::algorithm`](crate::Builder::algorithm).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Algorithm {
+    /// Counts requests in consecutive, fixed windows of one period.
+    ///
+    /// This is the cheapest algorithm, but it allows up to twice the limit in bursts straddling the
+    /// boundary between two windows.
+    #[default]
+    FixedWindow,
+
+    /// Estimates the number of requests over the last period by weighting the count of the
+    /// previous fixed window by how much it overlaps with a window sliding with the current time.
+    ///
+    /// This smooths out bursts at window boundaries, assuming requests in the previous window were
+    /// evenly distributed. Rejected requests are not counted.
+    SlidingWindow,
+
+    /// A token bucket, implemented as a generic cell rate algorithm (GCRA).
+    ///
+    /// The bucket holds up to `burst` tokens and is refilled at a rate of `limit` tokens per
+    /// period: clients can send `burst` requests at once, then one request every `period / limit`.
+    /// Rejected requests are not counted, and `burst` is reported to clients as their limit.
+    TokenBucket {
+        /// The maximum number of requests that can be sent at once; must be at least 1.
+        burst: usize,
+    },
+}
+
+impl Algorithm {
+    /// Returns how many requests a key can send at once with the given `limit`, which is reported
+    /// to clients as their limit.
+    pub(crate) fn capacity(self, limit: usize) -> usize {
+        match self {
+            Algorithm::TokenBucket { burst } => burst,
+            Algorithm::FixedWindow | Algorithm::SlidingWindow => limit,
+        }
+    }
+}
+
+/// Per-key state of an algorithm; timestamps are in microseconds since the UNIX epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+    FixedWindow {
+        count: u64,
+        reset_at: u64,
+    },
+    SlidingWindow {
+        window: u64,
+        current: u64,
+        previous: u64,
+    },
+    TokenBucket {
+        /// Theoretical arrival time of the next request.
+        tat: u64,
+    },
+}
+
+/// The new state of a key, and when it can be discarded.
+pub(crate) type Update = Option<(State, u64)>;
+
+/// Returns the current time, in microseconds since the UNIX epoch.
+pub(crate) fn now() -> u64 {
+    micros(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+    )
+}
+
+pub(crate) fn micros(duration: Duration) -> u64 {
+    u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
+
+/// Counts a request of the given `cost` against `quota` at time `now`, given the current `state`
+/// of its key.
+pub(crate) fn apply(
+    algorithm: Algorithm,
+    quota: Quota,
+    state: Option<State>,
+    now: u64,
+    cost: u64,
+) -> (Decision, Update) {
+    let limit = quota.limit as u64;
+    let period = micros(quota.period).max(1);
+
+    match algorithm {
+        Algorithm::FixedWindow => fixed_window(limit, period, state, now, cost),
+        Algorithm::SlidingWindow => sliding_window(limit, period, state, now, cost),
+        Algorithm::TokenBucket { burst } => {
+            token_bucket(limit, period, burst as u64, state, now, cost)
+        }
+    }
+}
+
+/// Gives back `cost` units consumed by a previous request, given the current `state` of its key.
+///
+/// Units are only given back to the window or bucket they were taken from, if it still exists.
+pub(crate) fn refund(
+    algorithm: Algorithm,
+    quota: Quota,
+    state: Option<State>,
+    now: u64,
+    cost: u64,
+) -> Update {
+    let period = micros(quota.period).max(1);
+
+    match (algorithm, state?) {
+        (Algorithm::FixedWindow, State::FixedWindow { count, reset_at }) if reset_at > now => {
+            let count = count.saturating_sub(cost);
+            Some((State::FixedWindow { count, reset_at }, reset_at))
+        }
+
+        (
+            Algorithm::SlidingWindow,
+            State::SlidingWindow {
+                window,
+                current,
+                previous,
+            },
+        ) if window + 1 >= now / period => {
+            let current = current.saturating_sub(cost);
+            let state = State::SlidingWindow {
+                window,
+                current,
+                previous,
+            };
+            Some((state, (window + 2) * period))
+        }
+
+        (Algorithm::TokenBucket { .. }, State::TokenBucket { tat }) if quota.limit > 0 => {
+            let interval = period as f64 / quota.limit as f64;
+            let tat = ((tat as f64 - interval * cost as f64).ceil() as u64).max(now);
+            Some((State::TokenBucket { tat }, tat))
+        }
+
+        _ => None,
+    }
+}
+
+/// See `storage/lua/fixed_window.lua`.
+fn fixed_window(
+    limit: u64,
+    period: u64,
+    state: Option<State>,
+    now: u64,
+    cost: u64,
+) -> (Decision, Update) {
+    let (count, reset_at) = match state {
+        Some(State::FixedWindow { count, reset_at }) if reset_at > now => (count + cost, reset_at),
+        _ => (cost, now + period),
+    };
+
+    let decision = Decision::new(
+        count <= limit,
+        limit.saturating_sub(count) as usize,
+        Duration::from_micros(reset_at - now),
+    );
+
+    (
+        decision,
+        Some((State::FixedWindow { count, reset_at }, reset_at)),
+    )
+}
+
+/// See `storage/lua/sliding_window.lua`.
+fn sliding_window(
+    limit: u64,
+    period: u64,
+    state: Option<State>,
+    now: u64,
+    cost: u64,
+) -> (Decision, Update) {
+    let window = now / period;
+    let elapsed = now - window * period;
+    let (current, previous) = match state {
+        Some(State::SlidingWindow {
+            window: stored,
+            current,
+            previous,
+        }) if stored == window => (current, previous),
+        Some(State::SlidingWindow {
+            window: stored,
+            current,
+            ..
+        }) if stored + 1 == window => (0, current),
+        _ => (0, 0),
+    };
+
+    let (limit_f, period_f, elapsed_f) = (limit as f64, period as f64, elapsed as f64);
+    let (current_f, previous_f, cost_f) = (current as f64, previous as f64, cost as f64);
+    let estimate = previous_f * (period_f - elapsed_f) / period_f + current_f;
+
+    if estimate + cost_f > limit_f {
+        let retry_after = if current + cost > limit {
+            // wait for the next window, during which the current count keeps weighing in
+            let mut retry_after = period_f - elapsed_f;
+            if limit > 0 && current > 0 {
+                retry_after += period_f * (1.0 - (limit_f - cost_f) / current_f);
+            }
+            retry_after
+        } else {
+            (period_f - elapsed_f) - period_f * (limit_f - cost_f - current_f) / previous_f
+        };
+
+        let decision =
+            Decision::rejected(Duration::from_micros(retry_after.max(0.0).ceil() as u64));
+        return (decision, None);
+    }
+
+    let decision = Decision::new(
+        true,
+        (limit_f - estimate - cost_f).floor() as usize,
+        Duration::from_micros(period - elapsed),
+    );
+    let state = State::SlidingWindow {
+        window,
+        current: current + cost,
+        previous,
+    };
+
+    (decision, Some((state, (window + 2) * period)))
+}
+
+/// See `storage/lua/token_bucket.lua`.
+fn token_bucket(
+    limit: u64,
+    period: u64,
+    burst: u64,
+    state: Option<State>,
+    now: u64,
+    cost: u64,
+) -> (Decision, Update) {
+    if limit == 0 {
+        return (Decision::rejected(Duration::from_micros(period)), None);
+    }
+
+    let interval = period as f64 / limit as f64;
+    let tolerance = interval * burst as f64;
+    let tat = match state {
+        Some(State::TokenBucket { tat }) => tat.max(now),
+        _ => now,
+    } as f64;
+
+    let new_tat = tat + interval * cost as f64;
+    let allow_at = new_tat - tolerance;
+    let now_f = now as f64;
+
+    if now_f < allow_at {
+        let decision = Decision::rejected(Duration::from_micros((allow_at - now_f).ceil() as u64));
+        return (decision, None);
+    }
+
+    let new_tat = new_tat.ceil() as u64;
+    let decision = Decision::new(
+        true,
+        ((tolerance - (new_tat - now) as f64) / interval)
+            .floor()
+            .max(0.0) as usize,
+        Duration::from_micros(new_tat - now),
+    );
+
+    (
+        decision,
+        Some((State::TokenBucket { tat: new_tat }, new_tat)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000;
+
+    /// Sends a request at `now`, updating `state`.
+    fn hit(algorithm: Algorithm, quota: Quota, state: &mut Option<State>, now: u64) -> Decision {
+        let (decision, update) = apply(algorithm, quota, *state, now, 1);
+        if let Some((new_state, _)) = update {
+            *state = Some(new_state);
+        }
+        decision
+    }
+
+    #[test]
+    fn test_fixed_window() {
+        let quota = Quota::new(2, Duration::from_secs(10));
+        let mut state = None;
+        let start = 100 * SECOND;
+
+        let decision = hit(Algorithm::FixedWindow, quota, &mut state, start);
+        assert!(decision.is_allowed());
+        assert_eq!(decision.remaining(), 1);
+        assert_eq!(decision.reset_after(), Duration::from_secs(10));
+
+        let decision = hit(Algorithm::FixedWindow, quota, &mut state, start + SECOND);
+        assert!(decision.is_allowed());
+        assert_eq!(decision.remaining(), 0);
+        assert_eq!(decision.reset_after(), Duration::from_secs(9));
+
+        let decision = hit(
+            Algorithm::FixedWindow,
+            quota,
+            &mut state,
+            start + 2 * SECOND,
+        );
+        assert!(!decision.is_allowed());
+
+        // a new window starts
+        let decision = hit(
+            Algorithm::FixedWindow,
+            quota,
+            &mut state,
+            start + 10 * SECOND,
+        );
+        assert!(decision.is_allowed());
+        assert_eq!(decision.remaining(), 1);
+    }
+
+    #[test]
+    fn test_sliding_window_smooths_bursts_at_window_boundaries() {
+        let quota = Quota::new(10, Duration::from_secs(10));
+        let mut state = None;
+
+        // 10 requests at the very end of a window
+        for _ in 0..10 {
+            let decision = hit(Algorithm::SlidingWindow, quota, &mut state, 109 * SECOND);
+            assert!(decision.is_allowed());
+        }
+        let decision = hit(Algorithm::SlidingWindow, quota, &mut state, 109 * SECOND);
+        assert!(!decision.is_allowed());
+        // the previous window weighs 90% a second later
+        assert_eq!(decision.reset_after(), Duration::from_secs(2));
+
+        // a fixed window would allow 10 more requests at the start of the next window
+        let decision = hit(Algorithm::SlidingWindow, quota, &mut state, 110 * SECOND);
+        assert!(!decision.is_allowed());
+
+        // 50% of the previous window remains after half of the next one
+        for _ in 0..5 {
+            let decision = hit(Algorithm::SlidingWindow, quota, &mut state, 115 * SECOND);
+            assert!(decision.is_allowed());
+        }
+        let decision = hit(Algorithm::SlidingWindow, quota, &mut state, 115 * SECOND);
+        assert!(!decision.is_allowed());
+    }
+
+    #[test]
+    fn test_sliding_window_status() {
+        let quota = Quota::new(10, Duration::from_secs(10));
+        let mut state = Some(State::SlidingWindow {
+            window: 10,
+            current: 4,
+            previous: 0,
+        });
+
+        let decision = hit(Algorithm::SlidingWindow, quota, &mut state, 112 * SECOND);
+        assert!(decision.is_allowed());
+        // 4 requests in the previous window, weighing 80%
+        assert_eq!(decision.remaining(), 5);
+        assert_eq!(decision.reset_after(), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let quota = Quota::new(1, Duration::from_secs(1));
+        let algorithm = Algorithm::TokenBucket { burst: 3 };
+        let mut state = None;
+        let start = 100 * SECOND;
+
+        for remaining in (0..3).rev() {
+            let decision = hit(algorithm, quota, &mut state, start);
+            assert!(decision.is_allowed());
+            assert_eq!(decision.remaining(), remaining);
+        }
+        assert_eq!(
+            hit(algorithm, quota, &mut state, start).reset_after(),
+            Duration::from_secs(1)
+        );
+
+        // one token is added every second
+        let decision = hit(algorithm, quota, &mut state, start + SECOND);
+        assert!(decision.is_allowed());
+        assert_eq!(decision.remaining(), 0);
+        assert!(!hit(algorithm, quota, &mut state, start + SECOND).is_allowed());
+
+        // the bucket is full again after three seconds
+        let decision = hit(algorithm, quota, &mut state, start + 4 * SECOND);
+        assert!(decision.is_allowed());
+        assert_eq!(decision.remaining(), 2);
+    }
+
+    #[test]
+    fn test_zero_limit_rejects_everything() {
+        let quota = Quota::new(0, Duration::from_secs(1));
+
+        for algorithm in [
+            Algorithm::FixedWindow,
+            Algorithm::SlidingWindow,
+            Algorithm::TokenBucket { burst: 1 },
+        ] {
+            assert!(!hit(algorithm, quota, &mut None, SECOND).is_allowed());
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        let quota = Quota::new(10, Duration::from_secs(10));
+
+        for algorithm in [
+            Algorithm::FixedWindow,
+            Algorithm::SlidingWindow,
+            Algorithm::TokenBucket { burst: 10 },
+        ] {
+            let mut state = None;
+
+            let (decision, update) = apply(algorithm, quota, state, 100 * SECOND, 4);
+            assert!(decision.is_allowed(), "{algorithm:?}");
+            assert_eq!(decision.remaining(), 6, "{algorithm:?}");
+            state = update.map(|(state, _)| state);
+
+            let (decision, _) = apply(algorithm, quota, state, 100 * SECOND, 7);
+            assert!(!decision.is_allowed(), "{algorithm:?}");
+
+            // requests costing more than the limit are always rejected
+            let (decision, _) = apply(algorithm, quota, None, 100 * SECOND, 11);
+            assert!(!decision.is_allowed(), "{algorithm:?}");
+        }
+    }
+
+    #[test]
+    fn test_refund() {
+        let quota = Quota::new(10, Duration::from_secs(10));
+
+        for algorithm in [
+            Algorithm::FixedWindow,
+            Algorithm::SlidingWindow,
+            Algorithm::TokenBucket { burst: 10 },
+        ] {
+            let (_, update) = apply(algorithm, quota, None, 100 * SECOND, 10);
+            let state = update.map(|(state, _)| state);
+
+            let update = refund(algorithm, quota, state, 100 * SECOND, 3);
+            let state = update.map(|(state, _)| state);
+            assert!(state.is_some(), "{algorithm:?}");
+
+            let (decision, _) = apply(algorithm, quota, state, 100 * SECOND, 3);
+            assert!(decision.is_allowed(), "{algorithm:?}");
+            assert_eq!(decision.remaining(), 0, "{algorithm:?}");
+
+            // nothing to refund
+            assert_eq!(refund(algorithm, quota, None, 100 * SECOND, 3), None);
+        }
+    }
+}
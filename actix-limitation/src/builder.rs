@@ -1,19 +1,40 @@
-use std::{borrow::Cow, sync::Arc, time::Duration};
+use std::{borrow::Cow, collections::HashSet, sync::Arc, time::Duration};
 
-#[cfg(feature = "session")]
-use actix_session::SessionExt as _;
 use actix_web::dev::ServiceRequest;
-use redis::Client;
 
-use crate::{errors::Error, GetArcBoxKeyFn, Limiter};
+use crate::{
+    errors::Error,
+    key::{KeyExtractor, KeyResolver},
+    quota::{QuotaResolver, QuotaSource},
+    storage::{RateLimitStore, RedisStore},
+    Algorithm, BanPolicy, Limiter, Quota,
+};
+
+/// Where a [`Limiter`] keeps its counters.
+#[derive(Debug)]
+pub(crate) enum StoreConfig {
+    /// Connect to the Redis server at the given URL when the limiter is built.
+    Redis(String),
+
+    /// Use an existing store.
+    Custom(Arc<dyn RateLimitStore>),
+}
 
 /// Rate limiter builder.
 #[derive(Debug)]
 pub struct Builder {
-    pub(crate) redis_url: String,
+    pub(crate) store: StoreConfig,
     pub(crate) limit: usize,
     pub(crate) period: Duration,
-    pub(crate) get_key_fn: Option<GetArcBoxKeyFn>,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) name: Option<Cow<'static, str>>,
+    pub(crate) shadow: bool,
+    pub(crate) key_resolver: Option<Arc<dyn KeyResolver>>,
+    pub(crate) allowlist: HashSet<String>,
+    pub(crate) denylist: HashSet<String>,
+    pub(crate) ban_policy: Option<BanPolicy>,
+    pub(crate) quota_resolver: Option<Arc<dyn QuotaResolver>>,
+    pub(crate) quota_cache_ttl: Option<Duration>,
     pub(crate) cookie_name: Cow<'static, str>,
     #[cfg(feature = "session")]
     pub(crate) session_key: Cow<'static, str>,
@@ -32,6 +53,38 @@ impl Builder {
         self
     }
 
+    /// Set rate limiting algorithm.
+    ///
+    /// Defaults to [`Algorithm::FixedWindow`].
+    pub fn algorithm(&mut self, algorithm: Algorithm) -> &mut Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Sets the name of the policy enforced by the limiter, used to label its traces and metrics.
+    ///
+    /// Limiters registered in [`Limiters`](crate::Limiters) are named after the name they are
+    /// registered under. Other limiters are labeled `default` unless named.
+    pub fn name(&mut self, name: impl Into<Cow<'static, str>>) -> &mut Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Runs the limiter in shadow mode, e.g. to try a new policy against production traffic
+    /// before enforcing it.
+    ///
+    /// Shadow limiters count requests as usual, but let those over the limit through, only
+    /// reporting them: they are logged, traced and counted by the metrics as limited (see the
+    /// [crate docs](crate#observability)). An enforced and a shadow limiter can be applied to the
+    /// same route; the rate limit headers then only describe enforced limits.
+    ///
+    /// Shadow limiters cannot deny or ban keys: [`build`](Self::build) fails if a denylist or a
+    /// ban policy is set.
+    pub fn shadow(&mut self, shadow: bool) -> &mut Self {
+        self.shadow = shadow;
+        self
+    }
+
     /// Sets rate limit key derivation function.
     ///
     /// Should not be used in combination with `cookie_name` or `session_key` as they conflict.
@@ -39,16 +92,136 @@ impl Builder {
     where
         F: Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
     {
-        self.get_key_fn = Some(Arc::new(resolver));
+        self.key_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Sets rate limit key extractor.
+    ///
+    /// See the [`key`](crate::key) module for built-in extractors. Should not be used in
+    /// combination with `cookie_name` or `session_key` as they conflict.
+    pub fn key_with(&mut self, extractor: impl KeyExtractor) -> &mut Self {
+        self.key_resolver = Some(Arc::new(extractor));
+        self
+    }
+
+    /// Sets an asynchronous rate limit key resolver, e.g. looking up the tenant owning an API key
+    /// in a database.
+    ///
+    /// Quotas returned along with keys (see [`ResolvedKey`](crate::key::ResolvedKey)) take
+    /// precedence over the quota resolver, if any. See the [`key`](crate::key) module for details.
+    /// Should not be used in combination with `cookie_name` or `session_key` as they conflict.
+    pub fn key_resolver(&mut self, resolver: impl KeyResolver) -> &mut Self {
+        self.key_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Sets a function returning the quota of a key, or `None` to use the default limit and period.
+    ///
+    /// Use this to give different quotas to different keys, e.g. to tenants on different plans.
+    /// Keys are passed as returned by the key derivation function, before any policy name prefix
+    /// is added. The rate limit status reported to clients reflects the resolved quota.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use actix_limitation::{storage::MemoryStore, Limiter, Quota};
+    ///
+    /// let limiter = Limiter::builder_with_store(MemoryStore::new())
+    ///     .limit(100)
+    ///     .period(Duration::from_secs(60))
+    ///     .quota_by(|api_key: &str| {
+    ///         api_key
+    ///             .starts_with("pro-")
+    ///             .then(|| Quota::new(1000, Duration::from_secs(60)))
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn quota_by<F>(&mut self, resolver: F) -> &mut Self
+    where
+        F: Fn(&str) -> Option<Quota> + Send + Sync + 'static,
+    {
+        self.quota_resolver(move |key: String| {
+            let quota = resolver(&key);
+            async move { Ok(quota) }
+        })
+    }
+
+    /// Sets an asynchronous quota resolver, e.g. looking up the plan of a tenant in a database.
+    ///
+    /// See [`QuotaResolver`] and [`quota_cache_ttl`](Self::quota_cache_ttl). Requests whose quota
+    /// cannot be resolved fail like requests that cannot be counted.
+    pub fn quota_resolver(&mut self, resolver: impl QuotaResolver) -> &mut Self {
+        self.quota_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Caches the quotas returned by the quota resolver for `ttl`.
+    ///
+    /// By default, quotas are resolved for each request.
+    pub fn quota_cache_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.quota_cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Exempts the given keys from the limit, e.g. the addresses of internal clients or the
+    /// identifiers of premium tenants.
+    ///
+    /// Keys are matched as returned by the key derivation function, before any policy name prefix
+    /// is added. Requests of exempt keys are not counted, and the middleware does not report a rate
+    /// limit status for them.
+    pub fn allow_keys<I>(&mut self, keys: I) -> &mut Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.allowlist.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// Rejects all requests of the given keys with [`Error::Banned`].
+    ///
+    /// Keys are matched as returned by the key derivation function, before any policy name prefix
+    /// is added.
+    pub fn deny_keys<I>(&mut self, keys: I) -> &mut Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.denylist.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// Bans keys for `duration` once `rejections` of their requests have been rejected within
+    /// `window`.
+    ///
+    /// Requests of banned keys are rejected with [`Error::Banned`] without being counted. Bans are
+    /// kept in the store of the limiter, which must support them (see
+    /// [`RateLimitStore::ban`]); rejections are counted there too, under the key suffixed with a
+    /// NUL character and `rejections`. Checking bans takes an additional call to the store for each
+    /// request, shared by the limiters of a request that use the same store.
+    pub fn ban_after(
+        &mut self,
+        rejections: usize,
+        window: Duration,
+        duration: Duration,
+    ) -> &mut Self {
+        self.ban_policy = Some(BanPolicy {
+            rejections: rejections.max(1),
+            window,
+            duration,
+        });
         self
     }
 
     /// Sets name of cookie to be sent.
     ///
     /// This method should not be used in combination of `key_by` as they conflict.
-    #[deprecated = "Prefer `key_by`."]
+    #[deprecated = "Prefer `key_with(key::Cookie::new(..))`."]
     pub fn cookie_name(&mut self, cookie_name: impl Into<Cow<'static, str>>) -> &mut Self {
-        if self.get_key_fn.is_some() {
+        if self.key_resolver.is_some() {
             panic!("This method should not be used in combination of get_key as they overwrite each other")
         }
         self.cookie_name = cookie_name.into();
@@ -58,10 +231,10 @@ impl Builder {
     /// Sets session key to be used in backend.
     ///
     /// This method should not be used in combination of `key_by` as they conflict.
-    #[deprecated = "Prefer `key_by`."]
+    #[deprecated = "Prefer `key_with(key::SessionKey::new(..))`."]
     #[cfg(feature = "session")]
     pub fn session_key(&mut self, session_key: impl Into<Cow<'static, str>>) -> &mut Self {
-        if self.get_key_fn.is_some() {
+        if self.key_resolver.is_some() {
             panic!("This method should not be used in combination of get_key as they overwrite each other")
         }
         self.session_key = session_key.into();
@@ -70,60 +243,122 @@ impl Builder {
 
     /// Finalizes and returns a `Limiter`.
     ///
-    /// Note that this method will connect to the Redis server to test its connection which is a
-    /// **synchronous** operation.
+    /// When using Redis, this method does not connect to the Redis server: the connection is
+    /// established when the first request is counted, so an unreachable server or invalid
+    /// credentials only show up as errors of the first requests. Only the URL is checked here.
+    ///
+    /// Returns an error if the burst of a token bucket is zero, or if a shadow limiter has a
+    /// denylist or a ban policy.
     pub fn build(&mut self) -> Result<Limiter, Error> {
-        let get_key = if let Some(resolver) = self.get_key_fn.clone() {
-            resolver
-        } else {
-            let cookie_name = self.cookie_name.clone();
-
-            #[cfg(feature = "session")]
-            let session_key = self.session_key.clone();
+        if self.algorithm == (Algorithm::TokenBucket { burst: 0 }) {
+            return Err(Error::Other(
+                "The burst of a token bucket must be at least 1".to_owned(),
+            ));
+        }
 
-            let closure: GetArcBoxKeyFn = Arc::new(Box::new(move |req: &ServiceRequest| {
-                #[cfg(feature = "session")]
-                let res = req
-                    .get_session()
-                    .get(&session_key)
-                    .unwrap_or_else(|_| req.cookie(&cookie_name).map(|c| c.to_string()));
+        if self.shadow && (!self.denylist.is_empty() || self.ban_policy.is_some()) {
+            return Err(Error::Other(
+                "Shadow limiters cannot have a denylist or ban keys".to_owned(),
+            ));
+        }
 
-                #[cfg(not(feature = "session"))]
-                let res = req.cookie(&cookie_name).map(|c| c.to_string());
+        let store: Arc<dyn RateLimitStore> = match self.store {
+            StoreConfig::Redis(ref redis_url) => Arc::new(RedisStore::open(redis_url)?),
+            StoreConfig::Custom(ref store) => Arc::clone(store),
+        };
 
-                res
-            }));
-            closure
+        let key_resolver = match self.key_resolver {
+            Some(ref resolver) => Arc::clone(resolver),
+            None => Arc::new(DefaultKey {
+                cookie_name: self.cookie_name.clone(),
+                #[cfg(feature = "session")]
+                session_key: self.session_key.clone(),
+            }),
         };
 
         Ok(Limiter {
-            client: Client::open(self.redis_url.as_str())?,
+            store,
             limit: self.limit,
             period: self.period,
-            get_key_fn: get_key,
+            algorithm: self.algorithm,
+            name: self.name.clone(),
+            shadow: self.shadow,
+            key_resolver,
+            allowlist: Arc::new(self.allowlist.clone()),
+            denylist: Arc::new(self.denylist.clone()),
+            ban_policy: self.ban_policy,
+            quotas: self
+                .quota_resolver
+                .clone()
+                .map(|resolver| Arc::new(QuotaSource::new(resolver, self.quota_cache_ttl))),
         })
     }
 }
 
+/// The key of limiters without a key extractor or resolver.
+///
+/// Keys are the value stored under the session key of the session or, if the session cannot be
+/// read, the whole cookie (`name=value`). Requests of sessions without a value are not limited.
+/// This is kept as is so that the counters of existing deployments carry over; see the
+/// `key::SessionKey` and `key::Cookie` extractors for the values alone.
+#[derive(Debug, Clone)]
+struct DefaultKey {
+    cookie_name: Cow<'static, str>,
+    #[cfg(feature = "session")]
+    session_key: Cow<'static, str>,
+}
+
+impl DefaultKey {
+    fn cookie(&self, req: &ServiceRequest) -> Option<String> {
+        req.cookie(&self.cookie_name)
+            .map(|cookie| cookie.to_string())
+    }
+}
+
+impl KeyExtractor for DefaultKey {
+    #[cfg(feature = "session")]
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        use actix_session::SessionExt as _;
+
+        req.get_session()
+            .get(&self.session_key)
+            .unwrap_or_else(|_| self.cookie(req))
+    }
+
+    #[cfg(not(feature = "session"))]
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        self.cookie(req)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::MemoryStore;
 
     #[test]
     fn test_create_builder() {
         let redis_url = "redis://127.0.0.1";
         let period = Duration::from_secs(10);
         let builder = Builder {
-            redis_url: redis_url.to_owned(),
+            store: StoreConfig::Redis(redis_url.to_owned()),
             limit: 100,
             period,
-            get_key_fn: Some(Arc::new(|_| None)),
+            algorithm: Algorithm::FixedWindow,
+            name: None,
+            shadow: false,
+            key_resolver: Some(Arc::new(|_: &ServiceRequest| None::<String>)),
+            allowlist: HashSet::new(),
+            denylist: HashSet::new(),
+            ban_policy: None,
+            quota_resolver: None,
+            quota_cache_ttl: None,
             cookie_name: Cow::Owned("session".to_string()),
             #[cfg(feature = "session")]
             session_key: Cow::Owned("rate-api".to_string()),
         };
 
-        assert_eq!(builder.redis_url, redis_url);
+        assert!(matches!(builder.store, StoreConfig::Redis(ref url) if url == redis_url));
         assert_eq!(builder.limit, 100);
         assert_eq!(builder.period, period);
         #[cfg(feature = "session")]
@@ -136,10 +371,18 @@ mod tests {
         let redis_url = "redis://127.0.0.1";
         let period = Duration::from_secs(20);
         let mut builder = Builder {
-            redis_url: redis_url.to_owned(),
+            store: StoreConfig::Redis(redis_url.to_owned()),
             limit: 100,
             period: Duration::from_secs(10),
-            get_key_fn: Some(Arc::new(|_| None)),
+            algorithm: Algorithm::FixedWindow,
+            name: None,
+            shadow: false,
+            key_resolver: Some(Arc::new(|_: &ServiceRequest| None::<String>)),
+            allowlist: HashSet::new(),
+            denylist: HashSet::new(),
+            ban_policy: None,
+            quota_resolver: None,
+            quota_cache_ttl: None,
             cookie_name: Cow::Borrowed("sid"),
             #[cfg(feature = "session")]
             session_key: Cow::Borrowed("key"),
@@ -157,10 +400,18 @@ mod tests {
         let redis_url = "127.0.0.1";
         let period = Duration::from_secs(20);
         let mut builder = Builder {
-            redis_url: redis_url.to_owned(),
+            store: StoreConfig::Redis(redis_url.to_owned()),
             limit: 100,
             period: Duration::from_secs(10),
-            get_key_fn: Some(Arc::new(|_| None)),
+            algorithm: Algorithm::FixedWindow,
+            name: None,
+            shadow: false,
+            key_resolver: Some(Arc::new(|_: &ServiceRequest| None::<String>)),
+            allowlist: HashSet::new(),
+            denylist: HashSet::new(),
+            ban_policy: None,
+            quota_resolver: None,
+            quota_cache_ttl: None,
             cookie_name: Cow::Borrowed("sid"),
             #[cfg(feature = "session")]
             session_key: Cow::Borrowed("key"),
@@ -168,4 +419,61 @@ mod tests {
 
         builder.limit(200).period(period).build().unwrap();
     }
+
+    #[test]
+    fn test_create_limiter_zero_burst() {
+        let result = Limiter::builder_with_store(MemoryStore::new())
+            .algorithm(Algorithm::TokenBucket { burst: 0 })
+            .build();
+
+        assert!(matches!(result, Err(Error::Other(_))));
+    }
+
+    #[test]
+    fn test_create_shadow_limiter_with_bans() {
+        let result = Limiter::builder_with_store(MemoryStore::new())
+            .shadow(true)
+            .deny_keys(["abuser"])
+            .build();
+        assert!(matches!(result, Err(Error::Other(_))));
+
+        let result = Limiter::builder_with_store(MemoryStore::new())
+            .shadow(true)
+            .ban_after(1, Duration::from_secs(60), Duration::from_secs(60))
+            .build();
+        assert!(matches!(result, Err(Error::Other(_))));
+    }
+
+    #[test]
+    fn test_default_key() {
+        use actix_web::{cookie::Cookie, test::TestRequest};
+
+        let builder = Limiter::builder_with_store(MemoryStore::new());
+        let key = DefaultKey {
+            cookie_name: builder.cookie_name.clone(),
+            #[cfg(feature = "session")]
+            session_key: builder.session_key.clone(),
+        };
+        let req = TestRequest::default()
+            .cookie(Cookie::new("sid", "abc"))
+            .to_srv_request();
+
+        #[cfg(feature = "session")]
+        {
+            use actix_session::SessionExt as _;
+
+            // sessions without a value are not limited
+            assert_eq!(key.extract(&req), None);
+
+            req.get_session().insert("rate-api-id", "user-1").unwrap();
+            assert_eq!(key.extract(&req).as_deref(), Some("user-1"));
+
+            // the whole cookie is used if the session value cannot be read
+            req.get_session().insert("rate-api-id", 42).unwrap();
+            assert_eq!(key.extract(&req).as_deref(), Some("sid=abc"));
+        }
+
+        #[cfg(not(feature = "session"))]
+        assert_eq!(key.extract(&req).as_deref(), Some("sid=abc"));
+    }
 }
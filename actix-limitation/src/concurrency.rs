@@ -0,0 +1,392 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use actix_utils::future::{ok, Ready};
+use actix_web::{
+    body::{BodySize, EitherBody, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::StatusCode,
+    web::Bytes,
+    Error, HttpResponse,
+};
+use pin_project_lite::pin_project;
+
+use crate::{
+    key::{KeyExtractor, KeyResolver, PeerIp},
+    observe,
+    storage::SemaphoreStore,
+    Error as LimitationError, DEFAULT_LEASE_SECS,
+};
+
+/// How often queued requests try to acquire a permit again, by default.
+const DEFAULT_QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Concurrency limit middleware.
+///
+/// Limits how many requests of each key are in flight at once, e.g. for slow upload or report
+/// endpoints, using a semaphore per key kept in a [`SemaphoreStore`]. A request holds a permit
+/// until its response body has been sent, or dropped.
+///
+/// Semaphores are kept under the key of the request prefixed with `concurrency:`, so that they do
+/// not collide with rate limit counters in the same store. Requests are keyed by the IP address of
+/// the peer by default; see [`key_by`](Self::key_by), [`key_with`](Self::key_with) and
+/// [`key_resolver`](Self::key_resolver). Requests without a key are not limited.
+///
+/// Permits of stores shared between instances, such as [`RedisStore`], expire after a
+/// [lease](Self::lease) in case an instance crashes while holding them: it should be longer than
+/// the slowest request.
+///
+/// Requests over the limit are rejected with an empty `429 Too Many Requests` response right away
+/// by default, or can wait for a permit for a while; see [`queue_timeout`](Self::queue_timeout).
+/// Requests whose key cannot be resolved or whose permit cannot be acquired, e.g. because Redis is
+/// unreachable, are rejected with an empty `500 Internal Server Error` response, unless
+/// [`fail_open`](Self::fail_open) is set.
+///
+/// [`RedisStore`]: crate::storage::RedisStore
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+///
+/// use actix_limitation::{storage::MemoryStore, ConcurrencyLimiter};
+/// use actix_web::{web, App, HttpResponse};
+///
+/// let app = App::new().service(
+///     web::resource("/reports")
+///         // at most 2 reports per client at once, waiting up to 5 seconds for a permit
+///         .wrap(
+///             ConcurrencyLimiter::new(MemoryStore::new(), 2)
+///                 .queue_timeout(Duration::from_secs(5)),
+///         )
+///         .to(HttpResponse::Ok),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimiter {
+    store: Arc<dyn SemaphoreStore>,
+    permits: usize,
+    lease: Duration,
+    queue_timeout: Option<Duration>,
+    queue_poll_interval: Duration,
+    fail_open: bool,
+    key_resolver: Arc<dyn KeyResolver>,
+}
+
+impl ConcurrencyLimiter {
+    /// Constructs a concurrency limiter allowing `permits` requests in flight per key, keeping its
+    /// semaphores in the given `store`.
+    pub fn new(store: impl SemaphoreStore, permits: usize) -> Self {
+        Self::with_shared_store(Arc::new(store), permits)
+    }
+
+    /// Constructs a concurrency limiter allowing `permits` requests in flight per key, keeping its
+    /// semaphores in a `store` shared with other limiters.
+    pub fn with_shared_store(store: Arc<dyn SemaphoreStore>, permits: usize) -> Self {
+        Self {
+            store,
+            permits,
+            lease: Duration::from_secs(DEFAULT_LEASE_SECS),
+            queue_timeout: None,
+            queue_poll_interval: DEFAULT_QUEUE_POLL_INTERVAL,
+            fail_open: false,
+            key_resolver: Arc::new(PeerIp::default()),
+        }
+    }
+
+    /// Sets the key derivation function.
+    pub fn key_by<F>(mut self, resolver: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key_resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Sets the key extractor.
+    ///
+    /// See the [`key`](crate::key) module for built-in extractors.
+    pub fn key_with(mut self, extractor: impl KeyExtractor) -> Self {
+        self.key_resolver = Arc::new(extractor);
+        self
+    }
+
+    /// Sets an asynchronous key resolver, e.g. looking up the tenant owning an API key in a
+    /// database.
+    ///
+    /// Quotas returned along with keys are ignored. See the [`key`](crate::key) module for details.
+    pub fn key_resolver(mut self, resolver: impl KeyResolver) -> Self {
+        self.key_resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Sets how long a permit can be held before the store releases it on its own.
+    ///
+    /// Defaults to 60 seconds.
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// Lets requests over the limit wait up to `timeout` for a permit to be released before they
+    /// are rejected.
+    ///
+    /// Waiting requests poll the store for a permit, see
+    /// [`queue_poll_interval`](Self::queue_poll_interval); they are not served in order. By
+    /// default, requests are rejected right away.
+    pub fn queue_timeout(mut self, timeout: Duration) -> Self {
+        self.queue_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how often waiting requests try to acquire a permit again.
+    ///
+    /// Each attempt is a call to the store, e.g. a round-trip to Redis, so shorter intervals
+    /// reduce the time permits stay unused at the cost of more load on the store while requests
+    /// are queued. Defaults to 20 milliseconds.
+    pub fn queue_poll_interval(mut self, interval: Duration) -> Self {
+        self.queue_poll_interval = interval;
+        self
+    }
+
+    /// Lets requests through, without limiting them, when their key cannot be resolved or their
+    /// permit cannot be acquired, e.g. because Redis is unreachable.
+    ///
+    /// Failures are still logged. By default, such requests are rejected (fail closed).
+    pub fn fail_open(mut self, fail_open: bool) -> Self {
+        self.fail_open = fail_open;
+        self
+    }
+
+    /// Acquires a permit for the key of `req`, unless it has none.
+    ///
+    /// Returns the key along with its permit, which is `None` if the limit was exceeded.
+    async fn acquire_for(
+        &self,
+        req: &ServiceRequest,
+    ) -> Result<Option<(String, Option<Permit>)>, LimitationError> {
+        let Some(resolved) = self.key_resolver.resolve(req).await? else {
+            return Ok(None);
+        };
+
+        let permit = self
+            .acquire(format!("concurrency:{}", resolved.key))
+            .await?;
+        Ok(Some((resolved.key, permit)))
+    }
+
+    /// Acquires a permit for `key`, waiting for one until the queue timeout if needed.
+    async fn acquire(&self, key: String) -> Result<Option<Permit>, LimitationError> {
+        let deadline = self.queue_timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let permit = self
+                .store
+                .acquire(key.clone(), self.permits, self.lease)
+                .await?;
+
+            if let Some(id) = permit {
+                return Ok(Some(Permit {
+                    store: Arc::clone(&self.store),
+                    key,
+                    id,
+                }));
+            }
+
+            match deadline {
+                Some(deadline) if Instant::now() < deadline => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    actix_web::rt::time::sleep(remaining.min(self.queue_poll_interval)).await;
+                }
+                _ => return Ok(None),
+            }
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ConcurrencyLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<PermitBody<B>>>;
+    type Error = Error;
+    type Transform = ConcurrencyLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ConcurrencyLimiterMiddleware {
+            service: Rc::new(service),
+            config: Rc::new(self.clone()),
+        })
+    }
+}
+
+/// Concurrency limit middleware service.
+#[derive(Debug)]
+pub struct ConcurrencyLimiterMiddleware<S> {
+    service: Rc<S>,
+    config: Rc<ConcurrencyLimiter>,
+}
+
+impl<S, B> Service<ServiceRequest> for ConcurrencyLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<PermitBody<B>>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let config = Rc::clone(&self.config);
+
+        Box::pin(async move {
+            let permit = match config.acquire_for(&req).await {
+                Ok(Some((_, Some(permit)))) => Some(permit),
+                Ok(Some((key, None))) => {
+                    log::warn!(
+                        "Concurrency limit exceeded for key {}",
+                        observe::key_hash(&key)
+                    );
+                    let res = HttpResponse::new(StatusCode::TOO_MANY_REQUESTS);
+                    return Ok(req.into_response(res.map_into_right_body()));
+                }
+                Ok(None) => None,
+                Err(err) => {
+                    log::error!("Permit acquisition failed: {err}");
+                    if !config.fail_open {
+                        let res = HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR);
+                        return Ok(req.into_response(res.map_into_right_body()));
+                    }
+                    None
+                }
+            };
+
+            // on errors, the permit is released right away
+            let res = service.call(req).await?;
+
+            Ok(res
+                .map_body(|_, body| PermitBody { body, permit })
+                .map_into_left_body())
+        })
+    }
+}
+
+pin_project! {
+    /// Response body holding a permit of [`ConcurrencyLimiter`], which is released once the body
+    /// has been sent or dropped.
+    pub struct PermitBody<B> {
+        #[pin]
+        body: B,
+        permit: Option<Permit>,
+    }
+}
+
+impl<B: MessageBody> MessageBody for PermitBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.project();
+        let next = this.body.poll_next(cx);
+
+        if let Poll::Ready(None | Some(Err(_))) = next {
+            this.permit.take();
+        }
+
+        next
+    }
+}
+
+/// A permit acquired from a [`SemaphoreStore`], released when dropped.
+struct Permit {
+    store: Arc<dyn SemaphoreStore>,
+    key: String,
+    id: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let store = Arc::clone(&self.store);
+        let key = std::mem::take(&mut self.key);
+        let id = std::mem::take(&mut self.id);
+
+        let mut release = Box::pin(async move {
+            if let Err(err) = store.release(key, id).await {
+                log::error!("Permit release failed: {err}");
+            }
+        });
+
+        // release the permit right away if the store does not need to wait, e.g. the in-memory
+        // store, and in the background otherwise; without a runtime, e.g. when the response is
+        // dropped after the server stopped, the lease of the permit expires on its own
+        if release
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+            .is_pending()
+        {
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn(release);
+                }
+                Err(_) => log::warn!("Permit could not be released outside of a runtime"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_core::future::BoxFuture;
+
+    use super::*;
+
+    /// A store whose permits are released asynchronously, and never actually.
+    struct PendingStore;
+
+    impl SemaphoreStore for PendingStore {
+        fn acquire(
+            &self,
+            _key: String,
+            _permits: usize,
+            _lease: Duration,
+        ) -> BoxFuture<'_, Result<Option<String>, LimitationError>> {
+            Box::pin(async { Ok(Some("permit".to_owned())) })
+        }
+
+        fn release(
+            &self,
+            _key: String,
+            _permit: String,
+        ) -> BoxFuture<'_, Result<(), LimitationError>> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[test]
+    fn test_permit_dropped_outside_runtime() {
+        drop(Permit {
+            store: Arc::new(PendingStore),
+            key: "key".to_owned(),
+            id: "permit".to_owned(),
+        });
+    }
+}
@@ -1,9 +1,12 @@
+use std::time::Duration;
+
 use derive_more::derive::{Display, Error, From};
 
 use crate::status::Status;
 
 /// Failure modes of the rate limiter.
 #[derive(Debug, Display, Error, From)]
+#[non_exhaustive]
 pub enum Error {
     /// Redis client failed to connect or run a query.
     #[display("Redis client failed to connect or run a query")]
@@ -14,12 +17,19 @@ pub enum Error {
     #[from(ignore)]
     LimitExceeded(#[error(not(source))] Status),
 
+    /// Key is denylisted or temporarily banned.
+    ///
+    /// Holds how long a temporary ban remains, or `None` for denylisted keys.
+    #[display("Key is banned")]
+    #[from(ignore)]
+    Banned(#[error(not(source))] Option<Duration>),
+
     /// Time conversion failed.
     #[display("Time conversion failed")]
     Time(time::error::ComponentRange),
 
-    /// Generic error.
-    #[display("Generic error")]
+    /// Generic error, e.g. a misconfigured policy or a failed key or quota lookup.
+    #[display("{_0}")]
     #[from(ignore)]
     Other(#[error(not(source))] String),
 }
@@ -38,5 +48,12 @@ mod tests {
         Error:
         From<String>,
         From<Status>,
+        From<Option<Duration>>,
+    }
+
+    #[test]
+    fn other_error_displays_its_message() {
+        let err = Error::Other("Rate limit policy `api` is not registered".to_owned());
+        assert_eq!(err.to_string(), "Rate limit policy `api` is not registered");
     }
 }
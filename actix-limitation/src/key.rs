@@ -0,0 +1,710 @@
+//! Extractors deriving rate limit keys from requests.
+//!
+//! A [`KeyExtractor`] decides which counter a request is counted against; requests without a key
+//! are not rate limited. Register one with [`Builder::key_with`](crate::Builder::key_with).
+//!
+//! The built-in extractors cover the usual cases:
+//!
+//! - [`PeerIp`]: the address of the peer, aggregating IPv6 addresses by /64 network;
+//! - [`ForwardedIp`]: the address of the client as reported by trusted reverse proxies;
+//! - [`Header`]: the value of a request header, e.g. an API key;
+//! - [`Cookie`]: the value of a cookie, e.g. a session id;
+//! - `SessionKey`: a value of the session, from `actix-session` (requires the `session` feature);
+//! - `IdentityId`: the id of the logged in user, from `actix-identity` (requires the `identity`
+//!   feature);
+//! - [`RoutePattern`]: the pattern of the matched route, e.g. `/users/{id}`.
+//!
+//! Extractors can be combined: [`and`](KeyExtractor::and) joins their keys, so that each
+//! combination is limited separately, while [`or`](KeyExtractor::or) falls back to another
+//! extractor when the first one has no key. Closures with the same signature as
+//! [`extract`](KeyExtractor::extract) are extractors too.
+//!
+//! # Examples
+//! ```
+//! use actix_limitation::{
+//!     key::{ForwardedIp, Header, KeyExtractor as _, RoutePattern},
+//!     storage::MemoryStore,
+//!     Limiter,
+//! };
+//! use actix_web::http::header::HeaderName;
+//!
+//! // limit each route separately, per API key or, for anonymous clients, per IP address
+//! let limiter = Limiter::builder_with_store(MemoryStore::new())
+//!     .key_with(
+//!         RoutePattern.and(
+//!             Header::new(HeaderName::from_static("x-api-key"))
+//!                 .or(ForwardedIp::new().trust([10, 0, 0, 1].into())),
+//!         ),
+//!     )
+//!     .build()
+//!     .unwrap();
+//! ```
+//!
+//! # Asynchronous keys
+//!
+//! Extractors are synchronous. To look up keys in a cache or a database, e.g. the tenant owning
+//! an API key, implement [`KeyResolver`] or use [`resolve_fn`], and register the resolver with
+//! [`Builder::key_resolver`](crate::Builder::key_resolver). Resolvers can also override the quota
+//! of the keys they return; see [`ResolvedKey`]. All extractors are resolvers too.
+
+use std::{
+    borrow::Cow,
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use actix_web::{
+    dev::ServiceRequest,
+    http::header::{self, HeaderName},
+};
+use futures_core::future::LocalBoxFuture;
+
+use crate::{Error, Quota};
+
+/// Derives the rate limit key of a request.
+pub trait KeyExtractor: Send + Sync + 'static {
+    /// Returns the key of `req`, or `None` if it should not be rate limited.
+    fn extract(&self, req: &ServiceRequest) -> Option<String>;
+
+    /// Joins the keys of this extractor and `other`, separated by a colon.
+    ///
+    /// Requests for which either extractor has no key are not rate limited.
+    fn and<E>(self, other: E) -> And<Self, E>
+    where
+        Self: Sized,
+        E: KeyExtractor,
+    {
+        And(self, other)
+    }
+
+    /// Uses `other` for requests for which this extractor has no key.
+    fn or<E>(self, other: E) -> Or<Self, E>
+    where
+        Self: Sized,
+        E: KeyExtractor,
+    {
+        Or(self, other)
+    }
+}
+
+impl<F> KeyExtractor for F
+where
+    F: Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+{
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        (self)(req)
+    }
+}
+
+impl fmt::Debug for dyn KeyExtractor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyExtractor")
+    }
+}
+
+/// A rate limit key returned by a [`KeyResolver`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedKey {
+    pub(crate) key: String,
+    pub(crate) quota: Option<Quota>,
+}
+
+impl ResolvedKey {
+    /// Constructs a key counted against the quota of the limiter.
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            quota: None,
+        }
+    }
+
+    /// Counts the key against the given `quota` instead of the quota of the limiter.
+    ///
+    /// Takes precedence over the quota resolver of the limiter, if any (see
+    /// [`Builder::quota_resolver`](crate::Builder::quota_resolver)).
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Returns the key.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl From<String> for ResolvedKey {
+    fn from(key: String) -> Self {
+        Self::new(key)
+    }
+}
+
+/// Asynchronously derives the rate limit key of a request, and possibly its quota.
+///
+/// All [`KeyExtractor`]s, including closures with the same signature as
+/// [`extract`](KeyExtractor::extract), are resolvers. See [`resolve_fn`] to build a resolver from
+/// an asynchronous function.
+pub trait KeyResolver: Send + Sync + 'static {
+    /// Returns the key of `req`, or `None` if it should not be rate limited.
+    ///
+    /// Requests whose key cannot be resolved fail like requests that cannot be counted.
+    fn resolve<'a>(
+        &'a self,
+        req: &'a ServiceRequest,
+    ) -> LocalBoxFuture<'a, Result<Option<ResolvedKey>, Error>>;
+}
+
+impl<E: KeyExtractor> KeyResolver for E {
+    fn resolve<'a>(
+        &'a self,
+        req: &'a ServiceRequest,
+    ) -> LocalBoxFuture<'a, Result<Option<ResolvedKey>, Error>> {
+        let key = self.extract(req).map(ResolvedKey::new);
+        Box::pin(async move { Ok(key) })
+    }
+}
+
+impl fmt::Debug for dyn KeyResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyResolver")
+    }
+}
+
+/// Builds a [`KeyResolver`] from a function returning a future.
+///
+/// The future cannot borrow the request: read what is needed from the request first.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+///
+/// use actix_limitation::{
+///     key::{self, ResolvedKey},
+///     storage::MemoryStore,
+///     Error, Limiter, Quota,
+/// };
+/// use actix_web::dev::ServiceRequest;
+///
+/// # async fn tenant_of(api_key: &str) -> Result<Option<(String, bool)>, Error> { Ok(None) }
+/// // limit requests per tenant, looked up from their API key
+/// let limiter = Limiter::builder_with_store(MemoryStore::new())
+///     .key_resolver(key::resolve_fn(|req: &ServiceRequest| {
+///         let api_key = req
+///             .headers()
+///             .get("x-api-key")
+///             .and_then(|value| value.to_str().ok())
+///             .map(str::to_owned);
+///
+///         async move {
+///             let Some(api_key) = api_key else {
+///                 return Ok(None);
+///             };
+///
+///             Ok(tenant_of(&api_key).await?.map(|(tenant, premium)| {
+///                 let key = ResolvedKey::new(tenant);
+///                 if premium {
+///                     key.with_quota(Quota::new(10_000, Duration::from_secs(3600)))
+///                 } else {
+///                     key
+///                 }
+///             }))
+///         }
+///     }))
+///     .build()
+///     .unwrap();
+/// ```
+pub fn resolve_fn<F, Fut>(resolve: F) -> ResolveFn<F>
+where
+    F: Fn(&ServiceRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Option<ResolvedKey>, Error>> + 'static,
+{
+    ResolveFn(resolve)
+}
+
+/// A [`KeyResolver`] built from a function; see [`resolve_fn`].
+#[derive(Clone)]
+pub struct ResolveFn<F>(F);
+
+impl<F, Fut> KeyResolver for ResolveFn<F>
+where
+    F: Fn(&ServiceRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Option<ResolvedKey>, Error>> + 'static,
+{
+    fn resolve<'a>(
+        &'a self,
+        req: &'a ServiceRequest,
+    ) -> LocalBoxFuture<'a, Result<Option<ResolvedKey>, Error>> {
+        Box::pin((self.0)(req))
+    }
+}
+
+impl<F> fmt::Debug for ResolveFn<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResolveFn")
+    }
+}
+
+/// Joins the keys of two extractors; see [`KeyExtractor::and`].
+#[derive(Debug, Clone)]
+pub struct And<A, B>(A, B);
+
+impl<A: KeyExtractor, B: KeyExtractor> KeyExtractor for And<A, B> {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        let first = self.0.extract(req)?;
+        let second = self.1.extract(req)?;
+        Some(format!("{first}:{second}"))
+    }
+}
+
+/// Falls back to another extractor; see [`KeyExtractor::or`].
+#[derive(Debug, Clone)]
+pub struct Or<A, B>(A, B);
+
+impl<A: KeyExtractor, B: KeyExtractor> KeyExtractor for Or<A, B> {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        self.0.extract(req).or_else(|| self.1.extract(req))
+    }
+}
+
+/// How many leading bits of client addresses are significant.
+///
+/// Clients usually get a whole IPv6 /64 network, and can pick a new address in it for every
+/// request: limiting individual IPv6 addresses would be pointless.
+#[derive(Debug, Clone, Copy)]
+struct Prefixes {
+    ipv4: u8,
+    ipv6: u8,
+}
+
+impl Default for Prefixes {
+    fn default() -> Self {
+        Self { ipv4: 32, ipv6: 64 }
+    }
+}
+
+impl Prefixes {
+    fn key(self, addr: IpAddr) -> String {
+        match addr {
+            IpAddr::V4(_) if self.ipv4 >= 32 => addr.to_string(),
+            IpAddr::V6(_) if self.ipv6 >= 128 => addr.to_string(),
+            IpAddr::V4(_) => format!("{}/{}", mask(addr, self.ipv4), self.ipv4),
+            IpAddr::V6(_) => format!("{}/{}", mask(addr, self.ipv6), self.ipv6),
+        }
+    }
+}
+
+/// Keeps the first `prefix_len` bits of `addr`.
+fn mask(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len.min(32)));
+            Ipv4Addr::from(u32::from(addr) & mask.unwrap_or(0)).into()
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len.min(128)));
+            Ipv6Addr::from(u128::from(addr) & mask.unwrap_or(0)).into()
+        }
+    }
+}
+
+/// The IP address of the peer, i.e. of the client or of the last proxy in front of the
+/// application.
+///
+/// Addresses are aggregated by network: IPv6 addresses by /64 by default, IPv4 addresses are kept
+/// whole. Aggregated addresses are formatted with their prefix length, e.g. `2001:db8::/64`.
+///
+/// Use [`ForwardedIp`] if the application is deployed behind a reverse proxy.
+#[derive(Debug, Clone, Default)]
+pub struct PeerIp {
+    prefixes: Prefixes,
+}
+
+impl PeerIp {
+    /// Constructs an extractor aggregating IPv6 addresses by /64 network.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many leading bits of IPv4 addresses are significant. Defaults to 32.
+    pub fn ipv4_prefix(mut self, prefix_len: u8) -> Self {
+        self.prefixes.ipv4 = prefix_len;
+        self
+    }
+
+    /// Sets how many leading bits of IPv6 addresses are significant. Defaults to 64.
+    pub fn ipv6_prefix(mut self, prefix_len: u8) -> Self {
+        self.prefixes.ipv6 = prefix_len;
+        self
+    }
+}
+
+impl KeyExtractor for PeerIp {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        let addr = req.peer_addr()?.ip().to_canonical();
+        Some(self.prefixes.key(addr))
+    }
+}
+
+/// The IP address of the client, as reported by trusted reverse proxies.
+///
+/// The `Forwarded` header, or the `X-Forwarded-For` header if there is none, lists the addresses a
+/// request was forwarded from, each proxy appending the address of its own peer. Since clients can
+/// send these headers too, only the entries appended by trusted proxies can be relied upon: walking
+/// the list back from the peer of the application, the client address is the first one that is not
+/// a trusted proxy.
+///
+/// Without any trusted proxy, this is equivalent to [`PeerIp`]. Addresses are aggregated the same
+/// way.
+///
+/// # Examples
+/// ```
+/// use std::net::Ipv4Addr;
+///
+/// use actix_limitation::key::ForwardedIp;
+///
+/// // a load balancer, and the private network of the ingress proxies
+/// let extractor = ForwardedIp::new()
+///     .trust(Ipv4Addr::new(192, 0, 2, 1).into())
+///     .trust_network(Ipv4Addr::new(10, 0, 0, 0).into(), 8);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ForwardedIp {
+    trusted: Vec<(IpAddr, u8)>,
+    prefixes: Prefixes,
+}
+
+impl ForwardedIp {
+    /// Constructs an extractor without any trusted proxy.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the proxy at `addr`.
+    pub fn trust(self, addr: IpAddr) -> Self {
+        let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        self.trust_network(addr, prefix_len)
+    }
+
+    /// Trusts all the proxies in the network of `addr` with the given prefix length, e.g.
+    /// `10.0.0.0/8`.
+    pub fn trust_network(mut self, addr: IpAddr, prefix_len: u8) -> Self {
+        let addr = addr.to_canonical();
+        self.trusted.push((mask(addr, prefix_len), prefix_len));
+        self
+    }
+
+    /// Sets how many leading bits of IPv4 addresses are significant. Defaults to 32.
+    pub fn ipv4_prefix(mut self, prefix_len: u8) -> Self {
+        self.prefixes.ipv4 = prefix_len;
+        self
+    }
+
+    /// Sets how many leading bits of IPv6 addresses are significant. Defaults to 64.
+    pub fn ipv6_prefix(mut self, prefix_len: u8) -> Self {
+        self.prefixes.ipv6 = prefix_len;
+        self
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted.iter().any(|&(network, prefix_len)| {
+            network.is_ipv4() == addr.is_ipv4() && mask(addr, prefix_len) == network
+        })
+    }
+}
+
+impl KeyExtractor for ForwardedIp {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        let mut client = req.peer_addr()?.ip().to_canonical();
+
+        if self.is_trusted(client) {
+            for hop in forwarded_hops(req).into_iter().rev() {
+                // an entry that is not an address hides the rest of the chain: stick to the last
+                // trusted proxy
+                let Some(addr) = hop else { break };
+
+                client = addr;
+                if !self.is_trusted(addr) {
+                    break;
+                }
+            }
+        }
+
+        Some(self.prefixes.key(client))
+    }
+}
+
+/// Returns the addresses listed by the `Forwarded` headers or, if there are none, by the
+/// `X-Forwarded-For` headers, in order.
+fn forwarded_hops(req: &ServiceRequest) -> Vec<Option<IpAddr>> {
+    let headers = req.headers();
+    let values = |name| {
+        headers
+            .get_all(name)
+            .flat_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+    };
+
+    if headers.contains_key(header::FORWARDED) {
+        values(header::FORWARDED)
+            .map(|element| {
+                let node = element.split(';').find_map(|pair| {
+                    let (name, value) = pair.trim().split_once('=')?;
+                    name.eq_ignore_ascii_case("for").then_some(value)
+                });
+                node.and_then(|node| parse_node(node.trim_matches('"')))
+            })
+            .collect()
+    } else {
+        values(HeaderName::from_static("x-forwarded-for"))
+            .map(parse_node)
+            .collect()
+    }
+}
+
+/// Parses an address, possibly with a port, e.g. `192.0.2.43:47011` or `[2001:db8::1]:4711`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+
+    if let Ok(addr) = node.parse::<IpAddr>() {
+        return Some(addr.to_canonical());
+    }
+
+    let host = match node.strip_prefix('[') {
+        Some(rest) => rest.split_once(']')?.0,
+        None => node.rsplit_once(':')?.0,
+    };
+    host.parse::<IpAddr>().ok().map(|addr| addr.to_canonical())
+}
+
+/// The value of a request header, e.g. an API key.
+///
+/// Requests without the header, or with a value that is not visible ASCII, are not rate limited:
+/// combine it with [`or`](KeyExtractor::or) to limit them by another key.
+#[derive(Debug, Clone)]
+pub struct Header {
+    name: HeaderName,
+}
+
+impl Header {
+    /// Constructs an extractor reading the header with the given `name`.
+    pub fn new(name: HeaderName) -> Self {
+        Self { name }
+    }
+}
+
+impl KeyExtractor for Header {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        let value = req.headers().get(&self.name)?.to_str().ok()?;
+        Some(value.to_owned())
+    }
+}
+
+/// The value of a cookie, e.g. a session id.
+///
+/// Requests without the cookie are not rate limited: combine it with [`or`](KeyExtractor::or) to
+/// limit them by another key.
+///
+/// Unlike the default key of limiters, which is the whole cookie (`name=value`), keys are the value
+/// of the cookie alone: switching to this extractor starts counting afresh.
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    name: Cow<'static, str>,
+}
+
+impl Cookie {
+    /// Constructs an extractor reading the cookie with the given `name`.
+    pub fn new(name: impl Into<Cow<'static, str>>) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl KeyExtractor for Cookie {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        req.cookie(&self.name)
+            .map(|cookie| cookie.value().to_owned())
+    }
+}
+
+/// The string stored under a key of the `actix-session` session of the request.
+///
+/// Requests without a value, or whose session cannot be read, are not rate limited: combine it
+/// with [`or`](KeyExtractor::or) to limit them by another key. The session middleware must be
+/// registered _after_ the rate limit middleware, so that it runs first.
+///
+/// Unlike the default key of limiters, requests whose session cannot be read are not limited by
+/// their cookie: fall back to it explicitly with `SessionKey::new(..).or(Cookie::new(..))`.
+#[cfg(feature = "session")]
+#[derive(Debug, Clone)]
+pub struct SessionKey {
+    key: Cow<'static, str>,
+}
+
+#[cfg(feature = "session")]
+impl SessionKey {
+    /// Constructs an extractor reading the session value stored under `key`.
+    pub fn new(key: impl Into<Cow<'static, str>>) -> Self {
+        Self { key: key.into() }
+    }
+}
+
+#[cfg(feature = "session")]
+impl KeyExtractor for SessionKey {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        use actix_session::SessionExt as _;
+
+        req.get_session().get(&self.key).ok().flatten()
+    }
+}
+
+/// The pattern of the route matched by the request, e.g. `/users/{id}`.
+///
+/// Combine it with another extractor, using [`and`](KeyExtractor::and), to limit each route
+/// separately. Requests that do not match any route are not rate limited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoutePattern;
+
+impl KeyExtractor for RoutePattern {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        req.match_pattern()
+    }
+}
+
+/// The id of the user logged in with `actix-identity`.
+///
+/// Requests without an identity are not rate limited: combine it with [`or`](KeyExtractor::or) to
+/// limit anonymous clients by another key. The identity middleware must be registered _after_ the
+/// rate limit middleware, so that it runs first.
+#[cfg(feature = "identity")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityId;
+
+#[cfg(feature = "identity")]
+impl KeyExtractor for IdentityId {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        use actix_identity::IdentityExt as _;
+
+        req.get_identity().ok()?.id().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn peer(addr: &str) -> TestRequest {
+        TestRequest::default().peer_addr(format!("{addr}:1234").parse().unwrap())
+    }
+
+    #[test]
+    fn test_peer_ip() {
+        let req = peer("192.0.2.1").to_srv_request();
+        assert_eq!(PeerIp::new().extract(&req).unwrap(), "192.0.2.1");
+        assert_eq!(
+            PeerIp::new().ipv4_prefix(24).extract(&req).unwrap(),
+            "192.0.2.0/24"
+        );
+
+        let req = peer("[2001:db8:1:2:3:4:5:6]").to_srv_request();
+        assert_eq!(PeerIp::new().extract(&req).unwrap(), "2001:db8:1:2::/64");
+        assert_eq!(
+            PeerIp::new().ipv6_prefix(128).extract(&req).unwrap(),
+            "2001:db8:1:2:3:4:5:6"
+        );
+
+        let req = peer("[::ffff:192.0.2.1]").to_srv_request();
+        assert_eq!(PeerIp::new().extract(&req).unwrap(), "192.0.2.1");
+
+        assert!(PeerIp::new()
+            .extract(&TestRequest::default().to_srv_request())
+            .is_none());
+    }
+
+    #[test]
+    fn test_forwarded_ip() {
+        let extractor = ForwardedIp::new()
+            .trust([192, 0, 2, 1].into())
+            .trust_network([10, 0, 0, 0].into(), 8);
+
+        // untrusted peers cannot spoof their address
+        let req = peer("198.51.100.7")
+            .insert_header(("x-forwarded-for", "203.0.113.9"))
+            .to_srv_request();
+        assert_eq!(extractor.extract(&req).unwrap(), "198.51.100.7");
+
+        // entries prepended by the client are ignored
+        let req = peer("192.0.2.1")
+            .insert_header(("x-forwarded-for", "203.0.113.9, 198.51.100.7, 10.1.2.3"))
+            .to_srv_request();
+        assert_eq!(extractor.extract(&req).unwrap(), "198.51.100.7");
+
+        let req = peer("192.0.2.1")
+            .insert_header((
+                "forwarded",
+                r#"for=203.0.113.9, for="[2001:db8::1]:4711";proto=https, for=10.0.0.1"#,
+            ))
+            .insert_header(("x-forwarded-for", "203.0.113.9"))
+            .to_srv_request();
+        assert_eq!(extractor.extract(&req).unwrap(), "2001:db8::/64");
+
+        // obfuscated entries hide the rest of the chain
+        let req = peer("192.0.2.1")
+            .insert_header(("forwarded", "for=203.0.113.9, for=unknown"))
+            .to_srv_request();
+        assert_eq!(extractor.extract(&req).unwrap(), "192.0.2.1");
+
+        // only trusted proxies
+        let req = peer("192.0.2.1")
+            .insert_header(("x-forwarded-for", "10.0.0.2:8080"))
+            .to_srv_request();
+        assert_eq!(extractor.extract(&req).unwrap(), "10.0.0.2");
+    }
+
+    #[test]
+    fn test_header() {
+        let extractor = Header::new(HeaderName::from_static("x-api-key"));
+
+        let req = TestRequest::default()
+            .insert_header(("x-api-key", "secret"))
+            .to_srv_request();
+        assert_eq!(extractor.extract(&req).unwrap(), "secret");
+
+        assert!(extractor
+            .extract(&TestRequest::default().to_srv_request())
+            .is_none());
+    }
+
+    #[test]
+    fn test_cookie() {
+        let extractor = Cookie::new("sid");
+
+        let req = TestRequest::default()
+            .cookie(actix_web::cookie::Cookie::new("sid", "abc"))
+            .to_srv_request();
+        assert_eq!(extractor.extract(&req).unwrap(), "abc");
+
+        assert!(extractor
+            .extract(&TestRequest::default().to_srv_request())
+            .is_none());
+    }
+
+    #[test]
+    fn test_combinators() {
+        let api_key = Header::new(HeaderName::from_static("x-api-key"));
+        let extractor =
+            (|_: &ServiceRequest| Some("search".to_owned())).and(api_key.clone().or(PeerIp::new()));
+
+        let req = peer("192.0.2.1")
+            .insert_header(("x-api-key", "secret"))
+            .to_srv_request();
+        assert_eq!(extractor.extract(&req).unwrap(), "search:secret");
+
+        let req = peer("192.0.2.1").to_srv_request();
+        assert_eq!(extractor.extract(&req).unwrap(), "search:192.0.2.1");
+
+        let extractor = PeerIp::new().and(api_key);
+        assert!(extractor.extract(&req).is_none());
+    }
+}
@@ -1,5 +1,11 @@
 //! Rate limiter using a fixed window counter for arbitrary keys, backed by Redis for Actix Web.
 //!
+//! Sliding window and token bucket algorithms are also available; see [`Algorithm`]. Counters can
+//! also be kept in memory, e.g. for single-instance deployments or tests; see the
+//! [`storage`] module.
+//!
+//! [`ConcurrencyLimiter`] limits how many requests of a key are in flight at once instead.
+//!
 //! ```toml
 //! [dependencies]
 //! actix-web = "4"
@@ -43,6 +49,19 @@
 //!     .await
 //! }
 //! ```
+//!
+//! # Observability
+//!
+//! With the `tracing` crate feature, each rate limit decision is made in a `rate_limit` span and
+//! reported by an event carrying the policy, a hash of the key, the cost, the remaining units, the
+//! algorithm and the time spent deciding. Keys themselves are not recorded, as they often are IP
+//! addresses or user identifiers: logs and traces only ever carry a hash of them.
+//!
+//! With the `metrics` crate feature, decisions are counted by the `actix_limitation_requests_total`
+//! counter, labeled with the `policy` and the `outcome` (`allowed`, `limited` or `errored`), and
+//! the time spent deciding is recorded by the `actix_limitation_decision_duration_seconds`
+//! histogram, labeled with the `policy`. Policies are labeled with the name of the limiter; see
+//! [`Builder::name`].
 
 #![forbid(unsafe_code)]
 #![warn(missing_docs, missing_debug_implementations)]
@@ -50,17 +69,41 @@
 #![doc(html_favicon_url = "https://actix.rs/favicon.ico")]
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
-use std::{borrow::Cow, fmt, sync::Arc, time::Duration};
-
-use actix_web::dev::ServiceRequest;
-use redis::{AsyncConnectionConfig, Client};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+pub mod algorithm;
 mod builder;
+mod concurrency;
 mod errors;
+pub mod key;
+mod limiters;
 mod middleware;
+mod observe;
+mod quota;
 mod status;
+pub mod storage;
+pub mod stream;
 
-pub use self::{builder::Builder, errors::Error, middleware::RateLimiter, status::Status};
+pub use self::{
+    algorithm::Algorithm,
+    builder::Builder,
+    concurrency::ConcurrencyLimiter,
+    errors::Error,
+    limiters::Limiters,
+    middleware::{HeaderStyle, RateLimiter},
+    quota::{Quota, QuotaResolver},
+    status::Status,
+};
+use self::{
+    builder::StoreConfig,
+    quota::QuotaSource,
+    storage::{Decision, Hit, RateLimitStore},
+};
 
 /// Default request limit.
 pub const DEFAULT_REQUEST_LIMIT: usize = 5000;
@@ -68,6 +111,9 @@ pub const DEFAULT_REQUEST_LIMIT: usize = 5000;
 /// Default period (in seconds).
 pub const DEFAULT_PERIOD_SECS: u64 = 3600;
 
+/// Default lease of concurrency limit permits (in seconds).
+pub const DEFAULT_LEASE_SECS: u64 = 60;
+
 /// Default cookie name.
 pub const DEFAULT_COOKIE_NAME: &str = "sid";
 
@@ -75,31 +121,49 @@ pub const DEFAULT_COOKIE_NAME: &str = "sid";
 #[cfg(feature = "session")]
 pub const DEFAULT_SESSION_KEY: &str = "rate-api-id";
 
-/// Helper trait to impl Debug on GetKeyFn type
-trait GetKeyFnT: Fn(&ServiceRequest) -> Option<String> {}
-
-impl<T> GetKeyFnT for T where T: Fn(&ServiceRequest) -> Option<String> {}
-
-/// Get key function type with auto traits
-type GetKeyFn = dyn GetKeyFnT + Send + Sync;
-
-/// Get key resolver function type
-impl fmt::Debug for GetKeyFn {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "GetKeyFn")
-    }
+/// When keys are temporarily banned.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BanPolicy {
+    /// Number of rejections that triggers a ban.
+    rejections: usize,
+    /// Period in which rejections are counted.
+    window: Duration,
+    /// How long bans last.
+    duration: Duration,
 }
 
-/// Wrapped Get key function Trait
-type GetArcBoxKeyFn = Arc<GetKeyFn>;
-
 /// Rate limiter.
 #[derive(Debug, Clone)]
 pub struct Limiter {
-    client: Client,
+    store: Arc<dyn RateLimitStore>,
     limit: usize,
     period: Duration,
-    get_key_fn: GetArcBoxKeyFn,
+    algorithm: Algorithm,
+    name: Option<Cow<'static, str>>,
+    shadow: bool,
+    key_resolver: Arc<dyn key::KeyResolver>,
+    allowlist: Arc<HashSet<String>>,
+    denylist: Arc<HashSet<String>>,
+    ban_policy: Option<BanPolicy>,
+    quotas: Option<Arc<QuotaSource>>,
+}
+
+/// A limit applied to a request.
+#[derive(Debug, Clone)]
+pub(crate) struct Target {
+    pub(crate) limiter: Arc<Limiter>,
+    /// The key the request is counted under.
+    pub(crate) key: String,
+    /// The quota of the key.
+    pub(crate) quota: Quota,
+}
+
+impl Target {
+    /// Gives back `cost` units consumed by a request counted against this limit.
+    pub(crate) async fn refund(&self, cost: usize) -> Result<(), Error> {
+        let hit = Hit::new(&self.key, self.quota, self.limiter.algorithm, cost);
+        self.limiter.store.refund(hit).await
+    }
 }
 
 impl Limiter {
@@ -109,11 +173,42 @@ impl Limiter {
     /// parameters for how to set the Redis URL.
     #[must_use]
     pub fn builder(redis_url: impl Into<String>) -> Builder {
+        Self::builder_from(StoreConfig::Redis(redis_url.into()))
+    }
+
+    /// Construct rate limiter builder with defaults, keeping counters in the given `store`.
+    ///
+    /// See the [`storage`] module for the available stores.
+    #[must_use]
+    pub fn builder_with_store(store: impl RateLimitStore) -> Builder {
+        Self::builder_from(StoreConfig::Custom(Arc::new(store)))
+    }
+
+    /// Construct rate limiter builder with defaults, keeping counters in a `store` shared with
+    /// other limiters.
+    ///
+    /// When several limiters apply to the same request (see [`RateLimiter::limiter`]), the limits of
+    /// those sharing a store are checked with a single call to [`RateLimitStore::hit_many`], i.e. in
+    /// a single round-trip for [`RedisStore`](storage::RedisStore).
+    #[must_use]
+    pub fn builder_with_shared_store(store: Arc<dyn RateLimitStore>) -> Builder {
+        Self::builder_from(StoreConfig::Custom(store))
+    }
+
+    fn builder_from(store: StoreConfig) -> Builder {
         Builder {
-            redis_url: redis_url.into(),
+            store,
             limit: DEFAULT_REQUEST_LIMIT,
             period: Duration::from_secs(DEFAULT_PERIOD_SECS),
-            get_key_fn: None,
+            algorithm: Algorithm::default(),
+            name: None,
+            shadow: false,
+            key_resolver: None,
+            allowlist: HashSet::new(),
+            denylist: HashSet::new(),
+            ban_policy: None,
+            quota_resolver: None,
+            quota_cache_ttl: None,
             cookie_name: Cow::Borrowed(DEFAULT_COOKIE_NAME),
             #[cfg(feature = "session")]
             session_key: Cow::Borrowed(DEFAULT_SESSION_KEY),
@@ -122,53 +217,293 @@ impl Limiter {
 
     /// Consumes one rate limit unit, returning the status.
     pub async fn count(&self, key: impl Into<String>) -> Result<Status, Error> {
-        let (count, reset) = self.track(key).await?;
-        let status = Status::new(count, self.limit, reset);
+        self.consume(key, 1).await
+    }
 
-        if count > self.limit {
-            Err(Error::LimitExceeded(status))
-        } else {
-            Ok(status)
+    /// Consumes `cost` rate limit units at once, returning the status.
+    ///
+    /// Use this for requests that are more expensive than others, e.g. exports or searches. A
+    /// request costing more than the limit is always rejected.
+    ///
+    /// Keys exempt from the limit (see [`Builder::allow_keys`]) are not counted, and always have
+    /// their full quota remaining. Shadow limiters (see [`Builder::shadow`]) return the status of
+    /// keys over the limit instead of [`Error::LimitExceeded`].
+    pub async fn consume(&self, key: impl Into<String>, cost: usize) -> Result<Status, Error> {
+        let key = key.into();
+
+        observe::in_span(cost, async {
+            let started = Instant::now();
+            let outcome = self.decide(&key, cost).await;
+            observe::record(self, &key, cost, outcome.as_ref(), Some(started.elapsed()));
+            self.enforce(&key, outcome)
+        })
+        .await
+    }
+
+    async fn decide(&self, key: &str, cost: usize) -> Result<Status, Error> {
+        if !self.screen(key)? {
+            let limit = self.algorithm.capacity(self.quota(key).await?.limit);
+            return Status::from_decision(limit, &Decision::new(true, limit, Duration::ZERO));
         }
+
+        if let Some(ttl) = self.ban_ttl(key).await? {
+            return Err(Error::Banned(Some(ttl)));
+        }
+
+        let quota = self.quota(key).await?;
+        let decision = self
+            .store
+            .hit(Hit::new(key, quota, self.algorithm, cost))
+            .await?;
+        self.judge(key, quota, &decision).await
     }
 
-    /// Tracks the given key in a period and returns the count and TTL for the key in seconds.
-    async fn track(&self, key: impl Into<String>) -> Result<(usize, usize), Error> {
+    /// Gives back `cost` rate limit units previously consumed, e.g. for a request that turned out
+    /// to be cheap.
+    ///
+    /// Units are only given back to the current period: units consumed in a period that has since
+    /// ended are not refunded.
+    pub async fn refund(&self, key: impl Into<String>, cost: usize) -> Result<(), Error> {
         let key = key.into();
-        let expires = self.period.as_secs();
-
-        // Keep pre-redis@1 behavior by opting out of default async connection/response timeouts.
-        let connection_config = AsyncConnectionConfig::new()
-            .set_connection_timeout(None)
-            .set_response_timeout(None);
-        let mut connection = self
-            .client
-            .get_multiplexed_async_connection_with_config(&connection_config)
-            .await?;
+        let quota = self.quota(&key).await?;
+        self.store
+            .refund(Hit::new(key, quota, self.algorithm, cost))
+            .await
+    }
+
+    /// Returns the quota of `key`, as resolved by the quota resolver if any.
+    pub(crate) async fn quota(&self, key: &str) -> Result<Quota, Error> {
+        let resolved = match self.quotas {
+            Some(ref quotas) => quotas.resolve(key).await?,
+            None => None,
+        };
+
+        Ok(resolved.unwrap_or_else(|| Quota::new(self.limit, self.period)))
+    }
+
+    /// Returns the keys currently banned in the store of the limiter, along with how long they
+    /// remain banned.
+    ///
+    /// Bans of all the limiters sharing the store are returned, under the keys they are counted
+    /// with, i.e. prefixed with the name of their policy for named policies. Denylisted keys are
+    /// not included.
+    pub async fn bans(&self) -> Result<Vec<(String, Duration)>, Error> {
+        self.store.bans().await
+    }
+
+    /// Lifts the ban of `key`, returning whether it was banned.
+    ///
+    /// For named policies, `key` must be prefixed with the name of the policy and a colon.
+    pub async fn unban(&self, key: impl Into<String>) -> Result<bool, Error> {
+        self.store.unban(key.into()).await
+    }
+
+    /// Returns whether `key` is counted against the limit, i.e. `false` if it is exempt, and an
+    /// error if it is denylisted.
+    pub(crate) fn screen(&self, key: &str) -> Result<bool, Error> {
+        if self.denylist.contains(key) {
+            Err(Error::Banned(None))
+        } else {
+            Ok(!self.allowlist.contains(key))
+        }
+    }
+
+    /// Returns how long `key` remains banned, if it is.
+    async fn ban_ttl(&self, key: &str) -> Result<Option<Duration>, Error> {
+        match self.ban_policy {
+            Some(_) => self.store.ban_ttl(key.to_owned()).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Returns an error if the key of any of the limiters that ban keys is banned.
+    ///
+    /// Bans of limiters sharing a store are checked together.
+    async fn check_bans(targets: &[Target]) -> Result<(), Error> {
+        let mut pending: Vec<_> = targets
+            .iter()
+            .filter(|target| target.limiter.ban_policy.is_some())
+            .collect();
+
+        while let Some(first) = pending.first() {
+            let store = Arc::clone(&first.limiter.store);
+            let (group, rest): (Vec<_>, Vec<_>) = pending
+                .into_iter()
+                .partition(|target| same_store(&target.limiter.store, &store));
+            pending = rest;
+
+            let keys = group.iter().map(|target| target.key.clone()).collect();
+            if let Some(ttl) = store.ban_ttl_many(keys).await?.into_iter().flatten().max() {
+                return Err(Error::Banned(Some(ttl)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Consumes `cost` rate limit units from each limiter, for the associated key.
+    ///
+    /// Limits of limiters sharing a store are checked together. Store failures and bans abort the
+    /// whole operation; otherwise, an outcome is returned for each limiter, in order. When an
+    /// enforced limit rejects the request, the units consumed from the other limits are given back,
+    /// so that e.g. a request over a burst limit does not count against a daily limit.
+    pub(crate) async fn count_all(
+        targets: &[Target],
+        cost: usize,
+    ) -> Result<Vec<Result<Status, Error>>, Error> {
+        observe::in_span(cost, async {
+            let started = Instant::now();
+            let outcomes = Self::count_targets(targets, cost).await;
+            let latency = started.elapsed();
+
+            for (idx, Target { limiter, key, .. }) in targets.iter().enumerate() {
+                let outcome = match outcomes {
+                    Ok(ref outcomes) => outcomes[idx].as_ref(),
+                    Err(ref err) => Err(err),
+                };
+                observe::record(limiter, key, cost, outcome, Some(latency));
+            }
+
+            outcomes.map(|outcomes| {
+                targets
+                    .iter()
+                    .zip(outcomes)
+                    .map(|(Target { limiter, key, .. }, outcome)| limiter.enforce(key, outcome))
+                    .collect()
+            })
+        })
+        .await
+    }
+
+    async fn count_targets(
+        targets: &[Target],
+        cost: usize,
+    ) -> Result<Vec<Result<Status, Error>>, Error> {
+        Self::check_bans(targets).await?;
 
-        // The seed of this approach is outlined Atul R in a blog post about rate limiting using
-        // NodeJS and Redis. For more details, see https://blog.atulr.com/rate-limiter
-        let mut pipe = redis::pipe();
-        pipe.atomic()
-            .cmd("SET") // Set key and value
-            .arg(&key)
-            .arg(0)
-            .arg("EX") // Set the specified expire time, in seconds.
-            .arg(expires)
-            .arg("NX") // Only set the key if it does not already exist.
-            .ignore() // --- ignore returned value of SET command ---
-            .cmd("INCR") // Increment key
-            .arg(&key)
-            .cmd("TTL") // Return time-to-live of key
-            .arg(&key);
-
-        let (count, ttl) = pipe.query_async(&mut connection).await?;
-        let reset = Status::epoch_utc_plus(Duration::from_secs(ttl))?;
-
-        Ok((count, reset))
+        let mut outcomes: Vec<_> = targets.iter().map(|_| None).collect();
+        let mut consumed = vec![false; targets.len()];
+        let mut pending: Vec<usize> = (0..targets.len()).collect();
+
+        while let Some(&first) = pending.first() {
+            let store = Arc::clone(&targets[first].limiter.store);
+            let (group, rest): (Vec<_>, Vec<_>) = pending
+                .into_iter()
+                .partition(|&idx| same_store(&targets[idx].limiter.store, &store));
+            pending = rest;
+
+            let decisions = store
+                .hit_many(
+                    group
+                        .iter()
+                        .map(|&idx| {
+                            let target = &targets[idx];
+                            Hit::new(&target.key, target.quota, target.limiter.algorithm, cost)
+                        })
+                        .collect(),
+                )
+                .await?;
+
+            for (idx, decision) in group.into_iter().zip(decisions) {
+                let Target {
+                    limiter,
+                    key,
+                    quota,
+                } = &targets[idx];
+                consumed[idx] = decision.is_allowed();
+                outcomes[idx] = Some(limiter.judge(key, *quota, &decision).await);
+            }
+        }
+
+        let rejected = targets.iter().zip(&outcomes).any(|(target, outcome)| {
+            !target.limiter.shadow
+                && matches!(
+                    outcome,
+                    Some(Err(Error::LimitExceeded(_) | Error::Banned(_)))
+                )
+        });
+        if rejected {
+            for (target, _) in targets
+                .iter()
+                .zip(consumed)
+                .filter(|(_, consumed)| *consumed)
+            {
+                if let Err(err) = target.refund(cost).await {
+                    log::error!("Refund failed: {}", err);
+                }
+            }
+        }
+
+        Ok(outcomes.into_iter().flatten().collect())
+    }
+
+    /// Returns the outcome of a request of `key` as enforced by the limiter, i.e. lets requests over
+    /// the limit of shadow limiters through.
+    fn enforce(&self, key: &str, outcome: Result<Status, Error>) -> Result<Status, Error> {
+        match outcome {
+            Err(Error::LimitExceeded(status)) if self.shadow => {
+                log::warn!(
+                    "Shadow rate limit exceeded for key {}",
+                    observe::key_hash(key)
+                );
+                Ok(status)
+            }
+            outcome => outcome,
+        }
+    }
+
+    /// Returns the outcome of a request of `key`, banning the key if it has been rejected too
+    /// often.
+    async fn judge(&self, key: &str, quota: Quota, decision: &Decision) -> Result<Status, Error> {
+        let status = Status::from_decision(self.algorithm.capacity(quota.limit), decision)?;
+
+        if decision.is_allowed() {
+            return Ok(status);
+        }
+
+        if let Some(policy) = self.ban_policy {
+            let rejections = Hit::new(
+                rejections_key(key),
+                Quota::new(policy.rejections - 1, policy.window),
+                Algorithm::FixedWindow,
+                1,
+            );
+
+            if !self.store.hit(rejections.clone()).await?.is_allowed() {
+                log::warn!(
+                    "Banning key {} for {:?}",
+                    observe::key_hash(key),
+                    policy.duration
+                );
+                self.store.ban(key.to_owned(), policy.duration).await?;
+
+                // start counting rejections afresh once the ban is over or lifted
+                let reset = Hit {
+                    cost: policy.rejections,
+                    ..rejections
+                };
+                self.store.refund(reset).await?;
+
+                return Err(Error::Banned(Some(policy.duration)));
+            }
+        }
+
+        Err(Error::LimitExceeded(status))
     }
 }
 
+/// Returns the key under which the rejections of `key` are counted.
+///
+/// Keys taken from requests cannot contain NUL characters, so this does not collide with them.
+fn rejections_key(key: &str) -> String {
+    format!("{key}\0rejections")
+}
+
+fn same_store(a: &Arc<dyn RateLimitStore>, b: &Arc<dyn RateLimitStore>) -> bool {
+    // compare data pointers only, vtables of the same type may differ between codegen units
+    std::ptr::addr_eq(Arc::as_ptr(a), Arc::as_ptr(b))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,75 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use crate::Limiter;
+
+/// A set of named rate limiters, shared by all the [`RateLimiter`](crate::RateLimiter) middleware
+/// of an application.
+///
+/// Register it as app data and select limiters by name with
+/// [`RateLimiter::policy`](crate::RateLimiter::policy).
+///
+/// ```no_run
+/// use std::{sync::Arc, time::Duration};
+///
+/// use actix_limitation::{storage::RedisStore, Limiter, Limiters, RateLimiter};
+/// use actix_web::{web, App, HttpResponse};
+///
+/// // both limits of `/search` are checked in a single round-trip
+/// let store = Arc::new(RedisStore::open("redis://127.0.0.1").unwrap());
+///
+/// let limiters = Limiters::new()
+///     .insert(
+///         "burst",
+///         Limiter::builder_with_shared_store(store.clone())
+///             .limit(10)
+///             .period(Duration::from_secs(1))
+///             .build()
+///             .unwrap(),
+///     )
+///     .insert(
+///         "daily",
+///         Limiter::builder_with_shared_store(store.clone())
+///             .limit(10_000)
+///             .period(Duration::from_secs(24 * 60 * 60))
+///             .build()
+///             .unwrap(),
+///     );
+///
+/// let app = App::new().app_data(web::Data::new(limiters)).service(
+///     web::resource("/search")
+///         .wrap(RateLimiter::default().policy("burst").policy("daily"))
+///         .to(HttpResponse::Ok),
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Limiters {
+    limiters: HashMap<Cow<'static, str>, Arc<Limiter>>,
+}
+
+impl Limiters {
+    /// Constructs an empty set of limiters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a limiter under the given `name`, replacing any limiter previously registered with
+    /// that name.
+    ///
+    /// The traces and metrics of the limiter are labeled with `name`, instead of the name set with
+    /// [`Builder::name`](crate::Builder::name).
+    pub fn insert(mut self, name: impl Into<Cow<'static, str>>, mut limiter: Limiter) -> Self {
+        let name = name.into();
+        limiter.name = Some(name.clone());
+        self.limiters.insert(name, Arc::new(limiter));
+        self
+    }
+
+    /// Returns the limiter registered under the given `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&Limiter> {
+        self.limiters.get(name).map(|limiter| &**limiter)
+    }
+
+    pub(crate) fn get_shared(&self, name: &str) -> Option<Arc<Limiter>> {
+        self.limiters.get(name).cloned()
+    }
+}
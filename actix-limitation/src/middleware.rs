@@ -1,19 +1,447 @@
-use std::{future::Future, pin::Pin, rc::Rc};
+use std::{borrow::Cow, fmt, future::Future, pin::Pin, rc::Rc, sync::Arc};
 
-use actix_utils::future::{ok, Ready};
+use actix_utils::future::{err, ok, Ready};
 use actix_web::{
     body::EitherBody,
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    http::StatusCode,
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        StatusCode,
+    },
     web, Error, HttpResponse,
 };
 
-use crate::{Error as LimitationError, Limiter};
+use crate::{
+    key::ResolvedKey, observe, Error as LimitationError, Limiter, Limiters, Quota, Status, Target,
+};
+
+/// Which headers [`RateLimiter`] uses to report the rate limit status to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum HeaderStyle {
+    /// `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` (in seconds from now), as
+    /// described by the IETF [RateLimit header fields draft].
+    ///
+    /// [RateLimit header fields draft]: https://datatracker.ietf.org/doc/draft-ietf-httpapi-ratelimit-headers/
+    #[default]
+    Draft,
+
+    /// `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset` (as a UNIX timestamp).
+    Legacy,
+
+    /// Do not report the rate limit status.
+    ///
+    /// `Retry-After` is still sent with `429 Too Many Requests` responses.
+    None,
+}
+
+impl HeaderStyle {
+    fn insert(self, headers: &mut HeaderMap, status: &Status) {
+        let (limit, remaining, reset, reset_value) = match self {
+            Self::Draft => (
+                "ratelimit-limit",
+                "ratelimit-remaining",
+                "ratelimit-reset",
+                status.reset_after_secs(),
+            ),
+            Self::Legacy => (
+                "x-ratelimit-limit",
+                "x-ratelimit-remaining",
+                "x-ratelimit-reset",
+                status.reset_epoch_utc(),
+            ),
+            Self::None => return,
+        };
+
+        for (name, value) in [
+            (limit, status.limit()),
+            (remaining, status.remaining()),
+            (reset, reset_value),
+        ] {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        }
+    }
+}
+
+/// Where [`RateLimiter`] finds one of the limiters it enforces.
+#[derive(Debug, Clone)]
+enum LimiterSource {
+    /// A limiter owned by the middleware.
+    Owned(Arc<Limiter>),
+
+    /// A limiter registered under the given name in the [`Limiters`] of the middleware, or in
+    /// app data.
+    Named(Cow<'static, str>),
+}
+
+/// A limit applied to a request, whose quota may be yet to be resolved.
+#[derive(Debug)]
+struct Unresolved {
+    limiter: Arc<Limiter>,
+    /// The key of the request, as returned by the key resolver.
+    key: String,
+    /// The key the request is counted under.
+    counter: String,
+    /// The quota returned by the key resolver, if any.
+    quota: Option<Quota>,
+}
+
+impl Unresolved {
+    fn new(limiter: Arc<Limiter>, resolved: ResolvedKey, counter: String) -> Self {
+        Self {
+            limiter,
+            key: resolved.key,
+            counter,
+            quota: resolved.quota,
+        }
+    }
+
+    async fn resolve(self) -> Result<Target, LimitationError> {
+        let quota = match self.quota {
+            Some(quota) => quota,
+            None => self.limiter.quota(&self.key).await?,
+        };
+        Ok(Target {
+            limiter: self.limiter,
+            key: self.counter,
+            quota,
+        })
+    }
+}
+
+impl LimiterSource {
+    /// Returns the limit applied by this source to the given request, if any, or an error if its
+    /// key is denylisted or cannot be resolved.
+    ///
+    /// Keys of named policies are prefixed with their name, so that policies sharing a store do
+    /// not share counters.
+    async fn resolve(
+        &self,
+        config: &RateLimiter,
+        req: &ServiceRequest,
+        cost: usize,
+    ) -> Result<Option<Unresolved>, LimitationError> {
+        match self {
+            Self::Owned(limiter) => Ok(screen(limiter, req, cost).await?.map(|resolved| {
+                let counter = resolved.key.clone();
+                Unresolved::new(Arc::clone(limiter), resolved, counter)
+            })),
+            Self::Named(name) => {
+                let limiter = named(config, req, name)?;
+                let resolved = screen(&limiter, req, cost).await?;
+                Ok(resolved.map(|resolved| {
+                    let counter = format!("{name}:{}", resolved.key);
+                    Unresolved::new(limiter, resolved, counter)
+                }))
+            }
+        }
+    }
+}
+
+/// Returns the limiter registered under `name`, in the limiters of the middleware if set,
+/// otherwise in app data.
+fn named(
+    config: &RateLimiter,
+    req: &ServiceRequest,
+    name: &str,
+) -> Result<Arc<Limiter>, LimitationError> {
+    let limiters = match config.registry {
+        Some(ref limiters) => limiters,
+        None => req.app_data::<web::Data<Limiters>>().ok_or_else(|| {
+            log::error!("web::Data<Limiters> should be set in app data for RateLimiter policies");
+            LimitationError::Other("No limiters are set in app data".to_owned())
+        })?,
+    };
+
+    limiters.get_shared(name).ok_or_else(|| {
+        log::error!("Rate limit policy `{name}` is not registered");
+        LimitationError::Other(format!("Rate limit policy `{name}` is not registered"))
+    })
+}
+
+/// Returns the key of the request for `limiter`, unless it has none or is exempt from the limit.
+async fn screen(
+    limiter: &Limiter,
+    req: &ServiceRequest,
+    cost: usize,
+) -> Result<Option<ResolvedKey>, LimitationError> {
+    let Some(resolved) = limiter.key_resolver.resolve(req).await? else {
+        return Ok(None);
+    };
+
+    match limiter.screen(&resolved.key) {
+        Ok(counted) => Ok(counted.then_some(resolved)),
+        Err(err) => {
+            observe::record(limiter, &resolved.key, cost, Err(&err), None);
+            Err(err)
+        }
+    }
+}
+
+/// Returns the limits applied by the middleware to the given request.
+async fn limits(
+    config: &RateLimiter,
+    req: &ServiceRequest,
+    cost: usize,
+) -> Result<Vec<Unresolved>, LimitationError> {
+    if config.limiters.is_empty() {
+        // A misconfiguration of the Actix App will result in a **runtime** failure, so the
+        // expect method description is important context for the developer.
+        let limiter = req
+            .app_data::<web::Data<Limiter>>()
+            .expect("web::Data<Limiter> should be set in app data for RateLimiter middleware")
+            .clone()
+            .into_inner();
+        let source = LimiterSource::Owned(limiter);
+        return Ok(source
+            .resolve(config, req, cost)
+            .await?
+            .into_iter()
+            .collect());
+    }
+
+    let mut limits = Vec::new();
+    for source in &config.limiters {
+        limits.extend(source.resolve(config, req, cost).await?);
+    }
+    Ok(limits)
+}
+
+/// How many rate limit units a request consumes.
+#[derive(Clone)]
+enum Cost {
+    Fixed(usize),
+    Computed(Arc<dyn Fn(&ServiceRequest) -> usize + Send + Sync>),
+}
+
+impl Cost {
+    fn of(&self, req: &ServiceRequest) -> usize {
+        match self {
+            Self::Fixed(cost) => *cost,
+            Self::Computed(cost_fn) => cost_fn(req),
+        }
+    }
+}
+
+impl Default for Cost {
+    fn default() -> Self {
+        Self::Fixed(1)
+    }
+}
+
+impl fmt::Debug for Cost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fixed(cost) => f.debug_tuple("Fixed").field(cost).finish(),
+            Self::Computed(_) => f.write_str("Computed"),
+        }
+    }
+}
+
+/// Decides, from the status of a response, whether the units consumed by a request are refunded.
+#[derive(Clone)]
+struct RefundOn(Arc<dyn Fn(StatusCode) -> bool + Send + Sync>);
+
+impl fmt::Debug for RefundOn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RefundOn")
+    }
+}
+
+/// Error handler function type with auto traits.
+type ErrorHandlerFn = dyn Fn(&ServiceRequest, &LimitationError) -> HttpResponse + Send + Sync;
+
+/// Builds the response sent when a request is rejected or cannot be counted.
+#[derive(Clone)]
+struct ErrorHandler(Arc<ErrorHandlerFn>);
+
+impl fmt::Debug for ErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ErrorHandler")
+    }
+}
 
 /// Rate limit middleware.
-#[derive(Debug, Default)]
+///
+/// By default, the [`Limiter`] set in app data (as `web::Data<Limiter>`) is enforced. Routes can
+/// instead enforce one or more specific limiters, e.g. a burst limit and a daily limit; see
+/// [`limiter`](Self::limiter) and [`policy`](Self::policy). A request is rejected as soon as one of
+/// its limits is exceeded, in which case it is not counted against its other limits.
+///
+/// The rate limit status is reported to clients using the IETF draft `RateLimit-*` headers by
+/// default; see [`header_style`](Self::header_style). When several limits apply, the headers
+/// describe the most restrictive one. Limits of shadow limiters (see
+/// [`Builder::shadow`](crate::Builder::shadow)) are never enforced nor reported in headers.
+///
+/// Each request consumes one unit of quota by default; expensive routes can consume more, see
+/// [`cost`](Self::cost) and [`cost_by`](Self::cost_by). Units can also be given back depending on
+/// the response, see [`refund_on`](Self::refund_on).
+///
+/// Requests over their limit are rejected with an empty `429 Too Many Requests` response, those
+/// of denylisted or banned keys (see [`Builder::ban_after`](crate::Builder::ban_after)) with an
+/// empty `403 Forbidden` response, and requests that cannot be counted, e.g. because Redis is
+/// unreachable, with an empty `500 Internal Server Error` response. See
+/// [`error_handler`](Self::error_handler) and [`fail_open`](Self::fail_open) to change this.
+#[derive(Debug, Clone, Default)]
 #[non_exhaustive]
-pub struct RateLimiter;
+pub struct RateLimiter {
+    header_style: HeaderStyle,
+    limiters: Vec<LimiterSource>,
+    registry: Option<web::Data<Limiters>>,
+    cost: Cost,
+    refund_on: Option<RefundOn>,
+    error_handler: Option<ErrorHandler>,
+    fail_open: bool,
+}
+
+impl RateLimiter {
+    /// Enforces the given limiter, in addition to those already added.
+    ///
+    /// Its keys are used as is: if it shares a store with other limiters, make sure their keys do
+    /// not collide.
+    ///
+    /// Once a limiter or a policy is added, the limiter set in app data is no longer used.
+    pub fn limiter(mut self, limiter: Limiter) -> Self {
+        self.limiters.push(LimiterSource::Owned(Arc::new(limiter)));
+        self
+    }
+
+    /// Enforces the limiter registered under `name`, in addition to those already added.
+    ///
+    /// The limiter is looked up in the [`Limiters`] passed to [`limiters`](Self::limiters), or
+    /// else in the `web::Data<Limiters>` set in app data. Its counters are kept under the keys of
+    /// the limiter, prefixed with `name` and a colon.
+    ///
+    /// Once a limiter or a policy is added, the limiter set in app data is no longer used.
+    pub fn policy(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.limiters.push(LimiterSource::Named(name.into()));
+        self
+    }
+
+    /// Looks up [policies](Self::policy) in the given limiters instead of app data.
+    ///
+    /// Policies are then checked when the middleware is constructed, which fails if no limiter
+    /// is registered under one of their names. Policies looked up in app data can only be checked
+    /// once requests are handled: requests fail like requests that cannot be counted if no
+    /// [`Limiters`] are set in app data or if a policy is not registered.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use actix_limitation::{storage::MemoryStore, Limiter, Limiters, RateLimiter};
+    /// use actix_web::{web, App, HttpResponse};
+    ///
+    /// let limiters = web::Data::new(Limiters::new().insert(
+    ///     "burst",
+    ///     Limiter::builder_with_store(MemoryStore::new())
+    ///         .limit(10)
+    ///         .period(Duration::from_secs(1))
+    ///         .build()
+    ///         .unwrap(),
+    /// ));
+    ///
+    /// let app = App::new().service(
+    ///     web::resource("/search")
+    ///         .wrap(RateLimiter::default().limiters(limiters).policy("burst"))
+    ///         .to(HttpResponse::Ok),
+    /// );
+    /// ```
+    pub fn limiters(mut self, limiters: web::Data<Limiters>) -> Self {
+        self.registry = Some(limiters);
+        self
+    }
+
+    /// Sets which headers are used to report the rate limit status to clients.
+    pub fn header_style(mut self, header_style: HeaderStyle) -> Self {
+        self.header_style = header_style;
+        self
+    }
+
+    /// Sets how many rate limit units each request consumes.
+    ///
+    /// Defaults to 1.
+    pub fn cost(mut self, cost: usize) -> Self {
+        self.cost = Cost::Fixed(cost);
+        self
+    }
+
+    /// Sets a function computing how many rate limit units a request consumes, e.g. from its
+    /// query parameters.
+    pub fn cost_by<F>(mut self, cost_fn: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> usize + Send + Sync + 'static,
+    {
+        self.cost = Cost::Computed(Arc::new(cost_fn));
+        self
+    }
+
+    /// Gives back the units consumed by a request when the status of its response matches the
+    /// given predicate.
+    ///
+    /// Refunds are applied before the response is returned, to all the limits of the request.
+    /// Responses rejected by the middleware itself (`429 Too Many Requests`) are never refunded.
+    ///
+    /// # Examples
+    /// ```
+    /// use actix_limitation::RateLimiter;
+    /// use actix_web::http::StatusCode;
+    ///
+    /// // do not count cache hits and server errors
+    /// let rate_limiter = RateLimiter::default().refund_on(|status: StatusCode| {
+    ///     status == StatusCode::NOT_MODIFIED || status.is_server_error()
+    /// });
+    /// ```
+    pub fn refund_on<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(StatusCode) -> bool + Send + Sync + 'static,
+    {
+        self.refund_on = Some(RefundOn(Arc::new(predicate)));
+        self
+    }
+
+    /// Sets a function building the response sent when a request is over its limit
+    /// ([`Error::LimitExceeded`](LimitationError::LimitExceeded)), banned
+    /// ([`Error::Banned`](LimitationError::Banned)) or cannot be counted.
+    ///
+    /// The rate limit headers and `Retry-After` are added to the responses of rejected requests,
+    /// and `Retry-After` to those of temporarily banned requests.
+    ///
+    /// # Examples
+    /// ```
+    /// use actix_limitation::{Error, RateLimiter};
+    /// use actix_web::{dev::ServiceRequest, http::header::ContentType, HttpResponse};
+    ///
+    /// let rate_limiter =
+    ///     RateLimiter::default().error_handler(|_req: &ServiceRequest, err: &Error| match err {
+    ///         Error::LimitExceeded(status) => HttpResponse::TooManyRequests()
+    ///             .content_type("application/problem+json")
+    ///             .body(format!(
+    ///                 r#"{{"title":"Too Many Requests","status":429,"limit":{}}}"#,
+    ///                 status.limit(),
+    ///             )),
+    ///         _ => HttpResponse::ServiceUnavailable()
+    ///             .content_type(ContentType::plaintext())
+    ///             .body("rate limiter is unavailable"),
+    ///     });
+    /// ```
+    pub fn error_handler<F>(mut self, error_handler: F) -> Self
+    where
+        F: Fn(&ServiceRequest, &LimitationError) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.error_handler = Some(ErrorHandler(Arc::new(error_handler)));
+        self
+    }
+
+    /// Lets requests through, without rate limiting them, when they cannot be counted, e.g.
+    /// because Redis is unreachable.
+    ///
+    /// Failures are still logged. By default, such requests are rejected (fail closed).
+    pub fn fail_open(mut self, fail_open: bool) -> Self {
+        self.fail_open = fail_open;
+        self
+    }
+}
 
 impl<S, B> Transform<S, ServiceRequest> for RateLimiter
 where
@@ -28,8 +456,20 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
+        if let Some(ref limiters) = self.registry {
+            for source in &self.limiters {
+                if let LimiterSource::Named(name) = source {
+                    if limiters.get(name).is_none() {
+                        log::error!("Rate limit policy `{name}` is not registered");
+                        return err(());
+                    }
+                }
+            }
+        }
+
         ok(RateLimiterMiddleware {
             service: Rc::new(service),
+            config: Rc::new(self.clone()),
         })
     }
 }
@@ -38,6 +478,7 @@ where
 #[derive(Debug)]
 pub struct RateLimiterMiddleware<S> {
     service: Rc<S>,
+    config: Rc<RateLimiter>,
 }
 
 impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
@@ -53,63 +494,171 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        // A misconfiguration of the Actix App will result in a **runtime** failure, so the expect
-        // method description is important context for the developer.
-        let limiter = req
-            .app_data::<web::Data<Limiter>>()
-            .expect("web::Data<Limiter> should be set in app data for RateLimiter middleware")
-            .clone();
-
-        let key = (limiter.get_key_fn)(&req);
         let service = Rc::clone(&self.service);
+        let config = Rc::clone(&self.config);
+        let header_style = config.header_style;
 
-        let key = match key {
-            Some(key) => key,
-            None => {
-                return Box::pin(async move {
-                    service
-                        .call(req)
-                        .await
-                        .map(ServiceResponse::map_into_left_body)
-                });
+        Box::pin(async move {
+            let cost = config.cost.of(&req);
+            let limits = limits(&config, &req, cost).await;
+
+            if matches!(limits, Ok(ref limits) if limits.is_empty()) {
+                return service
+                    .call(req)
+                    .await
+                    .map(ServiceResponse::map_into_left_body);
             }
-        };
 
-        Box::pin(async move {
-            let status = limiter.count(key.to_string()).await;
+            let (targets, status) = match resolve(limits).await {
+                Ok(targets) => {
+                    let status = Limiter::count_all(&targets, cost)
+                        .await
+                        .and_then(|outcomes| most_restrictive(&targets, outcomes));
+                    (targets, status)
+                }
+                Err(err) => (Vec::new(), Err(err)),
+            };
 
-            if let Err(err) = status {
-                match err {
-                    LimitationError::LimitExceeded(_) => {
-                        log::warn!("Rate limit exceed error for {}", key);
+            match status {
+                Ok(status) => {
+                    let res = service.call(req).await;
 
-                        Ok(req.into_response(
-                            HttpResponse::new(StatusCode::TOO_MANY_REQUESTS).map_into_right_body(),
-                        ))
+                    if let Some(RefundOn(ref refund_on)) = config.refund_on {
+                        let status_code = match res {
+                            Ok(ref res) => res.status(),
+                            Err(ref err) => err.as_response_error().status_code(),
+                        };
+                        if refund_on(status_code) {
+                            refund(&targets, cost).await;
+                        }
+                    }
+
+                    let mut res = res?;
+                    if let Some(ref status) = status {
+                        header_style.insert(res.headers_mut(), status);
+                    }
+                    Ok(res.map_into_left_body())
+                }
+                Err(err) => {
+                    match err {
+                        LimitationError::LimitExceeded(_) => {}
+                        LimitationError::Banned(_) => {
+                            log::warn!("Rejected request of a banned key");
+                        }
+                        LimitationError::Client(ref err) => {
+                            log::error!("Client request failed, redis error: {err}");
+                        }
+                        ref err => log::error!("Count failed: {}", err),
                     }
-                    LimitationError::Client(err) => {
-                        log::error!("Client request failed, redis error: {err}");
 
-                        Ok(req.into_response(
-                            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                                .map_into_right_body(),
-                        ))
+                    if config.fail_open
+                        && !matches!(
+                            err,
+                            LimitationError::LimitExceeded(_) | LimitationError::Banned(_)
+                        )
+                    {
+                        return service
+                            .call(req)
+                            .await
+                            .map(ServiceResponse::map_into_left_body);
                     }
-                    _ => {
-                        log::error!("Count failed: {}", err);
 
-                        Ok(req.into_response(
-                            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                                .map_into_right_body(),
-                        ))
+                    let mut res = match config.error_handler {
+                        Some(ErrorHandler(ref error_handler)) => error_handler(&req, &err),
+                        None => default_error_response(&err),
+                    };
+
+                    match err {
+                        LimitationError::LimitExceeded(ref status) => {
+                            header_style.insert(res.headers_mut(), status);
+                            res.headers_mut().insert(
+                                header::RETRY_AFTER,
+                                HeaderValue::from(status.reset_after_secs()),
+                            );
+                        }
+                        LimitationError::Banned(Some(ttl)) => {
+                            res.headers_mut().insert(
+                                header::RETRY_AFTER,
+                                HeaderValue::from(ttl.as_secs_f64().ceil() as u64),
+                            );
+                        }
+                        _ => {}
                     }
+
+                    Ok(req.into_response(res.map_into_right_body()))
                 }
-            } else {
-                service
-                    .call(req)
-                    .await
-                    .map(ServiceResponse::map_into_left_body)
             }
         })
     }
 }
+
+fn default_error_response(err: &LimitationError) -> HttpResponse {
+    match err {
+        LimitationError::LimitExceeded(_) => HttpResponse::new(StatusCode::TOO_MANY_REQUESTS),
+        LimitationError::Banned(_) => HttpResponse::new(StatusCode::FORBIDDEN),
+        _ => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Resolves the quotas of the limits applied to a request.
+async fn resolve(
+    limits: Result<Vec<Unresolved>, LimitationError>,
+) -> Result<Vec<Target>, LimitationError> {
+    let mut targets = Vec::new();
+    for limit in limits? {
+        targets.push(limit.resolve().await?);
+    }
+    Ok(targets)
+}
+
+/// Gives back `cost` units to each limit, logging failures.
+async fn refund(targets: &[Target], cost: usize) {
+    for target in targets {
+        if let Err(err) = target.refund(cost).await {
+            log::error!("Refund failed: {}", err);
+        }
+    }
+}
+
+/// Merges the outcomes of all the limits applied to a request.
+///
+/// Returns the status of the exceeded limit that resets last, if any, otherwise the status of the
+/// enforced limit with the fewest remaining requests, if any.
+fn most_restrictive(
+    targets: &[Target],
+    outcomes: Vec<Result<Status, LimitationError>>,
+) -> Result<Option<Status>, LimitationError> {
+    let mut exceeded: Option<Status> = None;
+    let mut tightest: Option<Status> = None;
+
+    for (Target { limiter, key, .. }, outcome) in targets.iter().zip(outcomes) {
+        match outcome {
+            // shadow limits are not reported to clients
+            Ok(_) if limiter.shadow => {}
+            Ok(status) => {
+                if tightest
+                    .as_ref()
+                    .is_none_or(|tightest| status.remaining() < tightest.remaining())
+                {
+                    tightest = Some(status);
+                }
+            }
+            Err(LimitationError::LimitExceeded(status)) => {
+                log::warn!("Rate limit exceeded for key {}", observe::key_hash(key));
+
+                if exceeded
+                    .as_ref()
+                    .is_none_or(|exceeded| status.reset_epoch_utc() > exceeded.reset_epoch_utc())
+                {
+                    exceeded = Some(status);
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    match exceeded {
+        Some(status) => Err(LimitationError::LimitExceeded(status)),
+        None => Ok(tightest),
+    }
+}
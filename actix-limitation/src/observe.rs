@@ -0,0 +1,240 @@
+//! Tracing and metrics of rate limit decisions.
+
+use std::{
+    future::Future,
+    hash::{DefaultHasher, Hash as _, Hasher as _},
+    time::Duration,
+};
+
+use crate::{Error, Limiter, Status};
+
+/// Runs `fut`, which decides on a request, in a `rate_limit` span.
+pub(crate) async fn in_span<F: Future>(cost: usize, fut: F) -> F::Output {
+    #[cfg(feature = "tracing")]
+    let fut = tracing::Instrument::instrument(fut, tracing::debug_span!("rate_limit", cost));
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = cost;
+
+    fut.await
+}
+
+/// Reports the outcome of a request of `key` counted against `limiter`.
+///
+/// `latency` is the time spent deciding, if the store was involved.
+pub(crate) fn record(
+    limiter: &Limiter,
+    key: &str,
+    cost: usize,
+    outcome: Result<&Status, &Error>,
+    latency: Option<Duration>,
+) {
+    #[cfg(feature = "tracing")]
+    trace(limiter, key, cost, outcome, latency);
+
+    #[cfg(feature = "metrics")]
+    measure(limiter, outcome, latency);
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = (key, cost);
+
+    #[cfg(not(any(feature = "tracing", feature = "metrics")))]
+    let _ = (limiter, outcome, latency);
+}
+
+/// Returns a hash of `key`, to identify it in logs and traces without revealing it.
+pub(crate) fn key_hash(key: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Returns the label of the policy enforced by `limiter`.
+#[cfg(any(feature = "tracing", feature = "metrics"))]
+fn policy(limiter: &Limiter) -> &str {
+    limiter.name.as_deref().unwrap_or("default")
+}
+
+#[cfg(feature = "tracing")]
+fn trace(
+    limiter: &Limiter,
+    key: &str,
+    cost: usize,
+    outcome: Result<&Status, &Error>,
+    latency: Option<Duration>,
+) {
+    use crate::Algorithm;
+
+    let key_hash = key_hash(key);
+    let policy = policy(limiter);
+    let shadow = limiter.shadow;
+    let algorithm = match limiter.algorithm {
+        Algorithm::FixedWindow => "fixed_window",
+        Algorithm::SlidingWindow => "sliding_window",
+        Algorithm::TokenBucket { .. } => "token_bucket",
+    };
+    let latency_us = latency.map(|latency| latency.as_micros() as u64);
+
+    match outcome {
+        Ok(status) => tracing::debug!(
+            policy,
+            shadow,
+            key_hash,
+            cost,
+            remaining = status.remaining(),
+            algorithm,
+            latency_us,
+            "request allowed",
+        ),
+        Err(Error::LimitExceeded(status)) => tracing::info!(
+            policy,
+            shadow,
+            key_hash,
+            cost,
+            remaining = status.remaining(),
+            algorithm,
+            latency_us,
+            "request limited",
+        ),
+        Err(Error::Banned(ttl)) => tracing::info!(
+            policy,
+            shadow,
+            key_hash,
+            cost,
+            ban_ttl_secs = ttl.map(|ttl| ttl.as_secs()),
+            algorithm,
+            latency_us,
+            "request of banned key rejected",
+        ),
+        Err(err) => tracing::warn!(
+            policy,
+            shadow,
+            key_hash,
+            cost,
+            algorithm,
+            latency_us,
+            error = %err,
+            "request could not be counted",
+        ),
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn measure(limiter: &Limiter, outcome: Result<&Status, &Error>, latency: Option<Duration>) {
+    let policy = policy(limiter).to_owned();
+    let outcome = match outcome {
+        Ok(_) => "allowed",
+        Err(Error::LimitExceeded(_) | Error::Banned(_)) => "limited",
+        Err(_) => "errored",
+    };
+
+    metrics::counter!(
+        "actix_limitation_requests_total",
+        "policy" => policy.clone(),
+        "outcome" => outcome,
+    )
+    .increment(1);
+
+    if let Some(latency) = latency {
+        metrics::histogram!("actix_limitation_decision_duration_seconds", "policy" => policy)
+            .record(latency);
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use metrics::{
+        Counter, CounterFn, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+    };
+
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    /// Records the increments of all counters, as `name{labels}`.
+    #[derive(Debug, Default)]
+    struct TestRecorder(Arc<Mutex<Vec<String>>>);
+
+    struct TestCounter {
+        key: String,
+        increments: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl CounterFn for TestCounter {
+        fn increment(&self, _value: u64) {
+            self.increments.lock().unwrap().push(self.key.clone());
+        }
+
+        fn absolute(&self, _value: u64) {}
+    }
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            let labels = key
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect::<Vec<_>>()
+                .join(",");
+
+            Counter::from_arc(Arc::new(TestCounter {
+                key: format!("{}{{{labels}}}", key.name()),
+                increments: Arc::clone(&self.0),
+            }))
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let recorder = TestRecorder::default();
+        let named = Limiter::builder_with_store(MemoryStore::new())
+            .name("burst")
+            .build()
+            .unwrap();
+        let unnamed = Limiter::builder_with_store(MemoryStore::new())
+            .build()
+            .unwrap();
+        let status = Status::new(1, 1, 0);
+
+        metrics::with_local_recorder(&recorder, || {
+            record(&named, "key", 1, Ok(&status), None);
+            record(
+                &named,
+                "key",
+                1,
+                Err(&Error::LimitExceeded(status.clone())),
+                None,
+            );
+            record(&named, "key", 1, Err(&Error::Banned(None)), None);
+            record(
+                &unnamed,
+                "key",
+                1,
+                Err(&Error::Other("down".to_owned())),
+                None,
+            );
+        });
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "actix_limitation_requests_total{policy=burst,outcome=allowed}",
+                "actix_limitation_requests_total{policy=burst,outcome=limited}",
+                "actix_limitation_requests_total{policy=burst,outcome=limited}",
+                "actix_limitation_requests_total{policy=default,outcome=errored}",
+            ]
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use futures_core::future::BoxFuture;
+
+use crate::Error;
+
+/// Number of lookups between two sweeps of expired cached quotas.
+const EVICTION_INTERVAL: usize = 1024;
+
+/// A number of units that can be consumed within a period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub(crate) limit: usize,
+    pub(crate) period: Duration,
+}
+
+impl Quota {
+    /// Constructs a quota of `limit` units per `period`.
+    #[must_use]
+    pub fn new(limit: usize, period: Duration) -> Self {
+        Self { limit, period }
+    }
+
+    /// Returns the maximum number of units that can be consumed within a period.
+    #[must_use]
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Returns the period over which the limit applies.
+    #[must_use]
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+/// Resolves the quota of a key at request time, e.g. from the plan of a tenant.
+///
+/// Implemented for async functions taking the key and returning the quota, or `None` to use the
+/// default quota of the limiter. See [`Builder::quota_by`](crate::Builder::quota_by) for
+/// synchronous resolvers.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+///
+/// use actix_limitation::{storage::MemoryStore, Error, Limiter, Quota};
+///
+/// async fn plan_quota(api_key: String) -> Result<Option<Quota>, Error> {
+///     // e.g. look up the plan of the tenant in a database
+///     Ok(api_key
+///         .starts_with("enterprise-")
+///         .then(|| Quota::new(10_000, Duration::from_secs(60))))
+/// }
+///
+/// let limiter = Limiter::builder_with_store(MemoryStore::new())
+///     .limit(100)
+///     .period(Duration::from_secs(60))
+///     .quota_resolver(plan_quota)
+///     .quota_cache_ttl(Duration::from_secs(300))
+///     .build()
+///     .unwrap();
+/// ```
+pub trait QuotaResolver: Send + Sync + 'static {
+    /// Returns the quota of `key`, or `None` to use the default quota of the limiter.
+    fn resolve(&self, key: String) -> BoxFuture<'_, Result<Option<Quota>, Error>>;
+}
+
+impl<F, Fut> QuotaResolver for F
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Option<Quota>, Error>> + Send + 'static,
+{
+    fn resolve(&self, key: String) -> BoxFuture<'_, Result<Option<Quota>, Error>> {
+        Box::pin(self(key))
+    }
+}
+
+impl fmt::Debug for dyn QuotaResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("QuotaResolver")
+    }
+}
+
+/// A quota resolver, along with the quotas it recently resolved.
+#[derive(Debug)]
+pub(crate) struct QuotaSource {
+    resolver: Arc<dyn QuotaResolver>,
+    /// How long resolved quotas are cached, if at all.
+    cache_ttl: Option<Duration>,
+    cache: DashMap<String, CachedQuota>,
+    lookups: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachedQuota {
+    quota: Option<Quota>,
+    expires_at: Instant,
+}
+
+impl QuotaSource {
+    pub(crate) fn new(resolver: Arc<dyn QuotaResolver>, cache_ttl: Option<Duration>) -> Self {
+        Self {
+            resolver,
+            cache_ttl,
+            cache: DashMap::new(),
+            lookups: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the quota of `key`, resolving it unless it is cached.
+    pub(crate) async fn resolve(&self, key: &str) -> Result<Option<Quota>, Error> {
+        let Some(cache_ttl) = self.cache_ttl else {
+            return self.resolver.resolve(key.to_owned()).await;
+        };
+
+        let now = Instant::now();
+        if let Some(cached) = self.cache.get(key).filter(|cached| cached.expires_at > now) {
+            return Ok(cached.quota);
+        }
+
+        if self
+            .lookups
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(EVICTION_INTERVAL)
+        {
+            self.cache.retain(|_, cached| cached.expires_at > now);
+        }
+
+        let quota = self.resolver.resolve(key.to_owned()).await?;
+        self.cache.insert(
+            key.to_owned(),
+            CachedQuota {
+                quota,
+                expires_at: Instant::now() + cache_ttl,
+            },
+        );
+        Ok(quota)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let resolver = {
+            let calls = Arc::clone(&calls);
+            move |key: String| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let quota = (key == "premium").then(|| Quota::new(10, Duration::from_secs(1)));
+                async move { Ok(quota) }
+            }
+        };
+
+        let source = QuotaSource::new(Arc::new(resolver), Some(Duration::from_millis(20)));
+        for _ in 0..3 {
+            assert_eq!(
+                source.resolve("premium").await.unwrap().unwrap().limit(),
+                10
+            );
+            assert_eq!(source.resolve("free").await.unwrap(), None);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        std::thread::sleep(Duration::from_millis(30));
+        source.resolve("premium").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -2,7 +2,7 @@ use std::{ops::Add, time::Duration};
 
 use chrono::SubsecRound as _;
 
-use crate::Error as LimitationError;
+use crate::{storage::Decision, Error as LimitationError};
 
 /// A report for a given key containing the limit status.
 #[derive(Debug, Clone)]
@@ -10,10 +10,13 @@ pub struct Status {
     pub(crate) limit: usize,
     pub(crate) remaining: usize,
     pub(crate) reset_epoch_utc: usize,
+    /// Time until the next period begins, as of when the status was decided.
+    pub(crate) reset_after: Duration,
 }
 
 impl Status {
     /// Constructs status limit status from parts.
+    #[cfg(test)]
     #[must_use]
     pub(crate) fn new(count: usize, limit: usize, reset_epoch_utc: usize) -> Self {
         let remaining = limit.saturating_sub(count);
@@ -22,9 +25,23 @@ impl Status {
             limit,
             remaining,
             reset_epoch_utc,
+            reset_after: Duration::ZERO,
         }
     }
 
+    /// Constructs status from the decision of a store.
+    pub(crate) fn from_decision(
+        limit: usize,
+        decision: &Decision,
+    ) -> Result<Self, LimitationError> {
+        Ok(Status {
+            limit,
+            remaining: decision.remaining(),
+            reset_epoch_utc: Self::epoch_utc_plus(decision.reset_after())?,
+            reset_after: decision.reset_after(),
+        })
+    }
+
     /// Returns the maximum number of requests allowed in the current period.
     #[must_use]
     pub fn limit(&self) -> usize {
@@ -43,6 +60,24 @@ impl Status {
         self.reset_epoch_utc
     }
 
+    /// Returns the number of seconds until [`reset_epoch_utc`](Self::reset_epoch_utc).
+    pub(crate) fn reset_after_secs(&self) -> usize {
+        let now = usize::try_from(chrono::Utc::now().timestamp()).unwrap_or(0);
+        self.reset_after_secs_at(now)
+    }
+
+    /// Returns the time until the next period begins, as of when the status was decided.
+    ///
+    /// Unlike [`reset_epoch_utc`](Self::reset_epoch_utc), it is not rounded to the second.
+    pub(crate) fn reset_after(&self) -> Duration {
+        self.reset_after
+    }
+
+    /// Returns the number of seconds from the UNIX timestamp `now` until the next period begins.
+    fn reset_after_secs_at(&self, now: usize) -> usize {
+        self.reset_epoch_utc.saturating_sub(now)
+    }
+
     pub(crate) fn epoch_utc_plus(duration: Duration) -> Result<usize, LimitationError> {
         match chrono::Duration::from_std(duration) {
             Ok(value) => Ok(chrono::Utc::now()
@@ -69,6 +104,7 @@ mod tests {
             limit: 100,
             remaining: 0,
             reset_epoch_utc: 1000,
+            reset_after: Duration::ZERO,
         };
 
         assert_eq!(status.limit(), 100);
@@ -95,6 +131,17 @@ mod tests {
         assert_eq!(status.reset_epoch_utc(), 2000);
     }
 
+    #[test]
+    fn test_reset_after_secs() {
+        let status = Status::new(0, 100, 1010);
+        assert_eq!(status.reset_after_secs_at(1000), 10);
+        assert_eq!(status.reset_after_secs_at(1010), 0);
+        assert_eq!(status.reset_after_secs_at(2000), 0);
+
+        let status = Status::new(0, 100, 1000);
+        assert_eq!(status.reset_after_secs(), 0);
+    }
+
     #[test]
     fn test_epoch_utc_plus_zero() {
         let duration = Duration::from_secs(0);
@@ -0,0 +1,412 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use futures_core::future::BoxFuture;
+
+use super::{Decision, Hit, RateLimitStore};
+use crate::Error;
+
+/// Number of hits between two sweeps of expired keys.
+const EVICTION_INTERVAL: usize = 1024;
+
+/// Default time between two syncs of a key (in milliseconds).
+const DEFAULT_SYNC_INTERVAL_MILLIS: u64 = 100;
+
+/// Default number of local hits between two syncs of a key.
+const DEFAULT_SYNC_HITS: usize = 100;
+
+/// Approximate rate limit counters, pre-aggregated locally and synced with another store.
+///
+/// Hits are counted locally, and only sent to the inner store, typically a
+/// [`RedisStore`](super::RedisStore), in batches: a key is synced when it is hit after its
+/// [sync interval](Self::sync_interval) has elapsed, or after [`sync_hits`](Self::sync_hits)
+/// local hits. Between two syncs, requests are allowed locally as long as they fit in a budget
+/// derived from the remaining count last reported by the inner store, and requests of keys whose
+/// limit was exceeded are rejected locally until they can be retried. Most requests are therefore
+/// decided without a round-trip.
+///
+/// # Accuracy
+/// The trade-off between accuracy and throughput is set by three parameters:
+///
+/// - the longer the [sync interval](Self::sync_interval) and the more [hits](Self::sync_hits)
+///   between syncs, the fewer calls to the inner store, but the staler the remaining counts used
+///   by each instance;
+/// - each instance consumes at most the last known remaining count divided by the number of
+///   [instances](Self::instances) sharing the limit before syncing. If this number is too low,
+///   instances may collectively allow more requests than the limit, by up to the sum of their
+///   budgets. If it is too high, syncs are more frequent.
+///
+/// Hits that were allowed locally are always sent to the inner store with the next sync of their
+/// key, even if the inner store then rejects them. They are sent as a separate hit, which
+/// algorithms other than [fixed window](crate::Algorithm::FixedWindow) count only if it fits in the
+/// quota. Local hits of a key that is not hit again are sent late, possibly in a later window, or
+/// never if the process exits.
+///
+/// Rate limit statuses reported to clients are based on the last sync, and thus approximate too.
+/// Bans are not cached: they are always checked against the inner store.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+///
+/// use actix_limitation::{
+///     storage::{ApproximateStore, RedisStore},
+///     Limiter,
+/// };
+///
+/// let store = ApproximateStore::new(RedisStore::open("redis://127.0.0.1").unwrap())
+///     .sync_interval(Duration::from_millis(250))
+///     .sync_hits(1000)
+///     .instances(4);
+///
+/// let limiter = Limiter::builder_with_store(store)
+///     .limit(10_000)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ApproximateStore {
+    inner: Arc<dyn RateLimitStore>,
+    sync_interval: Duration,
+    sync_hits: usize,
+    instances: usize,
+    keys: DashMap<String, LocalState>,
+    hits: AtomicUsize,
+}
+
+/// What an instance knows about a key since its last sync.
+#[derive(Debug)]
+struct LocalState {
+    /// When the key was last synced.
+    synced_at: Instant,
+    /// When the quota of the key is fully restored or, if the key was rejected, when a new request
+    /// can be allowed.
+    reset_at: Instant,
+    /// Whether the last synced request of the key was rejected.
+    rejected: bool,
+    /// Remaining units reported by the last sync.
+    remaining: usize,
+    /// Units that can be consumed locally between two syncs.
+    budget: usize,
+    /// Units consumed locally since the last sync.
+    pending: usize,
+    /// Number of requests counted locally since the last sync.
+    pending_hits: usize,
+    /// Whether a sync of the key is in flight.
+    syncing: bool,
+}
+
+impl LocalState {
+    fn is_fresh(&self, now: Instant, sync_interval: Duration) -> bool {
+        now.saturating_duration_since(self.synced_at) < sync_interval
+    }
+}
+
+/// How a hit is decided.
+enum Local {
+    Decided(Decision),
+    /// The key must be synced, along with the given units consumed locally.
+    Sync(usize),
+}
+
+impl ApproximateStore {
+    /// Constructs a store pre-aggregating hits before sending them to `inner`.
+    pub fn new(inner: impl RateLimitStore) -> Self {
+        Self::with_shared_store(Arc::new(inner))
+    }
+
+    /// Constructs a store pre-aggregating hits before sending them to an `inner` store shared with
+    /// other limiters.
+    pub fn with_shared_store(inner: Arc<dyn RateLimitStore>) -> Self {
+        Self {
+            inner,
+            sync_interval: Duration::from_millis(DEFAULT_SYNC_INTERVAL_MILLIS),
+            sync_hits: DEFAULT_SYNC_HITS,
+            instances: 1,
+            keys: DashMap::new(),
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets how long a key can be counted locally before it is synced with the inner store.
+    ///
+    /// Defaults to 100 milliseconds.
+    pub fn sync_interval(mut self, sync_interval: Duration) -> Self {
+        self.sync_interval = sync_interval;
+        self
+    }
+
+    /// Sets how many requests of a key can be counted locally before it is synced with the inner
+    /// store.
+    ///
+    /// Defaults to 100.
+    pub fn sync_hits(mut self, sync_hits: usize) -> Self {
+        self.sync_hits = sync_hits;
+        self
+    }
+
+    /// Sets how many instances share the limits, each of them consuming at most its share of the
+    /// last known remaining count between two syncs.
+    ///
+    /// Defaults to 1.
+    pub fn instances(mut self, instances: usize) -> Self {
+        self.instances = instances.max(1);
+        self
+    }
+
+    /// Decides `hit` locally if possible.
+    fn hit_local(&self, hit: &Hit, now: Instant) -> Local {
+        let Some(mut state) = self.keys.get_mut(&hit.key) else {
+            return Local::Sync(0);
+        };
+
+        // rejections last until their retry time, regardless of the sync interval
+        if state.rejected && now < state.reset_at {
+            return Local::Decided(Decision::rejected(state.reset_at - now));
+        }
+
+        let fits = state.pending + hit.cost <= state.budget && state.pending_hits < self.sync_hits;
+
+        if fits && (state.syncing || state.is_fresh(now, self.sync_interval)) {
+            state.pending += hit.cost;
+            state.pending_hits += 1;
+
+            return Local::Decided(Decision::new(
+                true,
+                state.remaining.saturating_sub(state.pending),
+                state.reset_at.saturating_duration_since(now),
+            ));
+        }
+
+        if state.syncing {
+            // do not wait for the sync in flight, and leave local hits to it
+            return Local::Sync(0);
+        }
+
+        state.syncing = true;
+        state.pending_hits = 0;
+        Local::Sync(std::mem::take(&mut state.pending))
+    }
+
+    /// Records the outcome of a sync of `key`.
+    fn synced(&self, key: String, decision: &Decision, now: Instant) {
+        let mut state = self.keys.entry(key).or_insert_with(|| LocalState {
+            synced_at: now,
+            reset_at: now,
+            rejected: false,
+            remaining: 0,
+            budget: 0,
+            pending: 0,
+            pending_hits: 0,
+            syncing: false,
+        });
+
+        state.synced_at = now;
+        state.reset_at = now + decision.reset_after();
+        state.rejected = !decision.is_allowed();
+        state.remaining = decision.remaining();
+        state.budget = decision.remaining() / self.instances;
+        state.syncing = false;
+    }
+
+    fn evict_expired(&self, now: Instant) {
+        if self
+            .hits
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(EVICTION_INTERVAL)
+        {
+            self.keys
+                .retain(|_, state| state.syncing || state.pending > 0 || state.reset_at > now);
+        }
+    }
+}
+
+impl RateLimitStore for ApproximateStore {
+    fn hit(&self, hit: Hit) -> BoxFuture<'_, Result<Decision, Error>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            self.evict_expired(now);
+
+            let pending = match self.hit_local(&hit, now) {
+                Local::Decided(decision) => return Ok(decision),
+                Local::Sync(pending) => pending,
+            };
+
+            // local hits are sent separately, so that their outcome does not affect this one
+            let mut hits = Vec::with_capacity(2);
+            if pending > 0 {
+                hits.push(Hit {
+                    cost: pending,
+                    ..hit.clone()
+                });
+            }
+            hits.push(hit.clone());
+
+            let decision = match self.inner.hit_many(hits).await {
+                Ok(mut decisions) => decisions.pop().expect("a decision for each hit"),
+                Err(err) => {
+                    // keep local hits for the next sync
+                    if let Some(mut state) = self.keys.get_mut(&hit.key) {
+                        state.pending += pending;
+                        state.syncing = false;
+                    }
+                    return Err(err);
+                }
+            };
+
+            self.synced(hit.key, &decision, Instant::now());
+            Ok(decision)
+        })
+    }
+
+    fn refund(&self, hit: Hit) -> BoxFuture<'_, Result<(), Error>> {
+        if let Some(mut state) = self.keys.get_mut(&hit.key) {
+            if state.pending >= hit.cost {
+                state.pending -= hit.cost;
+                return Box::pin(async { Ok(()) });
+            }
+        }
+
+        self.inner.refund(hit)
+    }
+
+    fn ban(&self, key: String, duration: Duration) -> BoxFuture<'_, Result<(), Error>> {
+        self.inner.ban(key, duration)
+    }
+
+    fn ban_ttl(&self, key: String) -> BoxFuture<'_, Result<Option<Duration>, Error>> {
+        self.inner.ban_ttl(key)
+    }
+
+    fn ban_ttl_many(
+        &self,
+        keys: Vec<String>,
+    ) -> BoxFuture<'_, Result<Vec<Option<Duration>>, Error>> {
+        self.inner.ban_ttl_many(keys)
+    }
+
+    fn unban(&self, key: String) -> BoxFuture<'_, Result<bool, Error>> {
+        self.inner.unban(key)
+    }
+
+    fn bans(&self) -> BoxFuture<'_, Result<Vec<(String, Duration)>, Error>> {
+        self.inner.bans()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::MemoryStore, Algorithm, Quota};
+
+    /// Counts the hits sent to a memory store.
+    #[derive(Debug, Default)]
+    struct CountingStore {
+        inner: MemoryStore,
+        calls: AtomicUsize,
+    }
+
+    impl RateLimitStore for CountingStore {
+        fn hit(&self, hit: Hit) -> BoxFuture<'_, Result<Decision, Error>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.hit(hit)
+        }
+
+        fn refund(&self, hit: Hit) -> BoxFuture<'_, Result<(), Error>> {
+            self.inner.refund(hit)
+        }
+    }
+
+    fn hit(quota: Quota) -> Hit {
+        Hit::new("key", quota, Algorithm::FixedWindow, 1)
+    }
+
+    fn calls(inner: &CountingStore) -> usize {
+        inner.calls.load(Ordering::SeqCst)
+    }
+
+    #[actix_web::test]
+    async fn test_hits_are_batched() {
+        let inner = Arc::new(CountingStore::default());
+        let store = ApproximateStore::with_shared_store(inner.clone())
+            .sync_interval(Duration::from_secs(60))
+            .sync_hits(10);
+        let quota = Quota::new(100, Duration::from_secs(60));
+
+        for remaining in (79..100).rev() {
+            let decision = store.hit(hit(quota)).await.unwrap();
+            assert!(decision.is_allowed());
+            assert_eq!(decision.remaining(), remaining);
+        }
+
+        // the first hit, then a batch of 10 local hits along with the hit triggering the sync
+        assert_eq!(calls(&inner), 3);
+        let decision = inner.inner.hit(hit(quota)).await.unwrap();
+        assert_eq!(decision.remaining(), 87);
+    }
+
+    #[actix_web::test]
+    async fn test_sync_interval() {
+        let inner = Arc::new(CountingStore::default());
+        let store = ApproximateStore::with_shared_store(inner.clone())
+            .sync_interval(Duration::from_millis(10));
+        let quota = Quota::new(100, Duration::from_secs(60));
+
+        store.hit(hit(quota)).await.unwrap();
+        store.hit(hit(quota)).await.unwrap();
+        assert_eq!(calls(&inner), 1);
+
+        std::thread::sleep(Duration::from_millis(20));
+        let decision = store.hit(hit(quota)).await.unwrap();
+        assert_eq!(decision.remaining(), 97);
+        assert_eq!(calls(&inner), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_budget_and_rejections() {
+        let inner = Arc::new(CountingStore::default());
+        let store = ApproximateStore::with_shared_store(inner.clone())
+            .sync_interval(Duration::from_secs(60))
+            .instances(2);
+        let quota = Quota::new(5, Duration::from_secs(60));
+
+        // 4 remaining after the first hit, 2 of which can be consumed locally
+        for _ in 0..3 {
+            assert!(store.hit(hit(quota)).await.unwrap().is_allowed());
+        }
+        assert_eq!(calls(&inner), 1);
+
+        // synced with the local hits: 1 remaining, none of which can be consumed locally
+        assert!(store.hit(hit(quota)).await.unwrap().is_allowed());
+        assert!(store.hit(hit(quota)).await.unwrap().is_allowed());
+        assert!(!store.hit(hit(quota)).await.unwrap().is_allowed());
+        let calls_before = calls(&inner);
+
+        // rejected locally until the window resets
+        assert!(!store.hit(hit(quota)).await.unwrap().is_allowed());
+        assert_eq!(calls(&inner), calls_before);
+    }
+
+    #[actix_web::test]
+    async fn test_refund() {
+        let inner = Arc::new(CountingStore::default());
+        let store = ApproximateStore::with_shared_store(inner.clone())
+            .sync_interval(Duration::from_secs(60));
+        let quota = Quota::new(100, Duration::from_secs(60));
+
+        store.hit(hit(quota)).await.unwrap();
+        store.hit(hit(quota)).await.unwrap();
+
+        // the local hit is refunded locally, the synced one by the inner store
+        store.refund(hit(quota)).await.unwrap();
+        store.refund(hit(quota)).await.unwrap();
+        let decision = inner.inner.hit(hit(quota)).await.unwrap();
+        assert_eq!(decision.remaining(), 99);
+    }
+}
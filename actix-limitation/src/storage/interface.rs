@@ -0,0 +1,209 @@
+use std::{fmt, time::Duration};
+
+use futures_core::future::BoxFuture;
+
+use crate::{Algorithm, Error, Quota};
+
+/// The interface to retrieve and update rate limit counters.
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// Counts a request against its quota.
+    ///
+    /// Implementations must check and update the state of the key atomically.
+    fn hit(&self, hit: Hit) -> BoxFuture<'_, Result<Decision, Error>>;
+
+    /// Counts a request against several quotas at once, returning a decision for each of them.
+    ///
+    /// The default implementation calls [`hit`](Self::hit) for each quota in turn. Stores should
+    /// override it if they can process all of them in a single round-trip.
+    fn hit_many(&self, hits: Vec<Hit>) -> BoxFuture<'_, Result<Vec<Decision>, Error>> {
+        Box::pin(async move {
+            let mut decisions = Vec::with_capacity(hits.len());
+            for hit in hits {
+                decisions.push(self.hit(hit).await?);
+            }
+            Ok(decisions)
+        })
+    }
+
+    /// Gives back the units consumed by a previous, allowed, hit.
+    ///
+    /// Units are only given back if the window or bucket they were taken from still exists, and a
+    /// count never goes below zero.
+    fn refund(&self, hit: Hit) -> BoxFuture<'_, Result<(), Error>>;
+
+    /// Bans `key` for `duration`, replacing any previous ban of the key.
+    ///
+    /// The default implementation returns an error: stores must implement [`ban`](Self::ban),
+    /// [`ban_ttl`](Self::ban_ttl), [`unban`](Self::unban) and [`bans`](Self::bans) to support
+    /// temporary bans.
+    fn ban(&self, key: String, duration: Duration) -> BoxFuture<'_, Result<(), Error>> {
+        let _ = (key, duration);
+        Box::pin(async { Err(bans_unsupported()) })
+    }
+
+    /// Returns how long `key` remains banned, if it is.
+    fn ban_ttl(&self, key: String) -> BoxFuture<'_, Result<Option<Duration>, Error>> {
+        let _ = key;
+        Box::pin(async { Err(bans_unsupported()) })
+    }
+
+    /// Returns how long each of `keys` remains banned, if it is.
+    ///
+    /// The default implementation calls [`ban_ttl`](Self::ban_ttl) for each key in turn. Stores
+    /// should override it if they can check all of them in a single round-trip.
+    fn ban_ttl_many(
+        &self,
+        keys: Vec<String>,
+    ) -> BoxFuture<'_, Result<Vec<Option<Duration>>, Error>> {
+        Box::pin(async move {
+            let mut ttls = Vec::with_capacity(keys.len());
+            for key in keys {
+                ttls.push(self.ban_ttl(key).await?);
+            }
+            Ok(ttls)
+        })
+    }
+
+    /// Lifts the ban of `key`, returning whether it was banned.
+    fn unban(&self, key: String) -> BoxFuture<'_, Result<bool, Error>> {
+        let _ = key;
+        Box::pin(async { Err(bans_unsupported()) })
+    }
+
+    /// Returns all the banned keys, along with how long they remain banned.
+    fn bans(&self) -> BoxFuture<'_, Result<Vec<(String, Duration)>, Error>> {
+        Box::pin(async { Err(bans_unsupported()) })
+    }
+}
+
+fn bans_unsupported() -> Error {
+    Error::Other("Bans are not supported by the store".to_owned())
+}
+
+impl fmt::Debug for dyn RateLimitStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RateLimitStore")
+    }
+}
+
+/// The interface to acquire and release permits of per-key semaphores, used to limit how many
+/// requests of a key are in flight at once.
+pub trait SemaphoreStore: Send + Sync + 'static {
+    /// Tries to acquire one of the `permits` of the semaphore of `key`, returning the identifier of
+    /// the acquired permit, or `None` if all of them are in use.
+    ///
+    /// Stores shared between processes should release permits that are held for longer than
+    /// `lease` on their own, so that permits of crashed instances are not lost forever.
+    /// Implementations must check and update the semaphore atomically.
+    fn acquire(
+        &self,
+        key: String,
+        permits: usize,
+        lease: Duration,
+    ) -> BoxFuture<'_, Result<Option<String>, Error>>;
+
+    /// Releases the given permit of the semaphore of `key`.
+    ///
+    /// Releasing a permit that has expired or was already released does nothing.
+    fn release(&self, key: String, permit: String) -> BoxFuture<'_, Result<(), Error>>;
+}
+
+impl fmt::Debug for dyn SemaphoreStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SemaphoreStore")
+    }
+}
+
+/// Whether a request is within its quota, as decided by a [`RateLimitStore`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    allowed: bool,
+    remaining: usize,
+    reset_after: Duration,
+}
+
+impl Decision {
+    /// Constructs a decision from parts.
+    ///
+    /// `reset_after` is the time until the quota is fully restored or, if the request is not
+    /// allowed, until a new request can be allowed.
+    #[must_use]
+    pub fn new(allowed: bool, remaining: usize, reset_after: Duration) -> Self {
+        Self {
+            allowed,
+            remaining,
+            reset_after,
+        }
+    }
+
+    /// Constructs a decision rejecting a request, which can be retried after `retry_after`.
+    #[must_use]
+    pub fn rejected(retry_after: Duration) -> Self {
+        Self::new(false, 0, retry_after)
+    }
+
+    /// Returns whether the request is allowed.
+    #[must_use]
+    pub fn is_allowed(&self) -> bool {
+        self.allowed
+    }
+
+    /// Returns how many requests are left in the quota.
+    #[must_use]
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Returns the time until the quota is fully restored or, if the request is not allowed, until
+    /// a new request can be allowed.
+    #[must_use]
+    pub fn reset_after(&self) -> Duration {
+        self.reset_after
+    }
+}
+
+/// A request counted by a [`RateLimitStore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    pub(crate) key: String,
+    pub(crate) quota: Quota,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) cost: usize,
+}
+
+impl Hit {
+    /// Constructs a hit of `cost` units for `key`, against `quota`, using the given `algorithm`.
+    #[must_use]
+    pub fn new(key: impl Into<String>, quota: Quota, algorithm: Algorithm, cost: usize) -> Self {
+        Self {
+            key: key.into(),
+            quota,
+            algorithm,
+            cost,
+        }
+    }
+
+    /// Returns the key of the counter.
+    #[must_use]
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the quota the request is counted against.
+    #[must_use]
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Returns the algorithm used to count the request.
+    #[must_use]
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Returns how many units the request consumes.
+    #[must_use]
+    pub fn cost(&self) -> usize {
+        self.cost
+    }
+}
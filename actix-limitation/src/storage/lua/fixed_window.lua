@@ -0,0 +1,34 @@
+-- Fixed window counter; see `fixed_window` and `refund` in `src/algorithm.rs`.
+--
+-- KEYS[1]: the counter
+-- ARGV[1]: limit
+-- ARGV[2]: period, in microseconds
+-- ARGV[3]: cost of the request; a negative cost refunds a previous request
+--
+-- Returns {allowed, remaining, reset after (in microseconds)}.
+
+local limit = tonumber(ARGV[1])
+local period = math.max(math.ceil(tonumber(ARGV[2]) / 1000), 1)
+local cost = tonumber(ARGV[3])
+
+if cost < 0 then
+    local count = tonumber(redis.call('GET', KEYS[1]))
+    if count and count > 0 then
+        redis.call('DECRBY', KEYS[1], math.min(-cost, count))
+    end
+    return { 1, 0, 0 }
+end
+
+local count = redis.call('INCRBY', KEYS[1], cost)
+local ttl = redis.call('PTTL', KEYS[1])
+if ttl < 0 then
+    redis.call('PEXPIRE', KEYS[1], period)
+    ttl = period
+end
+
+local allowed = 0
+if count <= limit then
+    allowed = 1
+end
+
+return { allowed, math.max(limit - count, 0), ttl * 1000 }
@@ -0,0 +1,34 @@
+-- Acquires a permit of a semaphore whose permits expire after a lease.
+--
+-- KEYS[1]: the permits in use, scored by the time their lease expires at, in microseconds
+-- ARGV[1]: number of permits
+-- ARGV[2]: lease, in microseconds
+-- ARGV[3]: identifier of the permit to acquire
+--
+-- Returns 1 if the permit was acquired, 0 if all permits are in use.
+
+-- the script reads the clock before writing: replicate its effects rather than the script itself
+-- (this is the only mode available, and a no-op, since Redis 7)
+redis.replicate_commands()
+
+local permits = tonumber(ARGV[1])
+local lease = math.max(tonumber(ARGV[2]), 1)
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', string.format('%d', now))
+
+if redis.call('ZCARD', KEYS[1]) >= permits then
+    return 0
+end
+
+redis.call('ZADD', KEYS[1], string.format('%d', now + lease), ARGV[3])
+
+-- keep the semaphore until its last lease expires
+local ttl = math.max(math.ceil(lease / 1000), 1)
+if redis.call('PTTL', KEYS[1]) < ttl then
+    redis.call('PEXPIRE', KEYS[1], ttl)
+end
+
+return 1
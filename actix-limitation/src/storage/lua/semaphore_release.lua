@@ -0,0 +1,8 @@
+-- Releases a permit of a semaphore; see `semaphore_acquire.lua`.
+--
+-- KEYS[1]: the permits in use
+-- ARGV[1]: identifier of the permit to release
+--
+-- Returns 1 if the permit was in use, 0 otherwise.
+
+return redis.call('ZREM', KEYS[1], ARGV[1])
@@ -0,0 +1,65 @@
+-- Sliding window counter; see `sliding_window` and `refund` in `src/algorithm.rs`.
+--
+-- KEYS[1]: a hash holding the counts of the current and previous windows
+-- ARGV[1]: limit
+-- ARGV[2]: period, in microseconds
+-- ARGV[3]: cost of the request; a negative cost refunds a previous request
+--
+-- Returns {allowed, remaining, reset after (in microseconds)}.
+
+-- the script reads the clock before writing: replicate its effects rather than the script itself
+-- (this is the only mode available, and a no-op, since Redis 7)
+redis.replicate_commands()
+
+local limit = tonumber(ARGV[1])
+local period = math.max(tonumber(ARGV[2]), 1)
+local cost = tonumber(ARGV[3])
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+local window = math.floor(now / period)
+local elapsed = now - window * period
+
+local state = redis.call('HMGET', KEYS[1], 'window', 'current', 'previous')
+local stored = tonumber(state[1])
+
+if cost < 0 then
+    if stored and stored + 1 >= window then
+        redis.call('HSET', KEYS[1], 'current', math.max(tonumber(state[2]) + cost, 0))
+    end
+    return { 1, 0, 0 }
+end
+
+local current, previous = 0, 0
+if stored == window then
+    current, previous = tonumber(state[2]), tonumber(state[3])
+elseif stored == window - 1 then
+    previous = tonumber(state[2])
+end
+
+local estimate = previous * (period - elapsed) / period + current
+
+if estimate + cost > limit then
+    local retry_after
+    if current + cost > limit then
+        -- wait for the next window, during which the current count keeps weighing in
+        retry_after = period - elapsed
+        if limit > 0 and current > 0 then
+            retry_after = retry_after + period * (1 - (limit - cost) / current)
+        end
+    else
+        retry_after = (period - elapsed) - period * (limit - cost - current) / previous
+    end
+
+    return { 0, 0, math.ceil(math.max(retry_after, 0)) }
+end
+
+redis.call(
+    'HSET', KEYS[1],
+    'window', string.format('%d', window),
+    'current', current + cost,
+    'previous', previous
+)
+redis.call('PEXPIRE', KEYS[1], math.ceil((2 * period - elapsed) / 1000))
+
+return { 1, math.floor(limit - estimate - cost), period - elapsed }
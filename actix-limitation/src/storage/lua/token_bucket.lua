@@ -0,0 +1,62 @@
+-- Token bucket, implemented as a generic cell rate algorithm; see `token_bucket` and `refund` in
+-- `src/algorithm.rs`.
+--
+-- KEYS[1]: the theoretical arrival time of the next request, in microseconds
+-- ARGV[1]: limit
+-- ARGV[2]: period, in microseconds
+-- ARGV[3]: cost of the request; a negative cost refunds a previous request
+-- ARGV[4]: burst
+--
+-- Returns {allowed, remaining, reset after (in microseconds)}.
+
+-- the script reads the clock before writing: replicate its effects rather than the script itself
+-- (this is the only mode available, and a no-op, since Redis 7)
+redis.replicate_commands()
+
+local limit = tonumber(ARGV[1])
+local period = math.max(tonumber(ARGV[2]), 1)
+local cost = tonumber(ARGV[3])
+local burst = tonumber(ARGV[4])
+
+if limit == 0 then
+    return { 0, 0, period }
+end
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+
+local interval = period / limit
+
+if cost < 0 then
+    local tat = tonumber(redis.call('GET', KEYS[1]))
+    if tat then
+        tat = math.max(math.ceil(tat + interval * cost), now)
+        if tat > now then
+            redis.call(
+                'SET', KEYS[1], string.format('%d', tat),
+                'PX', math.max(math.ceil((tat - now) / 1000), 1)
+            )
+        else
+            redis.call('DEL', KEYS[1])
+        end
+    end
+    return { 1, 0, 0 }
+end
+
+local tolerance = interval * burst
+local tat = math.max(tonumber(redis.call('GET', KEYS[1])) or now, now)
+
+local new_tat = tat + interval * cost
+local allow_at = new_tat - tolerance
+
+if now < allow_at then
+    return { 0, 0, math.ceil(allow_at - now) }
+end
+
+new_tat = math.ceil(new_tat)
+redis.call(
+    'SET', KEYS[1], string.format('%d', new_tat),
+    'PX', math.max(math.ceil((new_tat - now) / 1000), 1)
+)
+
+return { 1, math.max(math.floor((tolerance - (new_tat - now)) / interval), 0), new_tat - now }
@@ -0,0 +1,343 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use futures_core::future::BoxFuture;
+
+use super::{Decision, Hit, RateLimitStore, SemaphoreStore};
+use crate::{
+    algorithm::{self, micros, State, Update},
+    Error,
+};
+
+/// Number of hits between two sweeps of expired counters.
+const EVICTION_INTERVAL: usize = 1024;
+
+/// In-process rate limit counters.
+///
+/// Counters are kept in a sharded concurrent map, so workers rarely contend on the same lock.
+/// Expired counters are reset when they are next hit and periodically evicted.
+///
+/// Counters are not shared between processes: if your application is deployed on multiple
+/// instances, each of them enforces the limit separately. Use [`RedisStore`](super::RedisStore)
+/// for a limit shared between instances.
+///
+/// As a [`SemaphoreStore`], it keeps the permits in use for each key, which expire after their
+/// lease like those of [`RedisStore`](super::RedisStore).
+///
+/// # Examples
+/// ```
+/// use actix_limitation::{storage::MemoryStore, Limiter};
+///
+/// let limiter = Limiter::builder_with_store(MemoryStore::new())
+///     .limit(100)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    counters: DashMap<String, Counter>,
+    hits: AtomicUsize,
+    /// Permits in use, by key, with the time they expire at in microseconds since the UNIX epoch.
+    semaphores: DashMap<String, HashMap<String, u64>>,
+    permit_ids: AtomicU64,
+    /// Banned keys, with the time their ban expires at in microseconds since the UNIX epoch.
+    bans: DashMap<String, u64>,
+}
+
+#[derive(Debug)]
+struct Counter {
+    state: State,
+    /// When the counter can be discarded, in microseconds since the UNIX epoch.
+    expires_at: u64,
+}
+
+impl MemoryStore {
+    /// Constructs an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn hit_sync(&self, hit: Hit) -> Decision {
+        let now = algorithm::now();
+        let decision = self.update(hit.key, now, |state| {
+            algorithm::apply(hit.algorithm, hit.quota, state, now, hit.cost as u64)
+        });
+
+        if self
+            .hits
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(EVICTION_INTERVAL)
+        {
+            self.counters.retain(|_, counter| counter.expires_at > now);
+            self.bans.retain(|_, expires_at| *expires_at > now);
+        }
+
+        decision
+    }
+
+    fn refund_sync(&self, hit: Hit) {
+        let now = algorithm::now();
+        self.update(hit.key, now, |state| {
+            let update = algorithm::refund(hit.algorithm, hit.quota, state, now, hit.cost as u64);
+            ((), update)
+        });
+    }
+
+    /// Atomically updates the counter of `key`, which is passed to `f` unless it has expired.
+    fn update<T>(&self, key: String, now: u64, f: impl FnOnce(Option<State>) -> (T, Update)) -> T {
+        let mut entry = self.counters.entry(key);
+        let state = match entry {
+            dashmap::Entry::Occupied(ref counter) if counter.get().expires_at > now => {
+                Some(counter.get().state)
+            }
+            _ => None,
+        };
+
+        let (result, update) = f(state);
+        if let Some((state, expires_at)) = update {
+            let counter = Counter { state, expires_at };
+            match entry {
+                dashmap::Entry::Occupied(ref mut entry) => {
+                    entry.insert(counter);
+                }
+                dashmap::Entry::Vacant(entry) => {
+                    entry.insert(counter);
+                }
+            }
+        }
+
+        result
+    }
+
+    fn ban_ttl_sync(&self, key: &str) -> Option<Duration> {
+        let now = algorithm::now();
+        let expires_at = *self.bans.get(key)?;
+
+        if expires_at > now {
+            Some(Duration::from_micros(expires_at - now))
+        } else {
+            self.bans.remove_if(key, |_, expires_at| *expires_at <= now);
+            None
+        }
+    }
+
+    fn bans_sync(&self) -> Vec<(String, Duration)> {
+        let now = algorithm::now();
+        self.bans
+            .iter()
+            .filter(|ban| *ban.value() > now)
+            .map(|ban| (ban.key().clone(), Duration::from_micros(ban.value() - now)))
+            .collect()
+    }
+
+    fn acquire_sync(&self, key: String, permits: usize, lease: Duration) -> Option<String> {
+        let now = algorithm::now();
+        let mut held = self.semaphores.entry(key).or_default();
+        held.retain(|_, expires_at| *expires_at > now);
+
+        if held.len() >= permits {
+            return None;
+        }
+
+        let id = self.permit_ids.fetch_add(1, Ordering::Relaxed).to_string();
+        held.insert(id.clone(), now.saturating_add(micros(lease)));
+        Some(id)
+    }
+
+    fn release_sync(&self, key: &str, permit: &str) {
+        self.semaphores.remove_if_mut(key, |_, held| {
+            held.remove(permit);
+            held.is_empty()
+        });
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn hit(&self, hit: Hit) -> BoxFuture<'_, Result<Decision, Error>> {
+        let decision = self.hit_sync(hit);
+        Box::pin(async move { Ok(decision) })
+    }
+
+    fn refund(&self, hit: Hit) -> BoxFuture<'_, Result<(), Error>> {
+        self.refund_sync(hit);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn ban(&self, key: String, duration: Duration) -> BoxFuture<'_, Result<(), Error>> {
+        let expires_at = algorithm::now().saturating_add(micros(duration));
+        self.bans.insert(key, expires_at);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn ban_ttl(&self, key: String) -> BoxFuture<'_, Result<Option<Duration>, Error>> {
+        let ttl = self.ban_ttl_sync(&key);
+        Box::pin(async move { Ok(ttl) })
+    }
+
+    fn unban(&self, key: String) -> BoxFuture<'_, Result<bool, Error>> {
+        let now = algorithm::now();
+        let banned = self
+            .bans
+            .remove(&key)
+            .is_some_and(|(_, expires_at)| expires_at > now);
+        Box::pin(async move { Ok(banned) })
+    }
+
+    fn bans(&self) -> BoxFuture<'_, Result<Vec<(String, Duration)>, Error>> {
+        let bans = self.bans_sync();
+        Box::pin(async move { Ok(bans) })
+    }
+}
+
+impl SemaphoreStore for MemoryStore {
+    fn acquire(
+        &self,
+        key: String,
+        permits: usize,
+        lease: Duration,
+    ) -> BoxFuture<'_, Result<Option<String>, Error>> {
+        let permit = self.acquire_sync(key, permits, lease);
+        Box::pin(async move { Ok(permit) })
+    }
+
+    fn release(&self, key: String, permit: String) -> BoxFuture<'_, Result<(), Error>> {
+        self.release_sync(&key, &permit);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{Algorithm, Quota};
+
+    fn hit(key: &str, quota: Quota) -> Hit {
+        Hit::new(key, quota, Algorithm::FixedWindow, 1)
+    }
+
+    #[test]
+    fn test_hit() {
+        let store = MemoryStore::new();
+        let quota = Quota::new(2, Duration::from_secs(60));
+
+        for remaining in [1, 0] {
+            let decision = store.hit_sync(hit("key", quota));
+            assert!(decision.is_allowed());
+            assert_eq!(decision.remaining(), remaining);
+            assert!(decision.reset_after() <= quota.period());
+        }
+        let decision = store.hit_sync(hit("key", quota));
+        assert!(!decision.is_allowed());
+
+        let decision = store.hit_sync(hit("other", quota));
+        assert_eq!(decision.remaining(), 1);
+    }
+
+    #[test]
+    fn test_counter_is_reset_after_period() {
+        let store = MemoryStore::new();
+        let quota = Quota::new(1, Duration::from_millis(10));
+
+        store.hit_sync(hit("key", quota));
+        store.hit_sync(hit("key", quota));
+        std::thread::sleep(quota.period() * 2);
+
+        let decision = store.hit_sync(hit("key", quota));
+        assert!(decision.is_allowed());
+    }
+
+    #[test]
+    fn test_expired_counters_are_evicted() {
+        let store = MemoryStore::new();
+        let short = Quota::new(1, Duration::from_millis(10));
+        let long = Quota::new(1, Duration::from_secs(60));
+
+        store.hit_sync(hit("key", short));
+        std::thread::sleep(short.period() * 2);
+
+        for _ in 0..EVICTION_INTERVAL {
+            store.hit_sync(hit("other", long));
+        }
+
+        assert!(!store.counters.contains_key("key"));
+        assert!(store.counters.contains_key("other"));
+    }
+
+    #[test]
+    fn test_refund() {
+        let store = MemoryStore::new();
+        let quota = Quota::new(2, Duration::from_secs(60));
+
+        let decision = store.hit_sync(Hit::new("key", quota, Algorithm::FixedWindow, 2));
+        assert_eq!(decision.remaining(), 0);
+
+        store.refund_sync(Hit::new("key", quota, Algorithm::FixedWindow, 2));
+        assert_eq!(store.hit_sync(hit("key", quota)).remaining(), 1);
+
+        // refunds do not create counters
+        store.refund_sync(hit("other", quota));
+        assert!(!store.counters.contains_key("other"));
+    }
+
+    #[test]
+    fn test_semaphore() {
+        let store = MemoryStore::new();
+        let lease = Duration::from_secs(60);
+
+        let first = store.acquire_sync("key".to_owned(), 2, lease).unwrap();
+        let second = store.acquire_sync("key".to_owned(), 2, lease).unwrap();
+        assert_ne!(first, second);
+        assert!(store.acquire_sync("key".to_owned(), 2, lease).is_none());
+        assert!(store.acquire_sync("other".to_owned(), 2, lease).is_some());
+
+        store.release_sync("key", &first);
+        store.release_sync("key", &first);
+        assert!(store.acquire_sync("key".to_owned(), 2, lease).is_some());
+        assert!(store.acquire_sync("key".to_owned(), 2, lease).is_none());
+
+        // semaphores are removed once all their permits are released
+        store.release_sync("other", "0");
+        store.release_sync("other", "2");
+        assert!(!store.semaphores.contains_key("other"));
+    }
+
+    #[test]
+    fn test_semaphore_lease_expiry() {
+        let store = MemoryStore::new();
+        let lease = Duration::from_millis(10);
+
+        store.acquire_sync("key".to_owned(), 1, lease).unwrap();
+        assert!(store.acquire_sync("key".to_owned(), 1, lease).is_none());
+
+        std::thread::sleep(lease * 2);
+        assert!(store.acquire_sync("key".to_owned(), 1, lease).is_some());
+    }
+
+    #[test]
+    fn test_bans() {
+        let store = MemoryStore::new();
+        assert_eq!(store.ban_ttl_sync("key"), None);
+
+        store
+            .bans
+            .insert("key".to_owned(), algorithm::now() + 60_000_000);
+        store
+            .bans
+            .insert("expired".to_owned(), algorithm::now() - 1);
+
+        let ttl = store.ban_ttl_sync("key").unwrap();
+        assert!(ttl > Duration::from_secs(59) && ttl <= Duration::from_secs(60));
+        assert_eq!(store.ban_ttl_sync("expired"), None);
+        assert!(!store.bans.contains_key("expired"));
+
+        let bans = store.bans_sync();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].0, "key");
+    }
+}
@@ -0,0 +1,26 @@
+//! Pluggable storage backends for rate limit counters.
+//!
+//! [`Limiter`](crate::Limiter) keeps its counters in a [`RateLimitStore`]. Two implementations
+//! are provided:
+//!
+//! - [`RedisStore`], the default, shares counters between all the instances of an application;
+//! - [`MemoryStore`] keeps counters in-process, which is enough for single-instance deployments
+//!   and does not require a Redis server (e.g. in tests).
+//!
+//! Both also implement [`SemaphoreStore`], used by [`ConcurrencyLimiter`](crate::ConcurrencyLimiter)
+//! to limit how many requests are in flight at once.
+//!
+//! [`ApproximateStore`] can be wrapped around a rate limit store to count requests locally and
+//! sync counters in batches, trading accuracy for throughput.
+
+mod approximate;
+mod interface;
+mod memory;
+mod redis;
+
+pub use self::{
+    approximate::ApproximateStore,
+    interface::{Decision, Hit, RateLimitStore, SemaphoreStore},
+    memory::MemoryStore,
+    redis::RedisStore,
+};
@@ -0,0 +1,548 @@
+use std::{
+    fmt,
+    hash::{BuildHasher as _, RandomState},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_core::future::BoxFuture;
+use redis::{
+    aio::{ConnectionLike, ConnectionManager, ConnectionManagerConfig},
+    Client, Cmd, FromRedisValue, Pipeline, RedisFuture, Script, ServerErrorKind, Value,
+};
+use tokio::sync::OnceCell;
+
+use super::{Decision, Hit, RateLimitStore, SemaphoreStore};
+use crate::{algorithm::micros, Algorithm, Error};
+
+/// How long to wait for a response from Redis by default.
+const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Redis-backed rate limit counters, shared between all the instances of an application.
+///
+/// This is the store used by [`Limiter::builder`](crate::Limiter::builder). Each
+/// [algorithm](crate::Algorithm) is implemented as a Lua script, so that counters are checked and
+/// updated atomically. Several quotas are checked in a single round-trip.
+///
+/// Bans are kept under the banned key prefixed with `ban:`, with the same expiry as the ban.
+///
+/// As a [`SemaphoreStore`], it keeps the permits in use for each key in a sorted set, scored by
+/// the time their lease expires at.
+///
+/// # Connections
+/// The store connects to Redis on first use, and then keeps a single multiplexed connection that is
+/// shared by all requests, and by all clones of the store. It reconnects automatically if the
+/// connection is lost. By default, connecting never times out and responses time out after one
+/// second; see [`connection_timeout`](Self::connection_timeout) and
+/// [`response_timeout`](Self::response_timeout).
+///
+/// When the `redis-pool` crate feature is enabled, a pre-existing pool from [`deadpool_redis`] can
+/// be used instead:
+///
+/// ```no_run
+/// # #[cfg(feature = "redis-pool")]
+/// # {
+/// use actix_limitation::{storage::RedisStore, Limiter};
+/// use deadpool_redis::{Config, Runtime};
+///
+/// let redis_cfg = Config::from_url("redis://127.0.0.1:6379");
+/// let redis_pool = redis_cfg.create_pool(Some(Runtime::Tokio1)).unwrap();
+///
+/// let limiter = Limiter::builder_with_store(RedisStore::new_pooled(redis_pool))
+///     .build()
+///     .unwrap();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct RedisStore {
+    connection: RedisConnection,
+    fixed_window: Script,
+    sliding_window: Script,
+    token_bucket: Script,
+    acquire: Script,
+    release: Script,
+    permit_ids: Arc<PermitIds>,
+}
+
+/// Generates permit identifiers that are unique across instances.
+#[derive(Debug)]
+struct PermitIds {
+    /// Random prefix of the identifiers of this store.
+    instance: u64,
+    next: AtomicU64,
+}
+
+impl PermitIds {
+    fn new() -> Self {
+        Self {
+            instance: RandomState::new().hash_one(std::process::id()),
+            next: AtomicU64::new(0),
+        }
+    }
+
+    fn next(&self) -> String {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        format!("{:016x}-{id}", self.instance)
+    }
+}
+
+#[derive(Clone)]
+enum RedisConnection {
+    /// Single multiplexed connection.
+    Single(Box<SingleConnection>),
+
+    /// Connection pool.
+    #[cfg(feature = "redis-pool")]
+    Pool(deadpool_redis::Pool),
+}
+
+/// A connection from a [`RedisConnection`].
+enum Connection {
+    Managed(ConnectionManager),
+
+    #[cfg(feature = "redis-pool")]
+    Pooled(deadpool_redis::Connection),
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Managed(connection) => connection.req_packed_command(cmd),
+            #[cfg(feature = "redis-pool")]
+            Self::Pooled(connection) => connection.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Managed(connection) => connection.req_packed_commands(pipeline, offset, count),
+            #[cfg(feature = "redis-pool")]
+            Self::Pooled(connection) => connection.req_packed_commands(pipeline, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Managed(connection) => connection.get_db(),
+            #[cfg(feature = "redis-pool")]
+            Self::Pooled(connection) => connection.get_db(),
+        }
+    }
+}
+
+/// A multiplexed connection, established on first use.
+#[derive(Clone)]
+struct SingleConnection {
+    client: Client,
+    config: ConnectionManagerConfig,
+    manager: Arc<OnceCell<ConnectionManager>>,
+}
+
+impl fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let connection = match self.connection {
+            RedisConnection::Single(_) => "Single",
+            #[cfg(feature = "redis-pool")]
+            RedisConnection::Pool(_) => "Pool",
+        };
+
+        f.debug_struct("RedisStore")
+            .field("connection", &connection)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisStore {
+    /// Constructs a store from an existing Redis client.
+    pub fn new(client: Client) -> Self {
+        // Do not retry failed connections: requests would wait for all the attempts, while the next
+        // request tries to reconnect anyway.
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(None)
+            .set_response_timeout(Some(DEFAULT_RESPONSE_TIMEOUT))
+            .set_number_of_retries(0);
+
+        Self::with_connection(RedisConnection::Single(Box::new(SingleConnection {
+            client,
+            config,
+            manager: Arc::new(OnceCell::new()),
+        })))
+    }
+
+    /// Constructs a store connecting to the Redis server at `redis_url`.
+    ///
+    /// See [`redis-rs` docs](https://docs.rs/redis/1.0.4/redis/#connection-parameters) on
+    /// connection parameters for how to set the Redis URL.
+    pub fn open(redis_url: &str) -> Result<Self, Error> {
+        Ok(Self::new(Client::open(redis_url)?))
+    }
+
+    /// Constructs a store using connections from an existing pool.
+    #[cfg(feature = "redis-pool")]
+    pub fn new_pooled(pool: impl Into<deadpool_redis::Pool>) -> Self {
+        Self::with_connection(RedisConnection::Pool(pool.into()))
+    }
+
+    fn with_connection(connection: RedisConnection) -> Self {
+        Self {
+            connection,
+            fixed_window: Script::new(include_str!("lua/fixed_window.lua")),
+            sliding_window: Script::new(include_str!("lua/sliding_window.lua")),
+            token_bucket: Script::new(include_str!("lua/token_bucket.lua")),
+            acquire: Script::new(include_str!("lua/semaphore_acquire.lua")),
+            release: Script::new(include_str!("lua/semaphore_release.lua")),
+            permit_ids: Arc::new(PermitIds::new()),
+        }
+    }
+
+    /// Sets how long to wait for a connection to Redis to be established, including when
+    /// reconnecting.
+    ///
+    /// Has no effect on pooled stores, whose connections are configured by the pool. Defaults to no
+    /// timeout.
+    pub fn connection_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.update_config(|config| config.set_connection_timeout(timeout));
+        self
+    }
+
+    /// Sets how long to wait for a response from Redis. Requests that time out fail, and are then
+    /// handled like other store errors by [`RateLimiter`](crate::RateLimiter).
+    ///
+    /// Has no effect on pooled stores, whose connections are configured by the pool. Defaults to one
+    /// second; `None` waits indefinitely.
+    pub fn response_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.update_config(|config| config.set_response_timeout(timeout));
+        self
+    }
+
+    fn update_config(
+        &mut self,
+        update: impl FnOnce(ConnectionManagerConfig) -> ConnectionManagerConfig,
+    ) {
+        match self.connection {
+            RedisConnection::Single(ref mut single) => {
+                single.config = update(single.config.clone());
+            }
+
+            #[cfg(feature = "redis-pool")]
+            RedisConnection::Pool(_) => {}
+        }
+    }
+
+    fn script(&self, algorithm: Algorithm) -> &Script {
+        match algorithm {
+            Algorithm::FixedWindow => &self.fixed_window,
+            Algorithm::SlidingWindow => &self.sliding_window,
+            Algorithm::TokenBucket { .. } => &self.token_bucket,
+        }
+    }
+
+    /// Builds the call to the script for `hit`.
+    ///
+    /// A negative `cost` refunds a previous hit.
+    fn hit_call(&self, hit: &Hit, cost: i64) -> (&Script, Cmd) {
+        let script = self.script(hit.algorithm);
+        let mut cmd = eval_cmd(script, &hit.key);
+        cmd.arg(hit.quota.limit)
+            .arg(micros(hit.quota.period))
+            .arg(cost);
+        if let Algorithm::TokenBucket { burst } = hit.algorithm {
+            cmd.arg(burst);
+        }
+        (script, cmd)
+    }
+
+    /// Returns a connection, establishing the shared connection or taking one from the pool.
+    async fn connection(&self) -> Result<Connection, Error> {
+        match self.connection {
+            RedisConnection::Single(ref single) => {
+                let manager = single
+                    .manager
+                    .get_or_try_init(|| {
+                        ConnectionManager::new_with_config(
+                            single.client.clone(),
+                            single.config.clone(),
+                        )
+                    })
+                    .await?;
+                Ok(Connection::Managed(manager.clone()))
+            }
+
+            #[cfg(feature = "redis-pool")]
+            RedisConnection::Pool(ref pool) => {
+                let connection = pool
+                    .get()
+                    .await
+                    .map_err(|err| Error::Other(format!("Failed to get a connection: {err}")))?;
+                Ok(Connection::Pooled(connection))
+            }
+        }
+    }
+
+    /// Runs a single command.
+    async fn query<T: FromRedisValue>(&self, cmd: &Cmd) -> Result<T, Error> {
+        let mut connection = self.connection().await?;
+        Ok(cmd.query_async(&mut connection).await?)
+    }
+
+    /// Runs all the script `calls` in a single round-trip, returning their replies.
+    async fn eval_many(&self, calls: &[(&Script, Cmd)]) -> Result<Vec<Value>, Error> {
+        let connection = &mut self.connection().await?;
+
+        let mut pipe = redis::pipe();
+        pipe.ignore_errors();
+        for (_, cmd) in calls {
+            pipe.add_command(cmd.clone());
+        }
+        let mut replies: Vec<Value> = pipe.query_async(connection).await?;
+
+        // Scripts are loaded lazily, e.g. after the first use or a restart of the server: load
+        // them and retry the calls that failed (only those, since the others were counted).
+        for (reply, (script, cmd)) in replies.iter_mut().zip(calls) {
+            if matches!(
+                reply,
+                Value::ServerError(err) if err.kind() == Some(ServerErrorKind::NoScript)
+            ) {
+                script.load_async(connection).await?;
+                *reply = cmd.query_async(connection).await?;
+            }
+        }
+
+        replies
+            .into_iter()
+            .map(|reply| Ok(reply.extract_error()?))
+            .collect()
+    }
+}
+
+impl RateLimitStore for RedisStore {
+    fn hit(&self, hit: Hit) -> BoxFuture<'_, Result<Decision, Error>> {
+        Box::pin(async move {
+            let mut decisions = self.hit_many(vec![hit]).await?;
+            Ok(decisions.remove(0))
+        })
+    }
+
+    fn hit_many(&self, hits: Vec<Hit>) -> BoxFuture<'_, Result<Vec<Decision>, Error>> {
+        Box::pin(async move {
+            let calls: Vec<_> = hits
+                .iter()
+                .map(|hit| self.hit_call(hit, cost(hit)))
+                .collect();
+
+            self.eval_many(&calls)
+                .await?
+                .into_iter()
+                .map(|reply| {
+                    let (allowed, remaining, reset_after): (u8, usize, u64) =
+                        redis::from_redis_value(reply).map_err(redis::RedisError::from)?;
+
+                    Ok(Decision::new(
+                        allowed == 1,
+                        remaining,
+                        Duration::from_micros(reset_after),
+                    ))
+                })
+                .collect()
+        })
+    }
+
+    fn refund(&self, hit: Hit) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            self.eval_many(&[self.hit_call(&hit, -cost(&hit))]).await?;
+            Ok(())
+        })
+    }
+
+    fn ban(&self, key: String, duration: Duration) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(ban_key(&key))
+                .arg(1)
+                .arg("PX")
+                .arg(millis(duration).max(1));
+            self.query::<()>(&cmd).await
+        })
+    }
+
+    fn ban_ttl(&self, key: String) -> BoxFuture<'_, Result<Option<Duration>, Error>> {
+        Box::pin(async move {
+            let ttl: i64 = self.query(redis::cmd("PTTL").arg(ban_key(&key))).await?;
+            Ok(ban_ttl(ttl))
+        })
+    }
+
+    fn ban_ttl_many(
+        &self,
+        keys: Vec<String>,
+    ) -> BoxFuture<'_, Result<Vec<Option<Duration>>, Error>> {
+        Box::pin(async move {
+            let mut pipe = redis::pipe();
+            for key in &keys {
+                pipe.cmd("PTTL").arg(ban_key(key));
+            }
+            let ttls: Vec<i64> = pipe.query_async(&mut self.connection().await?).await?;
+            Ok(ttls.into_iter().map(ban_ttl).collect())
+        })
+    }
+
+    fn unban(&self, key: String) -> BoxFuture<'_, Result<bool, Error>> {
+        Box::pin(async move {
+            let deleted: u64 = self.query(redis::cmd("DEL").arg(ban_key(&key))).await?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn bans(&self) -> BoxFuture<'_, Result<Vec<(String, Duration)>, Error>> {
+        Box::pin(async move {
+            let connection = &mut self.connection().await?;
+
+            let mut keys = Vec::new();
+            let mut cursor = 0;
+            loop {
+                let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(format!("{BAN_PREFIX}*"))
+                    .arg("COUNT")
+                    .arg(1000)
+                    .query_async(connection)
+                    .await?;
+                keys.extend(batch);
+
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+
+            let mut pipe = redis::pipe();
+            for key in &keys {
+                pipe.cmd("PTTL").arg(key);
+            }
+            let ttls: Vec<i64> = pipe.query_async(connection).await?;
+
+            Ok(keys
+                .into_iter()
+                .zip(ttls)
+                .filter_map(|(key, ttl)| {
+                    let key = key.strip_prefix(BAN_PREFIX)?.to_owned();
+                    Some((key, ban_ttl(ttl)?))
+                })
+                .collect())
+        })
+    }
+}
+
+/// Prefix of the keys of bans.
+const BAN_PREFIX: &str = "ban:";
+
+fn ban_key(key: &str) -> String {
+    format!("{BAN_PREFIX}{key}")
+}
+
+/// Converts the reply of `PTTL` for a ban, which is negative if the key does not exist or has no
+/// expiry.
+fn ban_ttl(ttl: i64) -> Option<Duration> {
+    let ttl = u64::try_from(ttl).ok().filter(|&ttl| ttl > 0)?;
+    Some(Duration::from_millis(ttl))
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+impl SemaphoreStore for RedisStore {
+    fn acquire(
+        &self,
+        key: String,
+        permits: usize,
+        lease: Duration,
+    ) -> BoxFuture<'_, Result<Option<String>, Error>> {
+        Box::pin(async move {
+            let permit = self.permit_ids.next();
+            let mut cmd = eval_cmd(&self.acquire, &key);
+            cmd.arg(permits).arg(micros(lease)).arg(&permit);
+
+            let reply = self.eval_many(&[(&self.acquire, cmd)]).await?.remove(0);
+            let acquired: u8 = redis::from_redis_value(reply).map_err(redis::RedisError::from)?;
+            Ok((acquired == 1).then_some(permit))
+        })
+    }
+
+    fn release(&self, key: String, permit: String) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            let mut cmd = eval_cmd(&self.release, &key);
+            cmd.arg(permit);
+
+            self.eval_many(&[(&self.release, cmd)]).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Builds the command running `script` on `key`, assuming it has been loaded; its arguments are
+/// to be added by the caller.
+fn eval_cmd(script: &Script, key: &str) -> Cmd {
+    let mut cmd = redis::cmd("EVALSHA");
+    cmd.arg(script.get_hash()).arg(1).arg(key);
+    cmd
+}
+
+fn cost(hit: &Hit) -> i64 {
+    i64::try_from(hit.cost).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single(store: &RedisStore) -> &SingleConnection {
+        match store.connection {
+            RedisConnection::Single(ref single) => single,
+
+            #[cfg(feature = "redis-pool")]
+            RedisConnection::Pool(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let store = RedisStore::open("redis://127.0.0.1").unwrap();
+        assert_eq!(single(&store).config.connection_timeout(), None);
+        assert_eq!(
+            single(&store).config.response_timeout(),
+            Some(DEFAULT_RESPONSE_TIMEOUT)
+        );
+
+        let store = store
+            .connection_timeout(Some(Duration::from_secs(1)))
+            .response_timeout(Some(Duration::from_millis(500)));
+        assert_eq!(
+            single(&store).config.connection_timeout(),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            single(&store).config.response_timeout(),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn test_clones_share_connection() {
+        let store = RedisStore::open("redis://127.0.0.1").unwrap();
+        let clone = store.clone();
+        assert!(Arc::ptr_eq(
+            &single(&store).manager,
+            &single(&clone).manager
+        ));
+    }
+}
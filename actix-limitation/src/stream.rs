@@ -0,0 +1,284 @@
+//! Rate limiting for streams, such as the messages of a WebSocket.
+//!
+//! Once a connection is upgraded to a WebSocket, [`RateLimiter`](crate::RateLimiter) no longer
+//! applies to what goes through it. [`LimitedStream`] counts each item of a stream against a
+//! [`Limiter`], under a key chosen for the stream, e.g. the identifier of the connection or of the
+//! user. Items over the limit are dropped, delayed or end the stream; see [`OverQuota`].
+//!
+//! ```no_run
+//! # #[cfg(feature = "ws")]
+//! # {
+//! use actix_limitation::{
+//!     stream::{LimitedStream, OverQuota},
+//!     Limiter,
+//! };
+//! use actix_web::{web, Error, HttpRequest, HttpResponse};
+//! use actix_ws::Message;
+//! use futures_util::StreamExt as _;
+//!
+//! async fn ws(
+//!     req: HttpRequest,
+//!     body: web::Payload,
+//!     limiter: web::Data<Limiter>,
+//! ) -> Result<HttpResponse, Error> {
+//!     let (response, mut session, stream) = actix_ws::handle(&req, body)?;
+//!     let key = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+//!
+//!     let mut stream = LimitedStream::new(stream, limiter.into_inner(), key)
+//!         // close the socket with code 1008 (policy violation)
+//!         .over_quota(OverQuota::Close(1008))
+//!         .session(session.clone());
+//!
+//!     actix_web::rt::spawn(async move {
+//!         while let Some(Ok(Ok(msg))) = stream.next().await {
+//!             if let Message::Text(text) = msg {
+//!                 let _ = session.text(text).await;
+//!             }
+//!         }
+//!     });
+//!
+//!     Ok(response)
+//! }
+//! # }
+//! ```
+
+use std::{
+    fmt,
+    future::Future as _,
+    mem,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use actix_web::rt::time::{sleep, Sleep};
+use futures_core::{future::BoxFuture, Stream};
+use pin_project_lite::pin_project;
+
+use crate::{observe, Error, Limiter, Status};
+
+/// Shortest delay before a delayed item is counted again.
+const MIN_DELAY: Duration = Duration::from_millis(100);
+
+/// What [`LimitedStream`] does with items over the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum OverQuota {
+    /// Drops the item and moves on to the next one.
+    #[default]
+    Drop,
+
+    /// Holds the item, without reading further items, until the limit resets, then counts it
+    /// again.
+    Delay,
+
+    /// Yields [`Error::LimitExceeded`] and ends the stream.
+    ///
+    /// If a WebSocket session is set (see `LimitedStream::session`, behind the `ws` crate
+    /// feature), it is closed with the given close code.
+    Close(u16),
+}
+
+/// Cost function type with auto traits.
+type CostFn<T> = dyn Fn(&T) -> usize + Send + Sync;
+
+/// Function closing the underlying connection with a close code.
+type CloseFn = Box<dyn FnOnce(u16) + Send + Sync>;
+
+pin_project! {
+    /// Stream adapter counting each item against a [`Limiter`].
+    ///
+    /// Yields the items within the limit, and errors of the limiter, e.g. if its store is
+    /// unreachable; the items that could not be counted are dropped.
+    pub struct LimitedStream<S>
+    where
+        S: Stream,
+    {
+        #[pin]
+        stream: S,
+        limiter: Arc<Limiter>,
+        key: String,
+        cost: Option<Arc<CostFn<S::Item>>>,
+        over_quota: OverQuota,
+        state: State<S::Item>,
+        close: Option<CloseFn>,
+    }
+}
+
+enum State<T> {
+    /// Waiting for the next item.
+    Ready,
+
+    /// Counting an item.
+    Counting {
+        item: T,
+        count: BoxFuture<'static, Result<Status, Error>>,
+    },
+
+    /// Waiting to count an item again.
+    Delaying { item: T, delay: Pin<Box<Sleep>> },
+
+    /// The stream has ended.
+    Done,
+}
+
+impl<S: Stream> LimitedStream<S> {
+    /// Counts each item of `stream` against `limiter`, under the given `key`.
+    pub fn new(stream: S, limiter: impl Into<Arc<Limiter>>, key: impl Into<String>) -> Self {
+        Self {
+            stream,
+            limiter: limiter.into(),
+            key: key.into(),
+            cost: None,
+            over_quota: OverQuota::default(),
+            state: State::Ready,
+            close: None,
+        }
+    }
+
+    /// Sets what to do with items over the limit.
+    ///
+    /// Defaults to [`OverQuota::Drop`].
+    pub fn over_quota(mut self, over_quota: OverQuota) -> Self {
+        self.over_quota = over_quota;
+        self
+    }
+
+    /// Sets a function computing how many rate limit units an item consumes, e.g. from its size.
+    ///
+    /// Each item consumes one unit by default.
+    pub fn cost_by<F>(mut self, cost_fn: F) -> Self
+    where
+        F: Fn(&S::Item) -> usize + Send + Sync + 'static,
+    {
+        self.cost = Some(Arc::new(cost_fn));
+        self
+    }
+
+    /// Sets the WebSocket session to close when an item is over the limit, with
+    /// [`OverQuota::Close`].
+    #[cfg(feature = "ws")]
+    pub fn session(mut self, session: actix_ws::Session) -> Self {
+        self.close = Some(Box::new(move |code| {
+            let reason = actix_ws::CloseReason {
+                code: code.into(),
+                description: None,
+            };
+            actix_web::rt::spawn(async move {
+                let _ = session.close(Some(reason)).await;
+            });
+        }));
+        self
+    }
+}
+
+impl<S> Stream for LimitedStream<S>
+where
+    S: Stream,
+{
+    type Item = Result<S::Item, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+
+        loop {
+            match mem::replace(this.state, State::Done) {
+                State::Ready => match this.stream.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(item)) => {
+                        let cost = this.cost.as_ref().map_or(1, |cost_fn| cost_fn(&item));
+                        let count = count(this.limiter, this.key, cost);
+                        *this.state = State::Counting { item, count };
+                    }
+                    Poll::Ready(None) => return Poll::Ready(None),
+                    Poll::Pending => {
+                        *this.state = State::Ready;
+                        return Poll::Pending;
+                    }
+                },
+
+                State::Counting { item, mut count } => {
+                    let outcome = match count.as_mut().poll(cx) {
+                        Poll::Ready(outcome) => outcome,
+                        Poll::Pending => {
+                            *this.state = State::Counting { item, count };
+                            return Poll::Pending;
+                        }
+                    };
+
+                    match outcome {
+                        Ok(_) => {
+                            *this.state = State::Ready;
+                            return Poll::Ready(Some(Ok(item)));
+                        }
+                        Err(Error::LimitExceeded(status)) => match *this.over_quota {
+                            OverQuota::Drop => {
+                                log::warn!(
+                                    "Rate limit exceeded for key {}, dropping item",
+                                    observe::key_hash(this.key)
+                                );
+                                *this.state = State::Ready;
+                            }
+                            OverQuota::Delay => {
+                                let delay = status.reset_after().max(MIN_DELAY);
+                                *this.state = State::Delaying {
+                                    item,
+                                    delay: Box::pin(sleep(delay)),
+                                };
+                            }
+                            OverQuota::Close(code) => {
+                                log::warn!(
+                                    "Rate limit exceeded for key {}, closing stream",
+                                    observe::key_hash(this.key)
+                                );
+
+                                if let Some(close) = this.close.take() {
+                                    close(code);
+                                }
+
+                                return Poll::Ready(Some(Err(Error::LimitExceeded(status))));
+                            }
+                        },
+                        Err(err) => {
+                            *this.state = State::Ready;
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                    }
+                }
+
+                State::Delaying { item, mut delay } => {
+                    if delay.as_mut().poll(cx).is_pending() {
+                        *this.state = State::Delaying { item, delay };
+                        return Poll::Pending;
+                    }
+
+                    let cost = this.cost.as_ref().map_or(1, |cost_fn| cost_fn(&item));
+                    let count = count(this.limiter, this.key, cost);
+                    *this.state = State::Counting { item, count };
+                }
+
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+fn count(
+    limiter: &Arc<Limiter>,
+    key: &str,
+    cost: usize,
+) -> BoxFuture<'static, Result<Status, Error>> {
+    let limiter = Arc::clone(limiter);
+    let key = key.to_owned();
+    Box::pin(async move { limiter.consume(key, cost).await })
+}
+
+impl<S: Stream> fmt::Debug for LimitedStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LimitedStream")
+            .field("limiter", &self.limiter)
+            .field("key", &self.key)
+            .field("over_quota", &self.over_quota)
+            .finish_non_exhaustive()
+    }
+}
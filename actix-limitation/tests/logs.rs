@@ -0,0 +1,114 @@
+//! Checks that rate limit keys, which are often IP addresses or user ids, never reach the logs.
+//!
+//! Kept apart from the other tests as it installs a global logger.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_limitation::{
+    storage::MemoryStore,
+    stream::{LimitedStream, OverQuota},
+    ConcurrencyLimiter, Limiter, RateLimiter,
+};
+use actix_web::{dev::ServiceRequest, test, web, App, HttpResponse};
+use futures_util::{stream, StreamExt as _};
+
+const KEY: &str = "198.51.100.42";
+
+static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct CapturingLogger;
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        LOGS.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+fn limiter() -> Limiter {
+    Limiter::builder_with_store(MemoryStore::new())
+        .key_by(|_: &ServiceRequest| Some(KEY.to_owned()))
+        .limit(1)
+        .build()
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn keys_are_not_logged() {
+    log::set_logger(&CapturingLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+
+    // requests over the limit
+    let app = test::init_service(
+        App::new()
+            .wrap(RateLimiter::default())
+            .app_data(web::Data::new(limiter()))
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    for _ in 0..2 {
+        test::call_service(&app, test::TestRequest::default().to_request()).await;
+    }
+
+    // shadow limits and bans
+    let shadow = Limiter::builder_with_store(MemoryStore::new())
+        .limit(1)
+        .shadow(true)
+        .build()
+        .unwrap();
+    let banning = Limiter::builder_with_store(MemoryStore::new())
+        .limit(1)
+        .ban_after(1, Duration::from_secs(60), Duration::from_secs(60))
+        .build()
+        .unwrap();
+    for _ in 0..2 {
+        let _ = shadow.count(KEY).await;
+        let _ = banning.count(KEY).await;
+    }
+
+    // items of streams over the limit
+    let limiter = Arc::new(limiter());
+    for over_quota in [OverQuota::Drop, OverQuota::Close(1008)] {
+        LimitedStream::new(stream::iter(1..=2), Arc::clone(&limiter), KEY)
+            .over_quota(over_quota)
+            .collect::<Vec<_>>()
+            .await;
+    }
+
+    // concurrent requests over the limit
+    let app = test::init_service(
+        App::new()
+            .wrap(
+                ConcurrencyLimiter::new(MemoryStore::new(), 1)
+                    .key_by(|_: &ServiceRequest| Some(KEY.to_owned())),
+            )
+            .route("/", web::get().to(|| async { "report" })),
+    )
+    .await;
+    let _first = test::call_service(&app, test::TestRequest::default().to_request()).await;
+    test::call_service(&app, test::TestRequest::default().to_request()).await;
+
+    let logs = LOGS.lock().unwrap();
+    for message in [
+        "Rate limit exceeded for key",
+        "Shadow rate limit exceeded for key",
+        "Banning key",
+        "dropping item",
+        "closing stream",
+        "Concurrency limit exceeded for key",
+    ] {
+        assert!(
+            logs.iter().any(|log| log.contains(message)),
+            "{message:?} not logged: {logs:?}"
+        );
+    }
+    assert!(!logs.iter().any(|log| log.contains(KEY)), "{logs:?}");
+}
@@ -1,7 +1,24 @@
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use actix_limitation::{Error, Limiter, RateLimiter};
-use actix_web::{dev::ServiceRequest, http::StatusCode, test, web, App, HttpRequest, HttpResponse};
+use actix_limitation::{
+    key::{self, KeyExtractor as _, PeerIp, ResolvedKey, RoutePattern},
+    storage::{Decision, Hit, MemoryStore, RateLimitStore, RedisStore, SemaphoreStore},
+    stream::{LimitedStream, OverQuota},
+    Algorithm, ConcurrencyLimiter, Error, HeaderStyle, Limiter, Limiters, Quota, RateLimiter,
+};
+use actix_web::{
+    dev::{ServiceRequest, Transform as _},
+    http::StatusCode,
+    test, web, App, HttpRequest, HttpResponse,
+};
+use futures_core::future::BoxFuture;
+use futures_util::{stream, StreamExt as _};
 use uuid::Uuid;
 
 #[test]
@@ -28,6 +45,37 @@ async fn test_limiter_count() -> Result<(), Error> {
     Ok(())
 }
 
+#[actix_web::test]
+async fn test_redis_connection_is_lazy() {
+    // nothing listens on port 1: building succeeds, counting fails
+    let store = RedisStore::open("redis://127.0.0.1:1")
+        .unwrap()
+        .connection_timeout(Some(Duration::from_secs(1)));
+    let limiter = web::Data::new(
+        Limiter::builder_with_store(store)
+            .key_by(|_: &ServiceRequest| Some("key".to_owned()))
+            .build()
+            .unwrap(),
+    );
+
+    assert!(matches!(
+        limiter.count("key").await.unwrap_err(),
+        Error::Client(_)
+    ));
+
+    let app = test::init_service(
+        App::new()
+            .app_data(limiter)
+            .wrap(RateLimiter::default())
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let req = test::TestRequest::default().to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
 #[actix_web::test]
 async fn test_limiter_count_error() -> Result<(), Error> {
     let limiter = Limiter::builder("redis://127.0.0.1:6379/3")
@@ -90,3 +138,1188 @@ async fn test_limiter_key_by() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[actix_web::test]
+async fn test_memory_limiter_count_error() -> Result<(), Error> {
+    let limiter = Limiter::builder_with_store(MemoryStore::new())
+        .limit(25)
+        .build()
+        .unwrap();
+
+    let id = Uuid::new_v4();
+    for i in 0..25 {
+        let status = limiter.count(id.to_string()).await?;
+        assert_eq!(25 - status.remaining(), i + 1);
+    }
+
+    match limiter.count(id.to_string()).await.unwrap_err() {
+        Error::LimitExceeded(status) => assert_eq!(status.remaining(), 0),
+        _ => panic!("error should be LimitExceeded variant"),
+    };
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_memory_limiter_key_by() -> Result<(), Error> {
+    let cooldown_period = Duration::from_secs(1);
+    let limiter = Limiter::builder_with_store(MemoryStore::new())
+        .limit(2)
+        .period(cooldown_period)
+        .key_by(|_: &ServiceRequest| Some("fix_key".to_string()))
+        .build()
+        .unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .wrap(RateLimiter::default())
+            .app_data(web::Data::new(limiter))
+            .route(
+                "/",
+                web::get().to(|_: HttpRequest| async { HttpResponse::Ok().body("ok") }),
+            ),
+    )
+    .await;
+    for _ in 1..3 {
+        for index in 1..4 {
+            let req = test::TestRequest::default().to_request();
+            let resp = test::call_service(&app, req).await;
+            if index <= 2 {
+                assert!(resp.status().is_success());
+            } else {
+                assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+            }
+        }
+        std::thread::sleep(cooldown_period);
+    }
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_memory_limiter_algorithms() -> Result<(), Error> {
+    for algorithm in [
+        Algorithm::FixedWindow,
+        Algorithm::SlidingWindow,
+        Algorithm::TokenBucket { burst: 5 },
+    ] {
+        let limiter = Limiter::builder_with_store(MemoryStore::new())
+            .limit(5)
+            .period(Duration::from_secs(60))
+            .algorithm(algorithm)
+            .build()
+            .unwrap();
+
+        let id = Uuid::new_v4();
+        for i in 0..5 {
+            let status = limiter.count(id.to_string()).await?;
+            assert_eq!(status.remaining(), 4 - i, "{algorithm:?}");
+        }
+
+        match limiter.count(id.to_string()).await.unwrap_err() {
+            Error::LimitExceeded(status) => assert_eq!(status.remaining(), 0),
+            _ => panic!("error should be LimitExceeded variant"),
+        };
+    }
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_token_bucket_reports_burst() -> Result<(), Error> {
+    let limiter = Limiter::builder_with_store(MemoryStore::new())
+        .limit(1)
+        .period(Duration::from_secs(60))
+        .algorithm(Algorithm::TokenBucket { burst: 3 })
+        .build()
+        .unwrap();
+
+    let status = limiter.count(Uuid::new_v4().to_string()).await?;
+    assert_eq!(status.limit(), 3);
+    assert_eq!(status.remaining(), 2);
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_redis_limiter_algorithms() -> Result<(), Error> {
+    for algorithm in [
+        Algorithm::FixedWindow,
+        Algorithm::SlidingWindow,
+        Algorithm::TokenBucket { burst: 5 },
+    ] {
+        let limiter = Limiter::builder("redis://127.0.0.1:6379/3")
+            .limit(5)
+            .period(Duration::from_secs(60))
+            .algorithm(algorithm)
+            .build()
+            .unwrap();
+
+        let id = Uuid::new_v4();
+        for i in 0..5 {
+            let status = limiter.count(id.to_string()).await?;
+            assert_eq!(status.remaining(), 4 - i, "{algorithm:?}");
+        }
+
+        match limiter.count(id.to_string()).await.unwrap_err() {
+            Error::LimitExceeded(status) => assert_eq!(status.remaining(), 0),
+            _ => panic!("error should be LimitExceeded variant"),
+        };
+    }
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_rate_limit_headers() {
+    let limiter = web::Data::new(
+        Limiter::builder_with_store(MemoryStore::new())
+            .limit(1)
+            .period(Duration::from_secs(60))
+            .key_by(|_: &ServiceRequest| Some("fix_key".to_string()))
+            .build()
+            .unwrap(),
+    );
+
+    let app = test::init_service(
+        App::new()
+            .wrap(RateLimiter::default())
+            .app_data(limiter)
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let resp = test::call_service(&app, test::TestRequest::default().to_request()).await;
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "1");
+    assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "0");
+    let reset: u64 = resp
+        .headers()
+        .get("ratelimit-reset")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    // the reset time is rounded to the nearest second
+    assert!((59..=61).contains(&reset));
+    assert!(!resp.headers().contains_key("retry-after"));
+
+    let resp = test::call_service(&app, test::TestRequest::default().to_request()).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "0");
+    assert!(resp.headers().contains_key("retry-after"));
+}
+
+#[actix_web::test]
+async fn test_rate_limit_header_styles() {
+    let limiter = web::Data::new(
+        Limiter::builder_with_store(MemoryStore::new())
+            .limit(1)
+            .key_by(|_: &ServiceRequest| Some(Uuid::new_v4().to_string()))
+            .build()
+            .unwrap(),
+    );
+
+    let app = test::init_service(
+        App::new()
+            .app_data(limiter)
+            .service(
+                web::resource("/legacy")
+                    .wrap(RateLimiter::default().header_style(HeaderStyle::Legacy))
+                    .to(HttpResponse::Ok),
+            )
+            .service(
+                web::resource("/none")
+                    .wrap(RateLimiter::default().header_style(HeaderStyle::None))
+                    .to(HttpResponse::Ok),
+            ),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/legacy").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.headers().get("x-ratelimit-limit").unwrap(), "1");
+    assert_eq!(resp.headers().get("x-ratelimit-remaining").unwrap(), "0");
+    assert!(resp.headers().contains_key("x-ratelimit-reset"));
+    assert!(!resp.headers().contains_key("ratelimit-limit"));
+
+    let req = test::TestRequest::with_uri("/none").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert!(!resp.headers().contains_key("ratelimit-limit"));
+    assert!(!resp.headers().contains_key("x-ratelimit-limit"));
+}
+
+#[actix_web::test]
+async fn test_multiple_policies() {
+    let store: Arc<dyn RateLimitStore> = Arc::new(MemoryStore::new());
+    let id = Uuid::new_v4().to_string();
+    let key_by = move |_: &ServiceRequest| Some(id.clone());
+
+    let limiters = Limiters::new()
+        .insert(
+            "burst",
+            Limiter::builder_with_shared_store(Arc::clone(&store))
+                .limit(2)
+                .key_by(key_by.clone())
+                .build()
+                .unwrap(),
+        )
+        .insert(
+            "daily",
+            Limiter::builder_with_shared_store(Arc::clone(&store))
+                .limit(4)
+                .period(Duration::from_secs(24 * 60 * 60))
+                .key_by(key_by)
+                .build()
+                .unwrap(),
+        );
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(limiters))
+            .service(
+                web::resource("/search")
+                    .wrap(RateLimiter::default().policy("burst").policy("daily"))
+                    .to(HttpResponse::Ok),
+            )
+            .service(
+                web::resource("/export")
+                    .wrap(RateLimiter::default().policy("daily"))
+                    .to(HttpResponse::Ok),
+            ),
+    )
+    .await;
+
+    // headers describe the limit with the fewest remaining requests
+    for remaining in ["1", "0"] {
+        let req = test::TestRequest::with_uri("/search").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "2");
+        assert_eq!(
+            resp.headers().get("ratelimit-remaining").unwrap(),
+            remaining
+        );
+    }
+
+    // burst limit is exceeded, the rejected request does not count against the daily limit
+    let req = test::TestRequest::with_uri("/search").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    for remaining in ["1", "0"] {
+        let req = test::TestRequest::with_uri("/export").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "4");
+        assert_eq!(
+            resp.headers().get("ratelimit-remaining").unwrap(),
+            remaining
+        );
+    }
+
+    let req = test::TestRequest::with_uri("/export").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "4");
+}
+
+#[actix_web::test]
+async fn test_unregistered_policies() {
+    let limiters = web::Data::new(
+        Limiters::new().insert(
+            "burst",
+            Limiter::builder_with_store(MemoryStore::new())
+                .key_by(|_: &ServiceRequest| Some("key".to_owned()))
+                .build()
+                .unwrap(),
+        ),
+    );
+
+    // checked when the middleware is constructed
+    let rate_limiter = RateLimiter::default()
+        .limiters(limiters.clone())
+        .policy("burst");
+    assert!(rate_limiter.new_transform(test::ok_service()).await.is_ok());
+    let rate_limiter = rate_limiter.policy("daily");
+    assert!(rate_limiter
+        .new_transform(test::ok_service())
+        .await
+        .is_err());
+
+    // checked when requests are handled
+    let app = test::init_service(
+        App::new()
+            .app_data(limiters)
+            .service(
+                web::resource("/daily")
+                    .wrap(RateLimiter::default().policy("daily"))
+                    .to(HttpResponse::Ok),
+            )
+            .service(
+                web::resource("/open")
+                    .wrap(RateLimiter::default().policy("daily").fail_open(true))
+                    .to(HttpResponse::Ok),
+            ),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/daily").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let req = test::TestRequest::with_uri("/open").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+}
+
+/// Counts calls to the store, to check that limits are checked together.
+#[derive(Default)]
+struct CountingStore {
+    inner: MemoryStore,
+    calls: AtomicUsize,
+}
+
+impl RateLimitStore for CountingStore {
+    fn hit(&self, hit: Hit) -> BoxFuture<'_, Result<Decision, Error>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.inner.hit(hit)
+    }
+
+    fn hit_many(&self, hits: Vec<Hit>) -> BoxFuture<'_, Result<Vec<Decision>, Error>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.inner.hit_many(hits)
+    }
+
+    fn refund(&self, hit: Hit) -> BoxFuture<'_, Result<(), Error>> {
+        self.inner.refund(hit)
+    }
+}
+
+#[actix_web::test]
+async fn test_limits_sharing_a_store_are_checked_together() {
+    let shared = Arc::new(CountingStore::default());
+    let other = Arc::new(CountingStore::default());
+
+    let limiter = |store: &Arc<CountingStore>, limit: usize| {
+        Limiter::builder_with_shared_store(Arc::clone(store) as Arc<dyn RateLimitStore>)
+            .limit(limit)
+            .key_by(move |_: &ServiceRequest| Some(format!("limit-{limit}")))
+            .build()
+            .unwrap()
+    };
+
+    let app = test::init_service(
+        App::new().service(
+            web::resource("/")
+                .wrap(
+                    RateLimiter::default()
+                        .limiter(limiter(&shared, 1))
+                        .limiter(limiter(&other, 2))
+                        .limiter(limiter(&shared, 3)),
+                )
+                .to(HttpResponse::Ok),
+        ),
+    )
+    .await;
+
+    let req = test::TestRequest::default().to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "1");
+    assert_eq!(shared.calls.load(Ordering::SeqCst), 1);
+    assert_eq!(other.calls.load(Ordering::SeqCst), 1);
+}
+
+#[actix_web::test]
+async fn test_memory_limiter_consume() -> Result<(), Error> {
+    let limiter = Limiter::builder_with_store(MemoryStore::new())
+        .limit(10)
+        .build()
+        .unwrap();
+
+    let id = Uuid::new_v4();
+    assert_eq!(limiter.consume(id.to_string(), 4).await?.remaining(), 6);
+    assert_eq!(limiter.consume(id.to_string(), 6).await?.remaining(), 0);
+
+    limiter.refund(id.to_string(), 3).await?;
+    assert_eq!(limiter.count(id.to_string()).await?.remaining(), 2);
+
+    match limiter.consume(id.to_string(), 3).await.unwrap_err() {
+        Error::LimitExceeded(status) => assert_eq!(status.remaining(), 0),
+        _ => panic!("error should be LimitExceeded variant"),
+    };
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_request_cost_and_refunds() {
+    let limiter = web::Data::new(
+        Limiter::builder_with_store(MemoryStore::new())
+            .limit(10)
+            .key_by(|_: &ServiceRequest| Some("fix_key".to_string()))
+            .build()
+            .unwrap(),
+    );
+
+    let app = test::init_service(
+        App::new()
+            .app_data(limiter)
+            .service(
+                web::resource("/export")
+                    .wrap(RateLimiter::default().cost(5))
+                    .to(HttpResponse::Ok),
+            )
+            .service(
+                web::resource("/search")
+                    .wrap(
+                        RateLimiter::default()
+                            .cost_by(|req: &ServiceRequest| req.query_string().split('&').count()),
+                    )
+                    .to(HttpResponse::Ok),
+            )
+            .service(
+                web::resource("/cached")
+                    .wrap(
+                        RateLimiter::default()
+                            .refund_on(|status: StatusCode| status == StatusCode::NOT_MODIFIED),
+                    )
+                    .to(HttpResponse::NotModified),
+            ),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/export").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "5");
+
+    let req = test::TestRequest::with_uri("/search?q=actix&page=2").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "3");
+
+    // refunded requests do not count
+    for _ in 0..5 {
+        let req = test::TestRequest::with_uri("/cached").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    let req = test::TestRequest::with_uri("/export").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+/// A store that is always unavailable.
+struct FailingStore;
+
+impl RateLimitStore for FailingStore {
+    fn hit(&self, _: Hit) -> BoxFuture<'_, Result<Decision, Error>> {
+        Box::pin(async { Err(Error::Other("unavailable".to_owned())) })
+    }
+
+    fn refund(&self, _: Hit) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Err(Error::Other("unavailable".to_owned())) })
+    }
+}
+
+#[actix_web::test]
+async fn test_error_handler() {
+    let limiter = |store: Arc<dyn RateLimitStore>| {
+        Limiter::builder_with_shared_store(store)
+            .limit(1)
+            .key_by(|_: &ServiceRequest| Some("fix_key".to_string()))
+            .build()
+            .unwrap()
+    };
+    let rate_limiter = |limiter| {
+        RateLimiter::default()
+            .limiter(limiter)
+            .error_handler(|_: &ServiceRequest, err: &Error| match err {
+                Error::LimitExceeded(status) => HttpResponse::TooManyRequests()
+                    .content_type("application/problem+json")
+                    .body(format!(r#"{{"limit":{}}}"#, status.limit())),
+                _ => HttpResponse::ServiceUnavailable().finish(),
+            })
+    };
+
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::resource("/")
+                    .wrap(rate_limiter(limiter(Arc::new(MemoryStore::new()))))
+                    .to(HttpResponse::Ok),
+            )
+            .service(
+                web::resource("/unavailable")
+                    .wrap(rate_limiter(limiter(Arc::new(FailingStore))))
+                    .to(HttpResponse::Ok),
+            ),
+    )
+    .await;
+
+    let req = test::TestRequest::default().to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let req = test::TestRequest::default().to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/problem+json"
+    );
+    assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "1");
+    assert!(resp.headers().contains_key("retry-after"));
+    assert_eq!(test::read_body(resp).await, r#"{"limit":1}"#);
+
+    let req = test::TestRequest::with_uri("/unavailable").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(!resp.headers().contains_key("ratelimit-limit"));
+}
+
+#[actix_web::test]
+async fn test_fail_open() {
+    let limiter = || {
+        Limiter::builder_with_store(FailingStore)
+            .key_by(|_: &ServiceRequest| Some("fix_key".to_string()))
+            .build()
+            .unwrap()
+    };
+
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::resource("/closed")
+                    .wrap(RateLimiter::default().limiter(limiter()))
+                    .to(HttpResponse::Ok),
+            )
+            .service(
+                web::resource("/open")
+                    .wrap(RateLimiter::default().limiter(limiter()).fail_open(true))
+                    .to(HttpResponse::Ok),
+            ),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/closed").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let req = test::TestRequest::with_uri("/open").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert!(!resp.headers().contains_key("ratelimit-limit"));
+}
+
+#[actix_web::test]
+async fn test_route_pattern_key() {
+    let limiter = web::Data::new(
+        Limiter::builder_with_store(MemoryStore::new())
+            .limit(1)
+            .key_with(RoutePattern.and(PeerIp::new()))
+            .build()
+            .unwrap(),
+    );
+
+    let app = test::init_service(
+        App::new()
+            .wrap(RateLimiter::default())
+            .app_data(limiter)
+            .route("/users/{id}", web::get().to(HttpResponse::Ok))
+            .route("/search", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let request = |uri: &str, peer: &str| {
+        test::TestRequest::with_uri(uri)
+            .peer_addr(peer.parse().unwrap())
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, request("/users/1", "192.0.2.1:1234")).await;
+    assert!(resp.status().is_success());
+
+    // same route and client
+    let resp = test::call_service(&app, request("/users/2", "192.0.2.1:1234")).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let resp = test::call_service(&app, request("/search", "192.0.2.1:1234")).await;
+    assert!(resp.status().is_success());
+
+    let resp = test::call_service(&app, request("/users/1", "192.0.2.2:1234")).await;
+    assert!(resp.status().is_success());
+}
+
+#[actix_web::test]
+async fn test_redis_semaphore() -> Result<(), Error> {
+    let store = RedisStore::open("redis://127.0.0.1:6379/4")?;
+    let key = Uuid::new_v4().to_string();
+    let lease = Duration::from_secs(60);
+
+    let first = store.acquire(key.clone(), 2, lease).await?.unwrap();
+    let second = store.acquire(key.clone(), 2, lease).await?.unwrap();
+    assert_ne!(first, second);
+    assert!(store.acquire(key.clone(), 2, lease).await?.is_none());
+
+    store.release(key.clone(), first).await?;
+    assert!(store.acquire(key.clone(), 2, lease).await?.is_some());
+
+    // expired leases are released
+    let key = Uuid::new_v4().to_string();
+    let lease = Duration::from_millis(10);
+    store.acquire(key.clone(), 1, lease).await?.unwrap();
+    actix_web::rt::time::sleep(lease * 2).await;
+    assert!(store.acquire(key, 1, lease).await?.is_some());
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_concurrency_limiter() {
+    let app = test::init_service(
+        App::new()
+            .wrap(ConcurrencyLimiter::new(MemoryStore::new(), 1))
+            .route("/", web::get().to(|| async { "report" })),
+    )
+    .await;
+
+    let request = |peer: &str| {
+        test::TestRequest::default()
+            .peer_addr(peer.parse().unwrap())
+            .to_request()
+    };
+
+    // the permit is held until the body has been sent
+    let first = test::call_service(&app, request("192.0.2.1:1234")).await;
+    assert!(first.status().is_success());
+
+    let resp = test::call_service(&app, request("192.0.2.1:1234")).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let resp = test::call_service(&app, request("192.0.2.2:1234")).await;
+    assert!(resp.status().is_success());
+
+    assert_eq!(test::read_body(first).await, "report");
+
+    let resp = test::call_service(&app, request("192.0.2.1:1234")).await;
+    assert!(resp.status().is_success());
+}
+
+#[actix_web::test]
+async fn test_concurrency_limiter_queue() {
+    let store = Arc::new(MemoryStore::new());
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::resource("/short")
+                    .wrap(
+                        ConcurrencyLimiter::with_shared_store(store.clone(), 1)
+                            .key_by(|_| Some("client".to_owned()))
+                            .queue_timeout(Duration::from_millis(50)),
+                    )
+                    .to(HttpResponse::Ok),
+            )
+            .service(
+                web::resource("/long")
+                    .wrap(
+                        ConcurrencyLimiter::with_shared_store(store, 1)
+                            .key_by(|_| Some("client".to_owned()))
+                            .queue_timeout(Duration::from_secs(5)),
+                    )
+                    .to(HttpResponse::Ok),
+            ),
+    )
+    .await;
+
+    let held = test::call_service(&app, test::TestRequest::with_uri("/long").to_request()).await;
+    assert!(held.status().is_success());
+
+    // times out while the permit is held
+    let resp = test::call_service(&app, test::TestRequest::with_uri("/short").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // waits until the permit is released
+    actix_web::rt::spawn(async move {
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        drop(held);
+    });
+    let resp = test::call_service(&app, test::TestRequest::with_uri("/long").to_request()).await;
+    assert!(resp.status().is_success());
+}
+
+impl SemaphoreStore for FailingStore {
+    fn acquire(
+        &self,
+        _key: String,
+        _permits: usize,
+        _lease: Duration,
+    ) -> BoxFuture<'_, Result<Option<String>, Error>> {
+        Box::pin(async { Err(Error::Other("unavailable".to_owned())) })
+    }
+
+    fn release(&self, _key: String, _permit: String) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Err(Error::Other("unavailable".to_owned())) })
+    }
+}
+
+#[actix_web::test]
+async fn test_concurrency_limiter_fail_open() {
+    let store: Arc<dyn SemaphoreStore> = Arc::new(FailingStore);
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::resource("/closed")
+                    .wrap(ConcurrencyLimiter::with_shared_store(store.clone(), 1))
+                    .to(HttpResponse::Ok),
+            )
+            .service(
+                web::resource("/open")
+                    .wrap(ConcurrencyLimiter::with_shared_store(store, 1).fail_open(true))
+                    .to(HttpResponse::Ok),
+            ),
+    )
+    .await;
+
+    let request = |path: &str| {
+        test::TestRequest::with_uri(path)
+            .peer_addr("192.0.2.1:1234".parse().unwrap())
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, request("/closed")).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let resp = test::call_service(&app, request("/open")).await;
+    assert!(resp.status().is_success());
+}
+
+#[actix_web::test]
+async fn test_concurrency_limiter_key_resolver() {
+    let limiter = ConcurrencyLimiter::new(MemoryStore::new(), 1).key_resolver(key::resolve_fn(
+        |req: &ServiceRequest| {
+            let api_key = req
+                .headers()
+                .get("x-api-key")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned);
+
+            async move {
+                match api_key.as_deref() {
+                    Some("broken") => Err(Error::Other("lookup failed".to_owned())),
+                    api_key => Ok(api_key.map(ResolvedKey::new)),
+                }
+            }
+        },
+    ));
+
+    let app = test::init_service(
+        App::new()
+            .wrap(limiter)
+            .route("/", web::get().to(|| async { "report" })),
+    )
+    .await;
+
+    let request = |api_key: &str| {
+        test::TestRequest::default()
+            .insert_header(("x-api-key", api_key))
+            .to_request()
+    };
+
+    let first = test::call_service(&app, request("tenant")).await;
+    assert!(first.status().is_success());
+
+    let resp = test::call_service(&app, request("tenant")).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let resp = test::call_service(&app, request("broken")).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    // requests without a key are not limited
+    let resp = test::call_service(&app, test::TestRequest::default().to_request()).await;
+    assert!(resp.status().is_success());
+}
+
+#[actix_web::test]
+async fn test_allow_and_deny_lists() {
+    let limiter = web::Data::new(
+        Limiter::builder_with_store(MemoryStore::new())
+            .key_by(|req: &ServiceRequest| {
+                req.headers()
+                    .get("x-tenant")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_owned)
+            })
+            .allow_keys(["premium"])
+            .deny_keys(["abuser"])
+            .limit(1)
+            .build()
+            .unwrap(),
+    );
+
+    let app = test::init_service(
+        App::new()
+            .wrap(RateLimiter::default())
+            .app_data(limiter)
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let request = |tenant: &str| {
+        test::TestRequest::default()
+            .insert_header(("x-tenant", tenant))
+            .to_request()
+    };
+
+    for _ in 0..3 {
+        let resp = test::call_service(&app, request("premium")).await;
+        assert!(resp.status().is_success());
+        assert!(!resp.headers().contains_key("ratelimit-remaining"));
+    }
+
+    let resp = test::call_service(&app, request("abuser")).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(!resp.headers().contains_key("retry-after"));
+
+    let resp = test::call_service(&app, request("regular")).await;
+    assert!(resp.status().is_success());
+    let resp = test::call_service(&app, request("regular")).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn test_temporary_bans() -> Result<(), Error> {
+    let limiter = Limiter::builder_with_store(MemoryStore::new())
+        .limit(1)
+        .ban_after(2, Duration::from_secs(60), Duration::from_secs(600))
+        .build()
+        .unwrap();
+
+    limiter.count("key").await?;
+    assert!(matches!(
+        limiter.count("key").await,
+        Err(Error::LimitExceeded(_))
+    ));
+
+    // the second rejection bans the key
+    let ban = Some(Duration::from_secs(600));
+    assert!(matches!(limiter.count("key").await, Err(Error::Banned(ttl)) if ttl == ban));
+    assert!(matches!(
+        limiter.count("key").await,
+        Err(Error::Banned(Some(_)))
+    ));
+
+    let bans = limiter.bans().await?;
+    assert_eq!(bans.len(), 1);
+    assert_eq!(bans[0].0, "key");
+    assert!(bans[0].1 <= Duration::from_secs(600));
+
+    assert!(limiter.unban("key").await?);
+    assert!(!limiter.unban("key").await?);
+    assert!(limiter.bans().await?.is_empty());
+
+    // the key is still over its limit
+    assert!(!matches!(limiter.count("key").await, Err(Error::Banned(_))));
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_rejections_do_not_collide_with_keys() -> Result<(), Error> {
+    let limiter = Limiter::builder_with_store(MemoryStore::new())
+        .limit(1)
+        .ban_after(2, Duration::from_secs(60), Duration::from_secs(600))
+        .build()
+        .unwrap();
+
+    limiter.count("key:rejections").await?;
+    limiter.count("key").await?;
+
+    // only the first rejection of the key, which is not enough to ban it
+    assert!(matches!(
+        limiter.count("key").await,
+        Err(Error::LimitExceeded(_))
+    ));
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_banned_response() {
+    let limiter = web::Data::new(
+        Limiter::builder_with_store(MemoryStore::new())
+            .key_by(|_| Some("client".to_owned()))
+            .limit(1)
+            .ban_after(1, Duration::from_secs(60), Duration::from_secs(60))
+            .build()
+            .unwrap(),
+    );
+
+    let app = test::init_service(
+        App::new()
+            .wrap(RateLimiter::default())
+            .app_data(limiter)
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let resp = test::call_service(&app, test::TestRequest::default().to_request()).await;
+    assert!(resp.status().is_success());
+
+    for _ in 0..2 {
+        let resp = test::call_service(&app, test::TestRequest::default().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "60");
+    }
+}
+
+#[actix_web::test]
+async fn test_quota_resolver() {
+    let limiter = web::Data::new(
+        Limiter::builder_with_store(MemoryStore::new())
+            .key_by(|req: &ServiceRequest| {
+                req.headers()
+                    .get("x-api-key")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_owned)
+            })
+            .limit(1)
+            .quota_resolver(|api_key: String| async move {
+                Ok(api_key
+                    .starts_with("pro-")
+                    .then(|| Quota::new(3, Duration::from_secs(60))))
+            })
+            .quota_cache_ttl(Duration::from_secs(60))
+            .build()
+            .unwrap(),
+    );
+
+    let app = test::init_service(
+        App::new()
+            .wrap(RateLimiter::default())
+            .app_data(limiter)
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let request = |api_key: &str| {
+        test::TestRequest::default()
+            .insert_header(("x-api-key", api_key))
+            .to_request()
+    };
+
+    for remaining in ["2", "1", "0"] {
+        let resp = test::call_service(&app, request("pro-1")).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "3");
+        assert_eq!(
+            resp.headers().get("ratelimit-remaining").unwrap(),
+            remaining
+        );
+    }
+    let resp = test::call_service(&app, request("pro-1")).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "3");
+
+    let resp = test::call_service(&app, request("free-1")).await;
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "1");
+    let resp = test::call_service(&app, request("free-1")).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn test_allowlisted_keys_report_their_quota() -> Result<(), Error> {
+    let limiter = Limiter::builder_with_store(MemoryStore::new())
+        .limit(1)
+        .allow_keys(["pro-1"])
+        .quota_resolver(|_key: String| async { Ok(Some(Quota::new(3, Duration::from_secs(60)))) })
+        .build()
+        .unwrap();
+
+    let status = limiter.count("pro-1").await?;
+    assert_eq!(status.limit(), 3);
+    assert_eq!(status.remaining(), 3);
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_limited_stream() {
+    let limiter = || {
+        Arc::new(
+            Limiter::builder_with_store(MemoryStore::new())
+                .limit(2)
+                .period(Duration::from_secs(1))
+                .build()
+                .unwrap(),
+        )
+    };
+
+    let items = LimitedStream::new(stream::iter(1..=5), limiter(), "conn")
+        .collect::<Vec<_>>()
+        .await;
+    let items = items.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(items, [1, 2]);
+
+    let mut limited = LimitedStream::new(stream::iter(1..=5), limiter(), "conn")
+        .over_quota(OverQuota::Close(1008));
+    assert_eq!(limited.next().await.unwrap().unwrap(), 1);
+    assert_eq!(limited.next().await.unwrap().unwrap(), 2);
+    assert!(matches!(
+        limited.next().await,
+        Some(Err(Error::LimitExceeded(_)))
+    ));
+    assert!(limited.next().await.is_none());
+
+    let items = LimitedStream::new(stream::iter(1..=3), limiter(), "conn")
+        .over_quota(OverQuota::Delay)
+        .cost_by(|_| 2)
+        .collect::<Vec<_>>()
+        .await;
+    let items = items.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(items, [1, 2, 3]);
+}
+
+#[actix_web::test]
+async fn test_limited_stream_delays_until_reset() {
+    let limiter = Limiter::builder_with_store(MemoryStore::new())
+        .limit(1)
+        .period(Duration::from_millis(200))
+        .build()
+        .unwrap();
+
+    let started = Instant::now();
+    let items = LimitedStream::new(stream::iter(1..=3), Arc::new(limiter), "conn")
+        .over_quota(OverQuota::Delay)
+        .collect::<Vec<_>>()
+        .await;
+    let items = items.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(items, [1, 2, 3]);
+
+    // delays are not rounded up to whole seconds
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[actix_web::test]
+async fn test_shadow_limiter() {
+    let store: Arc<dyn RateLimitStore> = Arc::new(MemoryStore::new());
+    let limiter = |limit| {
+        let mut builder = Limiter::builder_with_shared_store(Arc::clone(&store));
+        builder
+            .key_by(|_: &ServiceRequest| Some("client".to_owned()))
+            .limit(limit);
+        builder
+    };
+
+    let shadow = limiter(1).shadow(true).build().unwrap();
+    assert!(shadow.count("direct").await.is_ok());
+    assert_eq!(shadow.count("direct").await.unwrap().remaining(), 0);
+
+    let limiters = Limiters::new()
+        .insert("enforced", limiter(3).build().unwrap())
+        .insert("candidate", limiter(1).shadow(true).build().unwrap());
+
+    let app = test::init_service(
+        App::new().app_data(web::Data::new(limiters)).service(
+            web::resource("/")
+                .wrap(
+                    RateLimiter::default()
+                        .policy("enforced")
+                        .policy("candidate"),
+                )
+                .to(HttpResponse::Ok),
+        ),
+    )
+    .await;
+
+    for remaining in ["2", "1", "0"] {
+        let resp = test::call_service(&app, test::TestRequest::default().to_request()).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "3");
+        assert_eq!(
+            resp.headers().get("ratelimit-remaining").unwrap(),
+            remaining
+        );
+    }
+
+    let resp = test::call_service(&app, test::TestRequest::default().to_request()).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "3");
+}
+
+#[actix_web::test]
+async fn test_key_resolver() {
+    let limiter = web::Data::new(
+        Limiter::builder_with_store(MemoryStore::new())
+            .key_resolver(key::resolve_fn(|req: &ServiceRequest| {
+                let api_key = req
+                    .headers()
+                    .get("x-api-key")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_owned);
+
+                async move {
+                    // e.g. look up the tenant in a database
+                    actix_web::rt::task::yield_now().await;
+
+                    match api_key.as_deref() {
+                        None => Ok(None),
+                        Some("broken") => Err(Error::Other("lookup failed".to_owned())),
+                        Some(api_key) => {
+                            let (tenant, plan) = api_key.split_once('-').unwrap();
+                            let key = ResolvedKey::new(tenant);
+                            Ok(Some(if plan == "pro" {
+                                key.with_quota(Quota::new(3, Duration::from_secs(60)))
+                            } else {
+                                key
+                            }))
+                        }
+                    }
+                }
+            }))
+            .limit(1)
+            .build()
+            .unwrap(),
+    );
+
+    let app = test::init_service(
+        App::new()
+            .wrap(RateLimiter::default())
+            .app_data(limiter)
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let request = |api_key: Option<&str>| {
+        let req = test::TestRequest::default();
+        match api_key {
+            Some(api_key) => req.insert_header(("x-api-key", api_key)),
+            None => req,
+        }
+        .to_request()
+    };
+
+    for _ in 0..3 {
+        let resp = test::call_service(&app, request(Some("acme-pro"))).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "3");
+    }
+    let resp = test::call_service(&app, request(Some("acme-pro"))).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let resp = test::call_service(&app, request(Some("initech-free"))).await;
+    assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "1");
+    let resp = test::call_service(&app, request(Some("initech-free"))).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let resp = test::call_service(&app, request(None)).await;
+    assert!(resp.status().is_success());
+    assert!(resp.headers().get("ratelimit-limit").is_none());
+
+    let resp = test::call_service(&app, request(Some("broken"))).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
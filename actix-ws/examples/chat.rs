@@ -96,10 +96,8 @@ async fn ws(
     actix_web::rt::spawn(async move {
         while let Some(Ok(msg)) = stream.recv().await {
             match msg {
-                AggregatedMessage::Ping(bytes) => {
-                    if session.pong(&bytes).await.is_err() {
-                        return;
-                    }
+                AggregatedMessage::Ping(bytes) if session.pong(&bytes).await.is_err() => {
+                    return;
                 }
 
                 AggregatedMessage::Text(string) => {